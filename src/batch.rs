@@ -0,0 +1,46 @@
+use serde::{Deserialize, Serialize};
+
+// Writes that engines apply all together or not at all, in order. Removing a
+// key that does not exist is not an error inside a batch.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct WriteBatch {
+    ops: Vec<BatchOp>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum BatchOp {
+    Set { key: Vec<u8>, value: Vec<u8> },
+    Remove { key: Vec<u8> },
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        WriteBatch::default()
+    }
+
+    pub fn set(&mut self, key: Vec<u8>, value: Vec<u8>) -> &mut Self {
+        self.ops.push(BatchOp::Set { key, value });
+        self
+    }
+
+    pub fn remove(&mut self, key: Vec<u8>) -> &mut Self {
+        self.ops.push(BatchOp::Remove { key });
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    pub fn ops(&self) -> &[BatchOp] {
+        &self.ops
+    }
+
+    pub fn into_ops(self) -> Vec<BatchOp> {
+        self.ops
+    }
+}
@@ -1,12 +1,19 @@
+// `#[derive(Fail)]` expands to impls nested inside a const block.
+#![allow(non_local_definitions)]
+
 use kvs::Client;
 
 use {
-    clap::{App, Arg, SubCommand},
+    clap::{App, Arg, ArgMatches, SubCommand},
     failure::Fail,
-    kvs::{KvsError, Result},
-    slog::{error,  o,  Drain, Logger},
+    kvs::{prefix_range, CasOutcome, KvsError, Result},
+    slog::{error, o, Drain, Logger},
     std::{
+        io::{self, Write},
+        ops::Bound,
+        path::Path,
         process::exit,
+        time::Duration,
     },
 };
 
@@ -34,13 +41,119 @@ fn main() {
             SubCommand::with_name("set")
                 .arg(Arg::with_name("key").required(true))
                 .arg(Arg::with_name("value").required(true))
+                .arg(
+                    Arg::with_name("ttl")
+                        .long("ttl")
+                        .takes_value(true)
+                        .help("seconds after which the key expires"),
+                )
                 .about("set the key to value in cache"),
         )
+        .subcommand(
+            SubCommand::with_name("expire")
+                .arg(Arg::with_name("key").required(true))
+                .arg(Arg::with_name("seconds").required(true))
+                .about("make the key expire after the given seconds"),
+        )
+        .subcommand(
+            SubCommand::with_name("ttl")
+                .arg(Arg::with_name("key").required(true))
+                .about("print the seconds left before the key expires"),
+        )
+        .subcommand(
+            SubCommand::with_name("persist")
+                .arg(Arg::with_name("key").required(true))
+                .about("make the key never expire"),
+        )
+        .subcommand(
+            SubCommand::with_name("incr")
+                .arg(Arg::with_name("key").required(true))
+                .arg(
+                    Arg::with_name("by")
+                        .long("by")
+                        .takes_value(true)
+                        .allow_hyphen_values(true)
+                        .default_value("1")
+                        .help("amount to add"),
+                )
+                .about("add to the integer value of the key and print the result"),
+        )
+        .subcommand(
+            SubCommand::with_name("decr")
+                .arg(Arg::with_name("key").required(true))
+                .arg(
+                    Arg::with_name("by")
+                        .long("by")
+                        .takes_value(true)
+                        .allow_hyphen_values(true)
+                        .default_value("1")
+                        .help("amount to subtract"),
+                )
+                .about("subtract from the integer value of the key and print the result"),
+        )
+        .subcommand(
+            SubCommand::with_name("append")
+                .arg(Arg::with_name("key").required(true))
+                .arg(Arg::with_name("value").required(true))
+                .about("append to the value of the key and print its new length"),
+        )
         .subcommand(
             SubCommand::with_name("rm")
                 .arg(Arg::with_name("key").required(true))
                 .about("rm the key value pair in cache"),
         )
+        .subcommand(
+            SubCommand::with_name("cas")
+                .arg(Arg::with_name("key").required(true))
+                .arg(
+                    Arg::with_name("expected")
+                        .long("expected")
+                        .takes_value(true)
+                        .help("value the key must hold; without it the key must be absent"),
+                )
+                .arg(
+                    Arg::with_name("new")
+                        .long("new")
+                        .takes_value(true)
+                        .help("value to set; without it the key is removed"),
+                )
+                .about("set or remove the key only if it holds the expected value"),
+        )
+        .subcommand(
+            SubCommand::with_name("backup")
+                .arg(
+                    Arg::with_name("dir")
+                        .required(true)
+                        .help("empty or missing directory, relative to the server's --backup-dir"),
+                )
+                .about("write a consistent copy of the store while it keeps serving"),
+        )
+        .subcommand(
+            SubCommand::with_name("scan")
+                .arg(Arg::with_name("start").help("first key, inclusive"))
+                .arg(Arg::with_name("end").help("last key, exclusive"))
+                .arg(
+                    Arg::with_name("prefix")
+                        .long("prefix")
+                        .takes_value(true)
+                        .conflicts_with_all(&["start", "end"])
+                        .help("only keys starting with this prefix"),
+                )
+                .arg(
+                    Arg::with_name("limit")
+                        .long("limit")
+                        .takes_value(true)
+                        .default_value("100")
+                        .help("most pairs to print"),
+                )
+                .arg(
+                    Arg::with_name("cursor")
+                        .long("cursor")
+                        .takes_value(true)
+                        .help("continue after this cursor, as printed in hex by a previous scan"),
+                )
+                .about("list key value pairs in key order, with keys escaped"),
+        )
         .get_matches();
 
     if std::env::args().len() < 2 {
@@ -50,31 +163,149 @@ fn main() {
 
     let addr = matches.value_of("addr").unwrap_or("127.0.0.1:4000");
 
-    let client = Client::new(addr.to_string());
-
     let res = || -> Result<()> {
+        let mut client = Client::connect(addr)?;
+
         match matches.subcommand() {
             ("get", Some(args)) => {
                 let key = args.value_of("key").ok_or(CliError::Key)?;
 
-                let s = client.get(key)?;
-                println!("{}", s);
+                // Values are printed as stored, even when they are not UTF-8.
+                match client.get(key.as_bytes())? {
+                    Some(value) => {
+                        let mut stdout = io::stdout();
+                        stdout.write_all(&value)?;
+                        stdout.write_all(b"\n")?;
+                    }
+                    None => println!("Key not found"),
+                }
             }
             ("set", Some(args)) => {
                 let key = args.value_of("key").ok_or(CliError::Key)?;
                 let value = args.value_of("value").ok_or(CliError::Value)?;
 
-                client.set(key, value)?;
+                match args.value_of("ttl") {
+                    Some(ttl) => {
+                        let ttl = parse_seconds(ttl)?;
+                        client.set_with_ttl(key.as_bytes(), value.as_bytes(), ttl)?;
+                    }
+                    None => client.set(key.as_bytes(), value.as_bytes())?,
+                }
+            }
+            ("expire", Some(args)) => {
+                let key = args.value_of("key").ok_or(CliError::Key)?;
+                let ttl = parse_seconds(args.value_of("seconds").ok_or(CliError::Ttl)?)?;
+
+                exit_if_not_found(client.expire(key.as_bytes(), ttl))?;
+            }
+            ("ttl", Some(args)) => {
+                let key = args.value_of("key").ok_or(CliError::Key)?;
+
+                match exit_if_not_found(client.ttl(key.as_bytes()))? {
+                    // Round up, so a key with time left never shows 0.
+                    Some(ttl) => println!("{}", ttl.as_millis().div_ceil(1000)),
+                    None => println!("No expiry"),
+                }
+            }
+            ("persist", Some(args)) => {
+                let key = args.value_of("key").ok_or(CliError::Key)?;
+
+                exit_if_not_found(client.persist(key.as_bytes()))?;
+            }
+            ("incr", Some(args)) => {
+                let key = args.value_of("key").ok_or(CliError::Key)?;
+                let delta = parse_delta(args)?;
+
+                println!(
+                    "{}",
+                    exit_if_not_integer(client.incr_by(key.as_bytes(), delta))?
+                );
+            }
+            ("decr", Some(args)) => {
+                let key = args.value_of("key").ok_or(CliError::Key)?;
+                let delta = parse_delta(args)?;
+
+                println!(
+                    "{}",
+                    exit_if_not_integer(client.decr_by(key.as_bytes(), delta))?
+                );
+            }
+            ("append", Some(args)) => {
+                let key = args.value_of("key").ok_or(CliError::Key)?;
+                let value = args.value_of("value").ok_or(CliError::Value)?;
+
+                println!("{}", client.append(key.as_bytes(), value.as_bytes())?);
             }
             ("rm", Some(args)) => {
                 let key = args.value_of("key").ok_or(CliError::Key)?;
 
-                let s = client.remove(key)?;
-                if s.len() != 0 {
-                    eprintln!("{}", s);
+                if let Err(e) = client.remove(key.as_bytes()) {
+                    if let Some(KvsError::Remove(_)) = e.downcast_ref::<KvsError>() {
+                        eprintln!("Key not found");
+                        exit(1);
+                    }
+                    return Err(e);
+                }
+            }
+            ("cas", Some(args)) => {
+                let key = args.value_of("key").ok_or(CliError::Key)?;
+                let expected = args.value_of("expected").map(str::as_bytes);
+                let new = args.value_of("new").map(str::as_bytes);
+
+                if let CasOutcome::Conflict { current } =
+                    client.compare_and_swap(key.as_bytes(), expected, new)?
+                {
+                    let mut stderr = io::stderr();
+                    match current {
+                        Some(value) => {
+                            stderr.write_all(b"Conflict, current value: ")?;
+                            stderr.write_all(&value)?;
+                            stderr.write_all(b"\n")?;
+                        }
+                        None => stderr.write_all(b"Conflict, key not found\n")?,
+                    }
                     exit(1);
                 }
             }
+            ("backup", Some(args)) => {
+                let dir = args.value_of("dir").ok_or(CliError::Dir)?;
+
+                client.backup(Path::new(dir))?;
+            }
+            ("scan", Some(args)) => {
+                let (mut start, end) = match args.value_of("prefix") {
+                    Some(prefix) => prefix_range(prefix.as_bytes().to_vec()),
+                    None => (
+                        args.value_of("start")
+                            .map_or(Bound::Unbounded, |k| Bound::Included(k.as_bytes().to_vec())),
+                        args.value_of("end")
+                            .map_or(Bound::Unbounded, |k| Bound::Excluded(k.as_bytes().to_vec())),
+                    ),
+                };
+                if let Some(cursor) = args.value_of("cursor") {
+                    start = Bound::Excluded(parse_cursor(cursor)?);
+                }
+                let limit = args
+                    .value_of("limit")
+                    .and_then(|n| n.parse().ok())
+                    .ok_or(CliError::Limit)?;
+
+                let page = client.scan(start, end, limit)?;
+                let mut stdout = io::stdout();
+                // Keys are escaped so a space or newline in one cannot be
+                // mistaken for the separator; values are printed as stored.
+                for (key, value) in page.pairs {
+                    stdout.write_all(escape_key(&key).as_bytes())?;
+                    stdout.write_all(b" ")?;
+                    stdout.write_all(&value)?;
+                    stdout.write_all(b"\n")?;
+                }
+                if let Some(cursor) = page.cursor {
+                    stdout.write_all(b"Next cursor: ")?;
+                    stdout.write_all(hex(&cursor).as_bytes())?;
+                    stdout.write_all(b"\n")?;
+                }
+            }
             (cmd, _) => {
                 return Err(KvsError::UnKnownOperation(cmd.to_string()).into());
             }
@@ -93,4 +324,69 @@ enum CliError {
     Key,
     #[fail(display = "The value is wanted")]
     Value,
+    #[fail(display = "The limit must be a number")]
+    Limit,
+    #[fail(display = "The ttl must be a number of seconds")]
+    Ttl,
+    #[fail(display = "The amount must be an integer")]
+    Delta,
+    #[fail(display = "The directory is wanted")]
+    Dir,
+    #[fail(display = "The cursor must be hex, as printed by scan")]
+    Cursor,
+}
+
+fn parse_delta(args: &ArgMatches) -> Result<i64> {
+    let delta = args.value_of("by").ok_or(CliError::Delta)?;
+    Ok(delta.parse().map_err(|_| CliError::Delta)?)
+}
+
+fn escape_key(key: &[u8]) -> String {
+    key.iter()
+        .map(|&b| match b {
+            b' ' => "\\x20".to_string(),
+            _ => std::ascii::escape_default(b).to_string(),
+        })
+        .collect()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn parse_cursor(cursor: &str) -> Result<Vec<u8>> {
+    if !cursor.len().is_multiple_of(2) || !cursor.is_ascii() {
+        return Err(CliError::Cursor.into());
+    }
+    (0..cursor.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&cursor[i..i + 2], 16).map_err(|_| CliError::Cursor.into()))
+        .collect()
+}
+
+fn parse_seconds(secs: &str) -> Result<Duration> {
+    let secs = secs.parse().map_err(|_| CliError::Ttl)?;
+    Ok(Duration::from_secs(secs))
+}
+
+// Report a missing key the way `rm` does.
+fn exit_if_not_found<T>(res: Result<T>) -> Result<T> {
+    if let Err(ref e) = res {
+        if let Some(KvsError::Get(_)) = e.downcast_ref::<KvsError>() {
+            eprintln!("Key not found");
+            exit(1);
+        }
+    }
+    res
+}
+
+// Report a value that is not an integer the way a missing key is reported.
+fn exit_if_not_integer<T>(res: Result<T>) -> Result<T> {
+    if let Err(ref e) = res {
+        if let Some(KvsError::NotAnInteger(_)) = e.downcast_ref::<KvsError>() {
+            eprintln!("Value is not an integer");
+            exit(1);
+        }
+    }
+    res
 }
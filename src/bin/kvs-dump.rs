@@ -0,0 +1,272 @@
+// `#[derive(Fail)]` expands to impls nested inside a const block.
+#![allow(non_local_definitions)]
+
+use {
+    clap::{App, Arg, ArgMatches, SubCommand},
+    failure::Fail,
+    kvs::{Durability, KvStore, KvsEngine, KvsSnapshot, Manifest, Options, Result, SledKvsEngine},
+    serde_json::{json, Value},
+    std::{
+        fs::File,
+        io::{self, BufRead, BufReader, BufWriter, Read, Write},
+        path::Path,
+        process::exit,
+    },
+};
+
+fn main() {
+    let matches = App::new("kvs-dump")
+        .version(env!("CARGO_PKG_VERSION"))
+        .about("export a store to JSON Lines or CSV, or load pairs back into it")
+        .author(env!("CARGO_PKG_AUTHORS"))
+        .arg(
+            Arg::with_name("dir")
+                .long("dir")
+                .takes_value(true)
+                .global(true)
+                .help("directory of the store [default: .]"),
+        )
+        .arg(
+            Arg::with_name("engine")
+                .long("engine")
+                .takes_value(true)
+                .global(true)
+                .possible_values(&["kvs", "sled"])
+                .help("engine of the store [default: as recorded in its manifest, else kvs]"),
+        )
+        .arg(
+            Arg::with_name("format")
+                .long("format")
+                .takes_value(true)
+                .global(true)
+                .possible_values(&["jsonl", "csv"])
+                .default_value("jsonl"),
+        )
+        .subcommand(
+            SubCommand::with_name("export")
+                .arg(
+                    Arg::with_name("output")
+                        .long("output")
+                        .takes_value(true)
+                        .help("file to write [default: stdout]"),
+                )
+                .about("write every key value pair in key order"),
+        )
+        .subcommand(
+            SubCommand::with_name("load")
+                .arg(
+                    Arg::with_name("input")
+                        .long("input")
+                        .takes_value(true)
+                        .help("file to read [default: stdin]"),
+                )
+                .about("set every key value pair read, overwriting existing keys"),
+        )
+        .get_matches();
+
+    if let Err(e) = run(&matches) {
+        eprintln!("kvs-dump: {}", e);
+        exit(1);
+    }
+}
+
+fn run(matches: &ArgMatches) -> Result<()> {
+    let dir = Path::new(matches.value_of("dir").unwrap_or("."));
+    let format = matches.value_of("format").unwrap_or("jsonl");
+    let mut manifest = Manifest::open(dir, matches.value_of("engine"))?;
+    let engine = manifest.engine.clone();
+
+    match (matches.subcommand(), engine.as_str()) {
+        (("export", Some(args)), "kvs") => {
+            let store = KvStore::open_with(dir, Options::new().read_only(true))?;
+            export(&store.snapshot()?, format, args.value_of("output"))
+        }
+        // sled has no read-only open, but reading from a snapshot leaves
+        // expired keys in place where a scan of the engine would delete them.
+        (("export", Some(args)), "sled") => {
+            let engine = SledKvsEngine::open(dir, Durability::None)?;
+            export(&engine.snapshot()?, format, args.value_of("output"))
+        }
+        (("load", Some(args)), engine) => {
+            let input = args.value_of("input");
+            match engine {
+                "kvs" => load(&KvStore::open(dir)?, format, input)?,
+                _ => load(
+                    &SledKvsEngine::open(dir, Durability::EveryWrite)?,
+                    format,
+                    input,
+                )?,
+            }
+            manifest.upgrade(dir)
+        }
+        _ => Err(DumpError::Usage.into()),
+    }
+}
+
+fn export<S: KvsSnapshot>(snapshot: &S, format: &str, output: Option<&str>) -> Result<()> {
+    let output: Box<dyn Write> = match output {
+        Some(path) => Box::new(File::create(path)?),
+        None => Box::new(io::stdout()),
+    };
+    let mut output = BufWriter::new(output);
+    if format == "csv" {
+        writeln!(output, "key,value")?;
+    }
+    for pair in snapshot.scan(..)? {
+        let (key, value) = pair?;
+        if format == "csv" {
+            writeln!(output, "{},{}", csv_field(key)?, csv_field(value)?)?;
+        } else {
+            let pair = json!({ "key": json_field(key), "value": json_field(value) });
+            writeln!(output, "{}", pair)?;
+        }
+    }
+    output.flush()?;
+    Ok(())
+}
+
+fn load<E: KvsEngine>(engine: &E, format: &str, input: Option<&str>) -> Result<()> {
+    let input: Box<dyn Read> = match input {
+        Some(path) => Box::new(File::open(path)?),
+        None => Box::new(io::stdin()),
+    };
+    let input = BufReader::new(input);
+    let loaded = if format == "csv" {
+        engine.bulk_load(CsvPairs::new(input))?
+    } else {
+        let pairs = input
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.as_ref().is_ok_and(|line| line.trim().is_empty()))
+            .map(|(i, line)| parse_json_line(i + 1, &line?));
+        engine.bulk_load(pairs)?
+    };
+    eprintln!("Loaded {} pairs", loaded);
+    Ok(())
+}
+
+// Keys and values are JSON strings, or arrays of byte values when they are
+// not valid UTF-8.
+fn json_field(bytes: Vec<u8>) -> Value {
+    match String::from_utf8(bytes) {
+        Ok(s) => Value::String(s),
+        Err(e) => Value::from(e.into_bytes()),
+    }
+}
+
+fn parse_json_line(line_no: usize, line: &str) -> Result<(Vec<u8>, Vec<u8>)> {
+    let bad_line = || DumpError::Line(line_no);
+    let pair: Value = serde_json::from_str(line).map_err(|_| bad_line())?;
+    let field = |name: &str| -> Result<Vec<u8>> {
+        match pair.get(name) {
+            Some(Value::String(s)) => Ok(s.clone().into_bytes()),
+            Some(Value::Array(bytes)) => bytes
+                .iter()
+                .map(|b| b.as_u64().filter(|&b| b <= 0xff).map(|b| b as u8))
+                .collect::<Option<Vec<u8>>>()
+                .ok_or_else(|| bad_line().into()),
+            _ => Err(bad_line().into()),
+        }
+    };
+    Ok((field("key")?, field("value")?))
+}
+
+// CSV fields are quoted when they contain a comma, quote or line break, with
+// quotes doubled. CSV only carries text, so binary pairs need JSON Lines.
+fn csv_field(bytes: Vec<u8>) -> Result<String> {
+    let s = String::from_utf8(bytes).map_err(|_| DumpError::Binary)?;
+    if s.contains([',', '"', '\n', '\r']) {
+        Ok(format!("\"{}\"", s.replace('"', "\"\"")))
+    } else {
+        Ok(s)
+    }
+}
+
+// Reads `key,value` records after a header line.
+struct CsvPairs<R: BufRead> {
+    input: R,
+    line_no: usize,
+    header_read: bool,
+}
+
+impl<R: BufRead> CsvPairs<R> {
+    fn new(input: R) -> Self {
+        CsvPairs {
+            input,
+            line_no: 0,
+            header_read: false,
+        }
+    }
+
+    // Return `None` at the end of the input. A quoted field may span lines.
+    fn read_record(&mut self) -> Result<Option<Vec<String>>> {
+        let mut fields = Vec::new();
+        let mut field = String::new();
+        let mut quoted = false;
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if self.input.read_line(&mut line)? == 0 {
+                if quoted {
+                    return Err(DumpError::Line(self.line_no).into());
+                }
+                return Ok(None);
+            }
+            self.line_no += 1;
+            if !quoted && fields.is_empty() && field.is_empty() && line.trim().is_empty() {
+                continue;
+            }
+            let mut chars = line.chars().peekable();
+            while let Some(c) = chars.next() {
+                match (c, quoted) {
+                    ('"', true) if chars.peek() == Some(&'"') => {
+                        chars.next();
+                        field.push('"');
+                    }
+                    ('"', true) => quoted = false,
+                    ('"', false) if field.is_empty() => quoted = true,
+                    (',', false) => fields.push(std::mem::take(&mut field)),
+                    ('\n', false) | ('\r', false) => {}
+                    (c, _) => field.push(c),
+                }
+            }
+            if !quoted {
+                fields.push(field);
+                return Ok(Some(fields));
+            }
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for CsvPairs<R> {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if !self.header_read {
+            self.header_read = true;
+            if let Err(e) = self.read_record().transpose()? {
+                return Some(Err(e));
+            }
+        }
+        let mut record = match self.read_record().transpose()? {
+            Ok(record) => record,
+            Err(e) => return Some(Err(e)),
+        };
+        if record.len() != 2 {
+            return Some(Err(DumpError::Line(self.line_no).into()));
+        }
+        let value = record.pop()?;
+        let key = record.pop()?;
+        Some(Ok((key.into_bytes(), value.into_bytes())))
+    }
+}
+
+#[derive(Fail, Debug)]
+enum DumpError {
+    #[fail(display = "a subcommand is wanted: export or load")]
+    Usage,
+    #[fail(display = "malformed record on line {}", _0)]
+    Line(usize),
+    #[fail(display = "the store holds binary data, which only jsonl can carry")]
+    Binary,
+}
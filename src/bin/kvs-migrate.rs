@@ -0,0 +1,104 @@
+// `#[derive(Fail)]` expands to impls nested inside a const block.
+#![allow(non_local_definitions)]
+
+use {
+    clap::{App, Arg, ArgMatches},
+    failure::Fail,
+    kvs::{
+        kvstore::lock_dir, migrate, Durability, KvStore, KvsEngine, Manifest, Options, Result,
+        SledKvsEngine,
+    },
+    std::{fs, path::Path, process::exit},
+};
+
+fn main() {
+    let engines = ["kvs", "sled"];
+    let matches = App::new("kvs-migrate")
+        .version(env!("CARGO_PKG_VERSION"))
+        .about("copy a stopped store to a new directory under another engine")
+        .author(env!("CARGO_PKG_AUTHORS"))
+        .arg(
+            Arg::with_name("from")
+                .long("from")
+                .takes_value(true)
+                .required(true)
+                .possible_values(&engines),
+        )
+        .arg(
+            Arg::with_name("to")
+                .long("to")
+                .takes_value(true)
+                .required(true)
+                .possible_values(&engines),
+        )
+        .arg(
+            Arg::with_name("src")
+                .required(true)
+                .help("directory of the store"),
+        )
+        .arg(
+            Arg::with_name("dst")
+                .required(true)
+                .help("empty or missing directory for the migrated store"),
+        )
+        .get_matches();
+
+    if let Err(e) = run(&matches) {
+        eprintln!("kvs-migrate: {}", e);
+        exit(1);
+    }
+}
+
+fn run(matches: &ArgMatches) -> Result<()> {
+    let from = matches.value_of("from").unwrap_or("kvs");
+    let to = matches.value_of("to").unwrap_or("kvs");
+    let src = Path::new(matches.value_of("src").unwrap_or("."));
+    let dst = Path::new(matches.value_of("dst").unwrap_or("."));
+
+    if !src.is_dir() {
+        return Err(MigrateError::Missing(src.display().to_string()).into());
+    }
+    Manifest::open(src, Some(from))?;
+    if dst.exists() && fs::read_dir(dst)?.next().is_some() {
+        return Err(MigrateError::NotEmpty(dst.display().to_string()).into());
+    }
+
+    match from {
+        "kvs" => {
+            // A read-only open takes no lock, so take the writer lock here to
+            // refuse a store that is still being written to.
+            let _lock = lock_dir(src)?;
+            with_source(
+                KvStore::open_with(src, Options::new().read_only(true))?,
+                to,
+                dst,
+            )?
+        }
+        // sled has no read-only open, but `migrate` reads from a snapshot,
+        // which leaves expired keys in place.
+        _ => with_source(SledKvsEngine::open(src, Durability::None)?, to, dst)?,
+    }
+    // The migrated store is a new store, with an id of its own.
+    Manifest::new(to)?.write(dst)?;
+    Ok(())
+}
+
+fn with_source<S: KvsEngine>(src: S, to: &str, dst: &Path) -> Result<()> {
+    let report = match to {
+        "kvs" => migrate(&src, &KvStore::open(dst)?)?,
+        _ => migrate(&src, &SledKvsEngine::open(dst, Durability::EveryWrite)?)?,
+    };
+    println!(
+        "Migrated {} pairs, checksum {:016x}",
+        report.pairs, report.checksum
+    );
+    Ok(())
+}
+
+#[derive(Fail, Debug)]
+enum MigrateError {
+    #[fail(display = "no store at {}", _0)]
+    Missing(String),
+    #[fail(display = "{} is not empty", _0)]
+    NotEmpty(String),
+}
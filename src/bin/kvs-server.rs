@@ -1,12 +1,19 @@
 use {
-    clap::{App, Arg},
-    kvs::{ KvStore, KvsEngine,SledKvsEngine,  Result},
-    slog::{ error, info, o, Drain, Logger},
+    clap::{App, Arg, ArgMatches},
+    kvs::{
+        kvstore::{create_empty_dir, lock_dir, LOCK_FILE},
+        manifest::MANIFEST_FILE,
+        thread_pool::{NaiveThreadPool, RayonThreadPool, SharedQueueThreadPool, ThreadPool},
+        CompactionTrigger, Durability, KvStore, KvsEngine, KvsError, Manifest, Options, Result,
+        SledKvsEngine,
+    },
+    slog::{error, info, o, Drain, Logger},
     std::{
         fs,
-        io::{Write},
+        path::{Path, PathBuf},
         process::exit,
-        rc::Rc,
+        thread,
+        time::Duration,
     },
 };
 
@@ -27,52 +34,201 @@ fn main() {
                 .short("e")
                 .long("engine")
                 .global(true)
-                .takes_value(true),
+                .takes_value(true)
+                .help("engine of the store [default: as recorded in its manifest, else kvs]"),
+        )
+        .arg(
+            Arg::with_name("data-dir")
+                .long("data-dir")
+                .takes_value(true)
+                .help("directory of the store [default: .]"),
+        )
+        .arg(
+            Arg::with_name("pool")
+                .long("pool")
+                .takes_value(true)
+                .possible_values(&["naive", "shared-queue", "rayon"]),
+        )
+        .arg(Arg::with_name("threads").long("threads").takes_value(true))
+        .arg(
+            Arg::with_name("compact-after")
+                .long("compact-after")
+                .takes_value(true)
+                .conflicts_with("compact-ratio")
+                .help("compact once this many bytes are stale (kvs engine)"),
+        )
+        .arg(
+            Arg::with_name("compact-ratio")
+                .long("compact-ratio")
+                .takes_value(true)
+                .help("compact once this fraction of the logs is stale (kvs engine)"),
+        )
+        .arg(
+            Arg::with_name("compact-min-bytes")
+                .long("compact-min-bytes")
+                .takes_value(true)
+                .requires("compact-ratio")
+                .help("stale bytes needed before --compact-ratio applies (kvs engine)"),
+        )
+        .arg(
+            Arg::with_name("merge-threshold")
+                .long("merge-threshold")
+                .takes_value(true)
+                .help("merge only logs at least this fraction stale [default: 0.5] (kvs engine)"),
+        )
+        .arg(
+            Arg::with_name("max-log-size")
+                .long("max-log-size")
+                .takes_value(true)
+                .help("start a new log file past this many bytes (kvs engine)"),
+        )
+        .arg(
+            Arg::with_name("durability")
+                .long("durability")
+                .takes_value(true)
+                .possible_values(&["none", "every-write", "interval", "group-commit"])
+                .help(
+                    "when writes are synced to disk before being acknowledged \
+                     [default: none for kvs, every-write for sled]",
+                ),
+        )
+        .arg(
+            Arg::with_name("sync-interval-ms")
+                .long("sync-interval-ms")
+                .takes_value(true)
+                .help("milliseconds between syncs with --durability interval [default: 100]"),
+        )
+        .arg(
+            Arg::with_name("read-only").long("read-only").help(
+                "reject writes and never touch the data files (kvs engine, refused for sled)",
+            ),
+        )
+        .arg(
+            Arg::with_name("no-create")
+                .long("no-create")
+                .help("fail instead of creating a missing store (kvs engine, refused for sled)"),
+        )
+        .arg(
+            Arg::with_name("backup-dir")
+                .long("backup-dir")
+                .takes_value(true)
+                .help("directory clients may write backups under [default: backups are refused]"),
+        )
+        .arg(
+            Arg::with_name("txn-timeout-ms")
+                .long("txn-timeout-ms")
+                .takes_value(true)
+                .help("abort transactions idle for this many milliseconds [default: 30000]"),
+        )
+        .arg(
+            Arg::with_name("max-txns")
+                .long("max-txns")
+                .takes_value(true)
+                .help("refuse to begin more transactions than this at once [default: 64]"),
+        )
+        .arg(
+            Arg::with_name("restore")
+                .long("restore")
+                .takes_value(true)
+                .conflicts_with("read-only")
+                .help("copy a backup into the data directory before serving it"),
         )
         .get_matches();
 
     let plain = slog_term::PlainSyncDecorator::new(std::io::stderr());
-    let logger = Rc::new(Logger::root(
-        slog_term::FullFormat::new(plain).build().fuse(),
-        o!(),
-    ));
+    let logger = Logger::root(slog_term::FullFormat::new(plain).build().fuse(), o!());
 
     info!(logger, "name: {}", "kvs-server");
     info!(logger, "version: {}", env!("CARGO_PKG_VERSION"));
 
-    let cli_engine = matches.value_of("engine").unwrap_or("kvs");
+    let data_dir = PathBuf::from(matches.value_of("data-dir").unwrap_or("."));
+    info!(logger, "data dir: {}", data_dir.display());
 
-    let pre_engine = match fs::read_to_string("./pre_engine") {
-        Ok(s) => s,
-        Err(_) => cli_engine.to_string(),
+    if let Some(backup) = matches.value_of("restore") {
+        if let Err(e) = restore(Path::new(backup), &data_dir, matches.value_of("engine")) {
+            error!(logger, "can not restore {}: {}", backup, e);
+            exit(1);
+        }
+        info!(logger, "restored backup: {}", backup);
+    }
+
+    let mut manifest = match Manifest::open(&data_dir, matches.value_of("engine")) {
+        Ok(manifest) => manifest,
+        Err(e) => {
+            error!(logger, "{}", e);
+            exit(1);
+        }
     };
+    info!(logger, "engine: {}", manifest.engine);
 
-    if !cli_engine.to_string().eq(&pre_engine) {
-        error!(logger, "use db engine different from previous");
-        exit(1);
+    // sled always opens its database for writing and creates it if missing.
+    if manifest.engine == "sled" {
+        for flag in ["read-only", "no-create"] {
+            if matches.is_present(flag) {
+                error!(logger, "--{} is not supported by the sled engine", flag);
+                exit(1);
+            }
+        }
     }
 
-    match fs::File::create("./pre_engine") {
-        Ok(mut f) => if let Err(e) = f.write_all(cli_engine.to_string().as_bytes()){
-            error!(logger,"can not write pre_engine: {:?}",e);
+    let options = match store_options(&matches) {
+        Ok(options) => options,
+        Err(e) => {
+            error!(logger, "{}", e);
             exit(1);
-        },
-        Err(e) => {error!(logger,"can not create pre_engine file: {:?}",e);exit(1);},
+        }
     };
 
+    // Already validated by `store_options`.
+    let sled_durability = durability(&matches).unwrap_or(None);
+    // Never set for sled, which was refused above.
+    let read_only = matches.is_present("read-only");
+
     let addr = matches.value_of("addr").unwrap_or("127.0.0.1:4000");
 
     info!(logger, "addr: {}", addr);
 
-    let res = || -> Result<()> {
-        Ok(match cli_engine {
-            "kvs" => run(KvStore::open("./")?, addr.to_string(), logger.clone())?,
-            "sled" => {let db = sled::Db::start_default("./")?;run(SledKvsEngine::new(db), addr.to_string(), logger.clone())?},
-            e @ _ => {
-                error!(logger, "no such engine: {}", e);
+    let threads = match matches.value_of("threads") {
+        Some(n) => match n.parse::<u32>() {
+            Ok(n) if n > 0 => n,
+            _ => {
+                error!(logger, "invalid thread count: {}", n);
                 exit(1);
             }
-        })
+        },
+        None => thread::available_parallelism().map_or(4, |n| n.get() as u32),
+    };
+    let pool = matches.value_of("pool").unwrap_or("shared-queue");
+    let settings = match server_settings(&matches) {
+        Ok(settings) => settings,
+        Err(e) => {
+            error!(logger, "{}", e);
+            exit(1);
+        }
+    };
+
+    // The manifest is only written once the store has opened, so a store
+    // that fails to open is left as it was.
+    let record = |manifest: &mut Manifest| -> Result<()> {
+        if !read_only {
+            manifest.upgrade(&data_dir)?;
+        }
+        info!(logger, "store id: {}", manifest.store_id);
+        Ok(())
+    };
+    let res = || -> Result<()> {
+        if manifest.engine == "sled" {
+            // sled keeps flushing on every write unless told otherwise, as it
+            // always did; kvs keeps the default of `Options`. See --durability.
+            let durability = sled_durability.unwrap_or(Durability::EveryWrite);
+            let engine = SledKvsEngine::open(&data_dir, durability)?;
+            record(&mut manifest)?;
+            run_with_pool(engine, pool, threads, addr, &settings, logger.clone())
+        } else {
+            let store = KvStore::open_with(&data_dir, options)?;
+            record(&mut manifest)?;
+            run_with_pool(store, pool, threads, addr, &settings, logger.clone())
+        }
     }();
 
     if let Err(e) = res {
@@ -81,8 +237,197 @@ fn main() {
     }
 }
 
-fn run<E: KvsEngine>(engine: E, addr: String, logger: Rc<Logger>) -> Result<()> {
-    let mut server = kvs::Server::new(engine, logger)?;
-    server.serve(&addr)?;
+fn run_with_pool<E: KvsEngine>(
+    engine: E,
+    pool: &str,
+    threads: u32,
+    addr: &str,
+    settings: &ServerSettings,
+    logger: Logger,
+) -> Result<()> {
+    info!(logger, "thread pool: {} with {} threads", pool, threads);
+    match pool {
+        "naive" => run(
+            engine,
+            NaiveThreadPool::new(threads)?,
+            addr,
+            settings,
+            logger,
+        ),
+        "rayon" => run(
+            engine,
+            RayonThreadPool::new(threads)?,
+            addr,
+            settings,
+            logger,
+        ),
+        _ => run(
+            engine,
+            SharedQueueThreadPool::new(threads)?,
+            addr,
+            settings,
+            logger,
+        ),
+    }
+}
+
+fn run<E: KvsEngine, P: ThreadPool>(
+    engine: E,
+    pool: P,
+    addr: &str,
+    settings: &ServerSettings,
+    logger: Logger,
+) -> Result<()> {
+    let mut server = kvs::Server::new(engine, pool, logger)?;
+    if let Some(ref dir) = settings.backup_dir {
+        server = server.backup_dir(dir.clone());
+    }
+    if let Some(timeout) = settings.txn_timeout {
+        server = server.transaction_timeout(timeout);
+    }
+    if let Some(max) = settings.max_txns {
+        server = server.max_transactions(max);
+    }
+    server.serve(addr)?;
+    Ok(())
+}
+
+// What the command line sets on `kvs::Server`, where it differs from the
+// defaults.
+struct ServerSettings {
+    backup_dir: Option<PathBuf>,
+    txn_timeout: Option<Duration>,
+    max_txns: Option<usize>,
+}
+
+fn server_settings(matches: &ArgMatches) -> std::result::Result<ServerSettings, String> {
+    let txn_timeout = match matches.value_of("txn-timeout-ms") {
+        Some(ms) => Some(Duration::from_millis(parse_arg(ms)?)),
+        None => None,
+    };
+    let max_txns = match matches.value_of("max-txns") {
+        Some(max) => Some(parse_arg(max)?),
+        None => None,
+    };
+    Ok(ServerSettings {
+        backup_dir: matches.value_of("backup-dir").map(PathBuf::from),
+        txn_timeout,
+        max_txns,
+    })
+}
+
+fn store_options(matches: &ArgMatches) -> std::result::Result<Options, String> {
+    let mut options = Options::new()
+        .read_only(matches.is_present("read-only"))
+        .create_if_missing(!matches.is_present("no-create"));
+    if let Some(bytes) = matches.value_of("compact-after") {
+        options = options.compaction_trigger(CompactionTrigger::Absolute(parse_arg(bytes)?));
+    }
+    if let Some(ratio) = matches.value_of("compact-ratio") {
+        let min_bytes = match matches.value_of("compact-min-bytes") {
+            Some(bytes) => parse_arg(bytes)?,
+            None => 1024 * 1024,
+        };
+        options = options.compaction_trigger(CompactionTrigger::StaleRatio {
+            ratio: parse_arg(ratio)?,
+            min_bytes,
+        });
+    }
+    if let Some(ratio) = matches.value_of("merge-threshold") {
+        options = options.merge_threshold(parse_arg(ratio)?);
+    }
+    if let Some(bytes) = matches.value_of("max-log-size") {
+        options = options.max_log_size(parse_arg(bytes)?);
+    }
+    if let Some(durability) = durability(matches)? {
+        options = options.durability(durability);
+    }
+    Ok(options)
+}
+
+fn durability(matches: &ArgMatches) -> std::result::Result<Option<Durability>, String> {
+    let durability = match matches.value_of("durability") {
+        Some("every-write") => Durability::EveryWrite,
+        Some("interval") => {
+            let ms = match matches.value_of("sync-interval-ms") {
+                Some(ms) => parse_arg(ms)?,
+                None => 100,
+            };
+            Durability::Interval(Duration::from_millis(ms))
+        }
+        Some("group-commit") => Durability::GroupCommit,
+        Some(_) => Durability::None,
+        None => return Ok(None),
+    };
+    Ok(Some(durability))
+}
+
+fn parse_arg<T: std::str::FromStr>(value: &str) -> std::result::Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("invalid argument value: {}", value))
+}
+
+// Copy every file of the backup into `dir`, which must be empty or missing,
+// so that no store is ever clobbered. `dir` stays locked meanwhile, so no
+// server opens a half restored store. The restored store gets the manifest of
+// the backup with an id of its own; a backup without one needs `engine`.
+fn restore(backup: &Path, dir: &Path, engine: Option<&str>) -> Result<()> {
+    if !backup.is_dir() {
+        return Err(KvsError::StoreNotFound(backup.display().to_string()).into());
+    }
+    let manifest = match (Manifest::read(backup)?, engine) {
+        (Some(manifest), engine) => {
+            manifest.check(backup, engine.unwrap_or(&manifest.engine))?;
+            Some(manifest)
+        }
+        (None, Some(_)) => None,
+        (None, None) => {
+            let msg = format!(
+                "{} has no manifest, so --engine must be given",
+                backup.display()
+            );
+            return Err(KvsError::Manifest(msg).into());
+        }
+    };
+    if let Err(e) = create_empty_dir(dir) {
+        // Tell a store that is being served apart from any other leftovers.
+        if dir.join(LOCK_FILE).exists() {
+            lock_dir(dir)?;
+        }
+        return Err(e);
+    }
+    let _lock = lock_dir(dir)?;
+    let files = backup_files(backup, Path::new(""))?;
+    let skipped = [Path::new(LOCK_FILE), Path::new(MANIFEST_FILE)];
+    for file in files
+        .into_iter()
+        .filter(|file| !skipped.contains(&file.as_path()))
+    {
+        let to = dir.join(&file);
+        if let Some(parent) = to.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::copy(backup.join(&file), to)?;
+    }
+    if let Some(mut manifest) = manifest {
+        manifest.renew_store_id();
+        manifest.write(dir)?;
+    }
     Ok(())
 }
+
+// Paths of the files under `backup.join(sub)`, relative to `backup`.
+fn backup_files(backup: &Path, sub: &Path) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(backup.join(sub))? {
+        let entry = entry?;
+        let path = sub.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            files.extend(backup_files(backup, &path)?);
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(files)
+}
@@ -0,0 +1,19 @@
+use crate::{KvsError, Result};
+
+// Counters are stored as ASCII decimal, so they also read back as strings.
+// An absent key counts as zero.
+pub(crate) fn add(key: &[u8], current: Option<&[u8]>, delta: i64) -> Result<i64> {
+    let not_an_integer = || KvsError::NotAnInteger(String::from_utf8_lossy(key).into_owned());
+    let current = match current {
+        Some(value) => std::str::from_utf8(value)
+            .ok()
+            .and_then(|value| value.parse::<i64>().ok())
+            .ok_or_else(not_an_integer)?,
+        None => 0,
+    };
+    Ok(current.checked_add(delta).ok_or_else(not_an_integer)?)
+}
+
+pub(crate) fn encode(value: i64) -> Vec<u8> {
+    value.to_string().into_bytes()
+}
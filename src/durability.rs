@@ -0,0 +1,95 @@
+use {
+    crate::Result,
+    std::{
+        cmp,
+        sync::{Condvar, Mutex},
+        time::Duration,
+    },
+};
+
+// When a write is made durable with an fsync. A write is acknowledged only
+// once it reached the chosen durability point.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Durability {
+    // Leave flushing to the operating system.
+    None,
+    // Sync before acknowledging every write.
+    EveryWrite,
+    // Sync in the background every given period; a crash loses at most one
+    // period of acknowledged writes.
+    Interval(Duration),
+    // Like `EveryWrite`, but writers waiting at the same time share one sync.
+    GroupCommit,
+}
+
+// Coordinates group commit: every write takes a ticket, and whoever waits
+// first syncs on behalf of all tickets handed out so far.
+pub(crate) struct GroupCommit {
+    state: Mutex<CommitState>,
+    synced: Condvar,
+}
+
+struct CommitState {
+    written: u64,
+    synced: u64,
+    syncing: bool,
+}
+
+impl GroupCommit {
+    pub fn new() -> Self {
+        GroupCommit {
+            state: Mutex::new(CommitState {
+                written: 0,
+                synced: 0,
+                syncing: false,
+            }),
+            synced: Condvar::new(),
+        }
+    }
+
+    // Record a write that has reached the OS and return its ticket. Callers
+    // must hand out tickets in write order, e.g. under their writer lock.
+    pub fn written(&self) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        state.written += 1;
+        state.written
+    }
+
+    // Everything written so far has been synced by other means.
+    pub fn mark_synced(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.synced = cmp::max(state.synced, state.written);
+        self.synced.notify_all();
+    }
+
+    // Block until the write of `ticket` is durable, running `sync` if no other
+    // writer is syncing already.
+    pub fn wait<F>(&self, ticket: u64, sync: F) -> Result<()>
+    where
+        F: Fn() -> Result<()>,
+    {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if state.synced >= ticket {
+                return Ok(());
+            }
+            if state.syncing {
+                state = self.synced.wait(state).unwrap();
+                continue;
+            }
+            state.syncing = true;
+            let target = state.written;
+            drop(state);
+
+            let res = sync();
+
+            state = self.state.lock().unwrap();
+            state.syncing = false;
+            if res.is_ok() {
+                state.synced = cmp::max(state.synced, target);
+            }
+            self.synced.notify_all();
+            res?;
+        }
+    }
+}
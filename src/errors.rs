@@ -1,3 +1,6 @@
+// `#[derive(Fail)]` expands to impls nested inside a const block.
+#![allow(non_local_definitions)]
+
 use {
     failure::{Error, Fail},
     std::result,
@@ -15,4 +18,47 @@ pub enum KvsError {
     UnKnownOperation(String),
     #[fail(display = "no such log file {}", _0)]
     UnKnownLog(u64),
+    #[fail(display = "malformed frame: {}", _0)]
+    Frame(String),
+    #[fail(display = "server error: {}", _0)]
+    Server(String),
+    #[fail(display = "compaction failed: {}", _0)]
+    Compaction(String),
+    #[fail(display = "corrupted record in log {} at offset {}", log_id, offset)]
+    Corruption { log_id: u64, offset: u64 },
+    #[fail(display = "log record format version {} is newer than supported", _0)]
+    UnsupportedFormat(u8),
+    #[fail(display = "the store is opened read-only")]
+    ReadOnly,
+    #[fail(display = "no store at {}", _0)]
+    StoreNotFound(String),
+    #[fail(
+        display = "the store at {} was written by sled 0.22, which this build cannot open; \
+                   see \"Upgrading sled stores\" in the README",
+        _0
+    )]
+    LegacySled(String),
+    #[fail(display = "value of key {} is not an integer or would overflow", _0)]
+    NotAnInteger(String),
+    #[fail(display = "{} already holds data", _0)]
+    StoreExists(String),
+    #[fail(display = "migration failed: {}", _0)]
+    Migration(String),
+    #[fail(display = "transaction conflicts with a concurrent write")]
+    TransactionConflict,
+    #[fail(
+        display = "the store at {} was created by the {} engine, not {}",
+        dir, recorded, requested
+    )]
+    EngineMismatch {
+        dir: String,
+        recorded: String,
+        requested: String,
+    },
+    #[fail(display = "bad manifest: {}", _0)]
+    Manifest(String),
+    #[fail(display = "the store at {} is already open for writing", _0)]
+    Locked(String),
+    #[fail(display = "{} of {} bytes does not fit in a log record", what, len)]
+    TooLarge { what: &'static str, len: usize },
 }
@@ -0,0 +1,23 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Expiry times are stored as milliseconds since the Unix epoch, so they stay
+// meaningful across restarts.
+pub(crate) fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis() as u64)
+}
+
+// When a key given `ttl` now expires.
+pub(crate) fn deadline(ttl: Duration) -> u64 {
+    now_millis().saturating_add(ttl.as_millis() as u64)
+}
+
+pub(crate) fn is_expired(expires_at: u64) -> bool {
+    expires_at <= now_millis()
+}
+
+// Time left before `expires_at`, zero once it has passed.
+pub(crate) fn remaining(expires_at: u64) -> Duration {
+    Duration::from_millis(expires_at.saturating_sub(now_millis()))
+}
@@ -1,13 +1,179 @@
-use crate::Result;
+use {
+    crate::{KvsError, Result, Transaction, WriteBatch},
+    std::{
+        ops::{Bound, RangeBounds},
+        path::Path,
+        time::Duration,
+    },
+};
 
-pub trait KvsEngine{
-    //Set the value of a string key to a string.
+// Outcome of `compare_and_swap`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CasOutcome {
+    Swapped,
+    // The key did not hold the expected value; this is what it held instead.
+    Conflict { current: Option<Vec<u8>> },
+}
+
+// Key/value pairs in ascending key order.
+pub type KvsIter<'a> = Box<dyn Iterator<Item = Result<(Vec<u8>, Vec<u8>)>> + 'a>;
+
+// A read-only view of an engine as of the moment it was taken. Writes made
+// afterwards never show up in it.
+pub trait KvsSnapshot {
+    //Get the value a key had when the snapshot was taken.
+    fn get(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>>;
+    //Get the time left now before a key the snapshot holds expires, zero if
+    //it has expired since, or None if it never does.
+    //Return an error if the snapshot does not hold the key.
+    fn ttl(&self, key: Vec<u8>) -> Result<Option<Duration>>;
+    //Iterate over the keys in `range` as they were when the snapshot was taken.
+    fn scan<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Result<KvsIter<'_>>;
+    //Iterate over the keys starting with `prefix` in ascending order.
+    fn scan_prefix(&self, prefix: Vec<u8>) -> Result<KvsIter<'_>> {
+        self.scan(prefix_range(prefix))
+    }
+}
+
+// Engines are cloned into every worker thread, so clones must share state.
+// Keys and values are arbitrary bytes.
+pub trait KvsEngine: Clone + Send + 'static {
+    type Snapshot: KvsSnapshot + Send;
+
+    //Set the value of a key.
     //Return an error if the value is not written successfully.
-    fn set(&mut self, key: String, value: String) -> Result<()>;
-    //Get the string value of a string key. If the key does not exist, return None.
+    fn set(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()>;
+    //Get the value of a key. If the key does not exist, return None.
     //Return an error if the value is not read successfully.
-    fn get(&mut self, key: String) -> Result<Option<String>>;
-    //Remove a given string key.
+    fn get(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>>;
+    //Remove a given key.
     //Return an error if the key does not exit or value is not read successfully.
-    fn remove(&mut self, key: String) -> Result<()>;
+    fn remove(&self, key: Vec<u8>) -> Result<()>;
+    //Set the value of a key that disappears once `ttl` has passed. A plain
+    //`set` of the key later on makes it persistent again.
+    fn set_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()>;
+    //Make an existing key disappear once `ttl` has passed.
+    //Return an error if the key does not exist.
+    fn expire(&self, key: Vec<u8>, ttl: Duration) -> Result<()>;
+    //Get the time left before a key expires, or None if it never does.
+    //Return an error if the key does not exist.
+    fn ttl(&self, key: Vec<u8>) -> Result<Option<Duration>>;
+    //Make an existing key never expire.
+    //Return an error if the key does not exist.
+    fn persist(&self, key: Vec<u8>) -> Result<()>;
+    //Apply every write of the batch, or none of them if it fails or the
+    //process crashes meanwhile.
+    fn write_batch(&self, batch: WriteBatch) -> Result<()>;
+    //Apply the batch like `write_batch`, but only if no write since
+    //`snapshot` was taken touched a key in `read`, even one that wrote back
+    //the value the key had. A snapshot of another store counts as changed.
+    //Return whether the batch was applied.
+    fn write_batch_unless_written(
+        &self,
+        snapshot: &Self::Snapshot,
+        read: Vec<Vec<u8>>,
+        batch: WriteBatch,
+    ) -> Result<bool>;
+    //Replace the value of a key with `new` only if it currently is `expected`,
+    //where `None` stands for an absent key on either side.
+    fn compare_and_swap(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<CasOutcome>;
+    //Set the value of a key that does not exist yet. Return whether it was set.
+    fn set_if_absent(&self, key: Vec<u8>, value: Vec<u8>) -> Result<bool> {
+        Ok(self.compare_and_swap(key, None, Some(value))? == CasOutcome::Swapped)
+    }
+    //Overwrite the value of a key that exists. Return whether it was set.
+    fn set_if_present(&self, key: Vec<u8>, value: Vec<u8>) -> Result<bool> {
+        let mut current = self.get(key.clone())?;
+        while current.is_some() {
+            match self.compare_and_swap(key.clone(), current, Some(value.clone()))? {
+                CasOutcome::Swapped => return Ok(true),
+                CasOutcome::Conflict { current: actual } => current = actual,
+            }
+        }
+        Ok(false)
+    }
+    //Add `delta` to the integer value of a key, starting from 0 if it does not
+    //exist, and return the result. The expiry of the key is kept.
+    //Return an error if the stored value is not a decimal i64 or the result
+    //would overflow.
+    fn incr_by(&self, key: Vec<u8>, delta: i64) -> Result<i64>;
+    //Subtract `delta` from the integer value of a key, like `incr_by`.
+    fn decr_by(&self, key: Vec<u8>, delta: i64) -> Result<i64> {
+        match delta.checked_neg() {
+            Some(delta) => self.incr_by(key, delta),
+            None => Err(KvsError::NotAnInteger(String::from_utf8_lossy(&key).into_owned()).into()),
+        }
+    }
+    //Append `value` to the value of a key, creating it if it does not exist,
+    //and return the new length of the value. The expiry of the key is kept.
+    fn append(&self, key: Vec<u8>, value: Vec<u8>) -> Result<u64>;
+    //Iterate over the keys in `range` in ascending order, with their values.
+    fn scan<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Result<KvsIter<'_>>;
+    //Iterate over the keys starting with `prefix` in ascending order.
+    fn scan_prefix(&self, prefix: Vec<u8>) -> Result<KvsIter<'_>> {
+        self.scan(prefix_range(prefix))
+    }
+    //Take a snapshot for reading several keys as of this moment while writes
+    //carry on.
+    fn snapshot(&self) -> Result<Self::Snapshot>;
+    //Set many keys as fast as possible and return how many were set. Writes
+    //are neither atomic nor durable one by one, only once the load returns.
+    fn bulk_load<I>(&self, pairs: I) -> Result<u64>
+    where
+        I: IntoIterator<Item = Result<(Vec<u8>, Vec<u8>)>>;
+    //Write a consistent copy of the store to the empty or missing directory
+    //`path`, from which an engine of the same kind can be opened. The copy
+    //has a manifest of its own, recording the engine.
+    fn backup_to(&self, path: &Path) -> Result<()>;
+    //Start a transaction, which reads the store as of now. Nothing it writes
+    //is visible to others before it commits.
+    fn begin(&self) -> Result<Transaction<Self>> {
+        Transaction::new(self.clone())
+    }
+
+    //Set the value of a string key to a string.
+    fn set_string(&self, key: String, value: String) -> Result<()> {
+        self.set(key.into_bytes(), value.into_bytes())
+    }
+    //Get the string value of a string key.
+    //Return an error if the stored value is not valid UTF-8.
+    fn get_string(&self, key: String) -> Result<Option<String>> {
+        match self.get(key.into_bytes())? {
+            Some(value) => Ok(Some(String::from_utf8(value)?)),
+            None => Ok(None),
+        }
+    }
+    //Remove a given string key.
+    fn remove_string(&self, key: String) -> Result<()> {
+        self.remove(key.into_bytes())
+    }
+}
+
+// The range of keys starting with `prefix`: from the prefix itself up to, but
+// excluding, the prefix with its last byte below 0xff incremented.
+pub fn prefix_range(prefix: Vec<u8>) -> (Bound<Vec<u8>>, Bound<Vec<u8>>) {
+    let mut end = prefix.clone();
+    while let Some(last) = end.pop() {
+        if last < 0xff {
+            end.push(last + 1);
+            return (Bound::Included(prefix), Bound::Excluded(end));
+        }
+    }
+    (Bound::Included(prefix), Bound::Unbounded)
+}
+
+// `BTreeMap::range` panics on ranges that end before they start.
+pub(crate) fn is_empty_range(start: &Bound<Vec<u8>>, end: &Bound<Vec<u8>>) -> bool {
+    match (start, end) {
+        (Bound::Included(start), Bound::Included(end)) => start > end,
+        (Bound::Included(start), Bound::Excluded(end))
+        | (Bound::Excluded(start), Bound::Included(end))
+        | (Bound::Excluded(start), Bound::Excluded(end)) => start >= end,
+        _ => false,
+    }
 }
@@ -1,43 +1,711 @@
 use {
-    crate::{KvsEngine, KvsError, Result},
-    sled::*,
+    crate::{
+        counter, durability::GroupCommit, expiry, kvsengine::is_empty_range,
+        kvstore::create_empty_dir, BatchOp, CasOutcome, Durability, KvsEngine, KvsError, KvsIter,
+        KvsSnapshot, Manifest, Result, WriteBatch,
+    },
+    sled::{
+        transaction::{ConflictableTransactionResult, TransactionError, TransactionalTree},
+        *,
+    },
+    std::{
+        collections::{BTreeMap, VecDeque},
+        convert::TryInto,
+        fs, io, mem,
+        ops::{Bound, RangeBounds},
+        path::Path,
+        sync::{Arc, Mutex, MutexGuard, RwLock, Weak},
+        time::Duration,
+    },
 };
 
-pub struct SledKvsEngine{
+// Expiry times live in their own tree, keyed like the values, as u64 BE
+// milliseconds since the Unix epoch. Every write touches both trees in one
+// transaction.
+const TTL_TREE: &str = "ttl";
+
+// Version of the layout above, as recorded in the manifest. sled itself
+// refuses to open a database written in another of its formats.
+pub(crate) const FORMAT_VERSION: u32 = 1;
+
+// Recorded for stores written by sled 0.22, before the layout above, which
+// this build cannot open.
+pub(crate) const LEGACY_FORMAT_VERSION: u32 = 0;
+
+// Pairs applied at once by `bulk_load`.
+const LOAD_CHUNK: u64 = 1024;
+
+// Whether `dir` holds a database written by a sled older than 0.29, such as
+// the 0.22 earlier versions of this crate used. Since 0.29 sled writes its
+// `conf` file as text lines, one of them naming its version.
+pub(crate) fn is_legacy_db(dir: &Path) -> Result<bool> {
+    match fs::read(dir.join("conf")) {
+        Ok(conf) => Ok(!conf
+            .split(|&b| b == b'\n')
+            .any(|line| line.starts_with(b"version: "))),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[derive(Clone)]
+pub struct SledKvsEngine {
     engine: Db,
+    ttl: Tree,
+    durability: Durability,
+    group: Arc<GroupCommit>,
+    // Writes share this lock; taking a snapshot holds it exclusively, just
+    // long enough to register the snapshot.
+    snapshot_gate: Arc<RwLock<()>>,
+    // What keys held before writes changed them, for every live snapshot.
+    snapshots: Arc<Mutex<Vec<Weak<Mutex<Saved>>>>>,
 }
 
 impl SledKvsEngine {
-    pub fn new(db:Db) -> Self {
-        SledKvsEngine{ engine:db}
+    // Wrap an already started database, flushing on every write.
+    pub fn new(db: Db) -> Result<Self> {
+        SledKvsEngine::with_durability(db, Durability::EveryWrite)
+    }
+
+    // Start the database at `path`, letting sled's own background flusher
+    // handle `Durability::Interval`. Otherwise it keeps its default period, so
+    // that even with `Durability::None` writes leave sled's buffers soon and
+    // survive the process being killed, as they do with `KvStore`. A database
+    // written by sled 0.22 is refused, as sled 0.34 cannot read it.
+    pub fn open<P: AsRef<Path>>(path: P, durability: Durability) -> Result<Self> {
+        if is_legacy_db(path.as_ref())? {
+            return Err(KvsError::LegacySled(path.as_ref().display().to_string()).into());
+        }
+        let mut config = Config::new().path(path);
+        if let Durability::Interval(period) = durability {
+            config = config.flush_every_ms(Some(duration_ms(period)));
+        }
+        SledKvsEngine::with_durability(config.open()?, durability)
+    }
+
+    pub fn with_durability(db: Db, durability: Durability) -> Result<Self> {
+        Ok(SledKvsEngine {
+            ttl: db.open_tree(TTL_TREE)?,
+            engine: db,
+            durability,
+            group: Arc::new(GroupCommit::new()),
+            snapshot_gate: Arc::new(RwLock::new(())),
+            snapshots: Arc::new(Mutex::new(Vec::new())),
+        })
+    }
+
+    // Run `f` on the value and expiry trees as one transaction.
+    fn transaction<F, A>(&self, f: F) -> Result<A>
+    where
+        F: Fn(&TransactionalTree, &TransactionalTree) -> ConflictableTransactionResult<A>,
+    {
+        match (&*self.engine, &self.ttl).transaction(|(db, ttl)| f(db, ttl)) {
+            Ok(res) => Ok(res),
+            Err(TransactionError::Storage(e)) => Err(e.into()),
+            // No closure ever aborts.
+            Err(TransactionError::Abort(())) => unreachable!(),
+        }
+    }
+
+    // Run `f`, which may change `keys`, as one transaction.
+    fn write<'k, K, F, A>(&self, keys: K, f: F) -> Result<A>
+    where
+        K: IntoIterator<Item = &'k [u8]>,
+        F: Fn(&TransactionalTree, &TransactionalTree) -> ConflictableTransactionResult<A>,
+    {
+        self.changing(keys, || self.transaction(f))
+    }
+
+    // Run `f`, which may change `keys`, after saving what those keys hold for
+    // every live snapshot that has not saved them yet. Holding the snapshots
+    // serializes writes while any lives, so the keys cannot change between
+    // saving and writing.
+    fn changing<'k, K, F, A>(&self, keys: K, f: F) -> Result<A>
+    where
+        K: IntoIterator<Item = &'k [u8]>,
+        F: FnOnce() -> Result<A>,
+    {
+        let _gate = self.snapshot_gate.read().unwrap();
+        let snapshots = self.live_snapshots();
+        let saved = snapshots
+            .iter()
+            .map(|saved| saved.lock().unwrap())
+            .collect();
+        self.save_and_run(saved, keys, f)
+    }
+
+    // Run `f` like `changing`, unless a write since `snapshot` was taken
+    // touched a key in `read`: every such write saved the key for it. A
+    // snapshot of another store counts as changed.
+    fn changing_unless_written<'k, K, F, A>(
+        &self,
+        snapshot: &SledSnapshot,
+        read: &[Vec<u8>],
+        keys: K,
+        f: F,
+    ) -> Result<Option<A>>
+    where
+        K: IntoIterator<Item = &'k [u8]>,
+        F: FnOnce() -> Result<A>,
+    {
+        let _gate = self.snapshot_gate.read().unwrap();
+        let snapshots = self.live_snapshots();
+        let saved: Vec<MutexGuard<'_, Saved>> = snapshots
+            .iter()
+            .map(|saved| saved.lock().unwrap())
+            .collect();
+        let ours = match snapshots
+            .iter()
+            .position(|s| Arc::ptr_eq(s, &snapshot.saved))
+        {
+            Some(ours) => &saved[ours],
+            None => return Ok(None),
+        };
+        if read.iter().any(|key| ours.contains_key(key)) {
+            return Ok(None);
+        }
+        self.save_and_run(saved, keys, f).map(Some)
+    }
+
+    // Save `keys` for every locked snapshot in `saved`, then run `f`.
+    fn save_and_run<'k, K, F, A>(
+        &self,
+        mut saved: Vec<MutexGuard<'_, Saved>>,
+        keys: K,
+        f: F,
+    ) -> Result<A>
+    where
+        K: IntoIterator<Item = &'k [u8]>,
+        F: FnOnce() -> Result<A>,
+    {
+        if !saved.is_empty() {
+            for key in keys {
+                let entry = read_entry(&self.engine, &self.ttl, key)?;
+                for saved in saved.iter_mut() {
+                    saved.entry(key.to_vec()).or_insert_with(|| entry.clone());
+                }
+            }
+        }
+        f()
+    }
+
+    fn live_snapshots(&self) -> Vec<Arc<Mutex<Saved>>> {
+        let mut snapshots = self.snapshots.lock().unwrap();
+        snapshots.retain(|saved| saved.strong_count() > 0);
+        snapshots.iter().filter_map(Weak::upgrade).collect()
+    }
+
+    fn write_value(&self, key: &[u8], value: &[u8], expires_at: Option<u64>) -> Result<()> {
+        self.write([key], |db, ttl| {
+            db.insert(key, value)?;
+            match expires_at {
+                Some(expires_at) => ttl.insert(key, &expires_at.to_be_bytes())?,
+                None => ttl.remove(key)?,
+            };
+            Ok(())
+        })?;
+        self.after_write()
+    }
+
+    // Change the expiry of an existing key.
+    fn set_expiry(&self, key: &[u8], expires_at: Option<u64>) -> Result<()> {
+        let exists = self.write([key], |db, ttl| {
+            if lookup(db, ttl, key)?.is_none() {
+                return Ok(false);
+            }
+            match expires_at {
+                Some(expires_at) => ttl.insert(key, &expires_at.to_be_bytes())?,
+                None => ttl.remove(key)?,
+            };
+            Ok(true)
+        })?;
+        if !exists {
+            return Err(KvsError::Get(String::from_utf8_lossy(key).into_owned()).into());
+        }
+        self.after_write()
+    }
+
+    // The value of `key` and its expiry time, unless it is absent or expired.
+    // Expired keys are deleted as they are found.
+    fn lookup(&self, key: &[u8]) -> Result<Option<Entry>> {
+        match self.transaction(|db, ttl| read_entry_in(db, ttl, key))? {
+            Some((_, Some(expires_at))) if expiry::is_expired(expires_at) => {
+                self.purge(key, expires_at)?;
+                Ok(None)
+            }
+            entry => Ok(entry),
+        }
+    }
+
+    // Whether `key` has expired, deleting it if so.
+    fn purge_if_expired(&self, key: &[u8]) -> Result<bool> {
+        match self.ttl.get(key)?.and_then(|t| decode_expiry(&t)) {
+            Some(expires_at) if expiry::is_expired(expires_at) => {
+                self.purge(key, expires_at)?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    // Delete `key` from both trees, unless it was given another expiry time
+    // than the `expires_at` found to have passed. Nothing is flushed: a purge
+    // lost in a crash is simply done again.
+    fn purge(&self, key: &[u8], expires_at: u64) -> Result<()> {
+        self.write([key], |db, ttl| {
+            if ttl.get(key)?.and_then(|t| decode_expiry(&t)) == Some(expires_at) {
+                db.remove(key)?;
+                ttl.remove(key)?;
+            }
+            Ok(())
+        })
+    }
+
+    fn after_write(&self) -> Result<()> {
+        match self.durability {
+            Durability::EveryWrite => {
+                self.engine.flush()?;
+            }
+            Durability::GroupCommit => {
+                let ticket = self.group.written();
+                self.group.wait(ticket, || {
+                    self.engine.flush()?;
+                    Ok(())
+                })?;
+            }
+            Durability::None | Durability::Interval(_) => {}
+        }
+        Ok(())
+    }
+
+    fn load_chunk(&self, pairs: Vec<(Vec<u8>, Vec<u8>)>) -> Result<()> {
+        let mut values = Batch::default();
+        let mut expiries = Batch::default();
+        for (key, value) in &pairs {
+            expiries.remove(&key[..]);
+            values.insert(&key[..], &value[..]);
+        }
+        self.changing(pairs.iter().map(|(key, _)| &key[..]), || {
+            self.ttl.apply_batch(expiries)?;
+            self.engine.apply_batch(values)?;
+            Ok(())
+        })
     }
 }
 
+// The value of `key` and its expiry time, unless it is absent or expired.
+fn lookup(
+    db: &TransactionalTree,
+    ttl: &TransactionalTree,
+    key: &[u8],
+) -> ConflictableTransactionResult<Option<Entry>> {
+    match read_entry_in(db, ttl, key)? {
+        Some((_, Some(expires_at))) if expiry::is_expired(expires_at) => Ok(None),
+        entry => Ok(entry),
+    }
+}
+
+// `read_entry` inside a transaction.
+fn read_entry_in(
+    db: &TransactionalTree,
+    ttl: &TransactionalTree,
+    key: &[u8],
+) -> ConflictableTransactionResult<Option<Entry>> {
+    let value = match db.get(key)? {
+        Some(value) => value,
+        None => return Ok(None),
+    };
+    Ok(Some((value, ttl.get(key)?.and_then(|t| decode_expiry(&t)))))
+}
+
+// The value of `key` and its expiry time as the trees hold them, expired or
+// not.
+fn read_entry(db: &Tree, ttl: &Tree, key: &[u8]) -> Result<Option<Entry>> {
+    let value = match db.get(key)? {
+        Some(value) => value,
+        None => return Ok(None),
+    };
+    Ok(Some((value, ttl.get(key)?.and_then(|t| decode_expiry(&t)))))
+}
+
+// An expiry time of any other length than the 8 bytes written for it reads as
+// none, so that a damaged entry leaves its key without a TTL instead of
+// panicking every reader of the key.
+fn decode_expiry(buf: &[u8]) -> Option<u64> {
+    buf.try_into().ok().map(u64::from_be_bytes)
+}
+
+fn duration_ms(period: Duration) -> u64 {
+    // sled treats 0 as "flush constantly", so round up to at least 1ms.
+    (period.as_millis() as u64).max(1)
+}
+
 impl KvsEngine for SledKvsEngine {
-    fn set(&mut self, key: String, value: String) -> Result<()> {
-        self.engine.set(key, value.as_bytes())?;
-        self.engine.flush()?;
+    type Snapshot = SledSnapshot;
+
+    fn set(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.write_value(&key, &value, None)
+    }
+
+    fn get(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        Ok(self.lookup(&key)?.map(|(value, _)| value.to_vec()))
+    }
+
+    fn remove(&self, key: Vec<u8>) -> Result<()> {
+        let existed = self.write([&key[..]], |db, ttl| {
+            let existed = lookup(db, ttl, &key)?.is_some();
+            db.remove(&key[..])?;
+            ttl.remove(&key[..])?;
+            Ok(existed)
+        })?;
+        self.after_write()?;
+        if !existed {
+            return Err(KvsError::Remove(String::from_utf8_lossy(&key).into_owned()).into());
+        }
         Ok(())
     }
 
-    fn get(&mut self, key: String) -> Result<Option<String>> {
-        match self.engine.get(key)? {
-            Some(v) => {
-                let v: &[u8] = v.as_ref();
-                let v = String::from_utf8_lossy(v).to_string();
-                Ok(Some(v))
+    fn set_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        self.write_value(&key, &value, Some(expiry::deadline(ttl)))
+    }
+
+    fn expire(&self, key: Vec<u8>, ttl: Duration) -> Result<()> {
+        self.set_expiry(&key, Some(expiry::deadline(ttl)))
+    }
+
+    fn ttl(&self, key: Vec<u8>) -> Result<Option<Duration>> {
+        match self.lookup(&key)? {
+            Some((_, expires_at)) => Ok(expires_at.map(expiry::remaining)),
+            None => Err(KvsError::Get(String::from_utf8_lossy(&key).into_owned()).into()),
+        }
+    }
+
+    fn persist(&self, key: Vec<u8>) -> Result<()> {
+        self.set_expiry(&key, None)
+    }
+
+    // sled's own compare-and-swap cannot see expiry times, so this compares
+    // inside a transaction instead.
+    fn compare_and_swap(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<CasOutcome> {
+        let outcome = self.write([&key[..]], |db, ttl| {
+            let current = lookup(db, ttl, &key)?.map(|(value, _)| value.to_vec());
+            if current != expected {
+                return Ok(CasOutcome::Conflict { current });
+            }
+            match new {
+                Some(ref value) => db.insert(&key[..], &value[..])?,
+                None => db.remove(&key[..])?,
+            };
+            ttl.remove(&key[..])?;
+            Ok(CasOutcome::Swapped)
+        })?;
+        if outcome == CasOutcome::Swapped {
+            self.after_write()?;
+        }
+        Ok(outcome)
+    }
+
+    fn incr_by(&self, key: Vec<u8>, delta: i64) -> Result<i64> {
+        let res = self.write([&key[..]], |db, ttl| {
+            let current = match lookup(db, ttl, &key)? {
+                Some((value, _)) => Some(value),
+                // An expired key starts over without an expiry.
+                None => {
+                    ttl.remove(&key[..])?;
+                    None
+                }
+            };
+            // Nothing is written when the value is not an integer.
+            let value = match counter::add(&key, current.as_deref(), delta) {
+                Ok(value) => value,
+                Err(e) => return Ok(Err(e)),
+            };
+            db.insert(&key[..], counter::encode(value))?;
+            Ok(Ok(value))
+        })?;
+        let value = res?;
+        self.after_write()?;
+        Ok(value)
+    }
+
+    fn append(&self, key: Vec<u8>, value: Vec<u8>) -> Result<u64> {
+        let len = self.write([&key[..]], |db, ttl| {
+            let mut current = match lookup(db, ttl, &key)? {
+                Some((current, _)) => current.to_vec(),
+                None => {
+                    ttl.remove(&key[..])?;
+                    Vec::new()
+                }
+            };
+            current.extend_from_slice(&value);
+            let len = current.len() as u64;
+            db.insert(&key[..], current)?;
+            Ok(len)
+        })?;
+        self.after_write()?;
+        Ok(len)
+    }
+
+    fn scan<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Result<KvsIter<'_>> {
+        let iter = self.engine.range(range).filter_map(move |pair| {
+            let (key, value) = match pair {
+                Ok(pair) => pair,
+                Err(e) => return Some(Err(e.into())),
+            };
+            match self.purge_if_expired(&key) {
+                Ok(true) => None,
+                Ok(false) => Some(Ok((key.to_vec(), value.to_vec()))),
+                Err(e) => Some(Err(e)),
+            }
+        });
+        Ok(Box::new(iter))
+    }
+
+    // sled cannot read a consistent view of many keys, so nothing is copied
+    // up front: instead, while the snapshot lives, writes save what keys held
+    // before changing them. It costs memory in proportion to the changes.
+    fn snapshot(&self) -> Result<SledSnapshot> {
+        let saved = Arc::new(Mutex::new(BTreeMap::new()));
+        let _gate = self.snapshot_gate.write().unwrap();
+        self.snapshots.lock().unwrap().push(Arc::downgrade(&saved));
+        Ok(SledSnapshot {
+            engine: self.engine.clone(),
+            ttl: self.ttl.clone(),
+            saved,
+            taken_at: expiry::now_millis(),
+        })
+    }
+
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        let (keys, values, expiries) = split_batch(batch);
+        self.write(keys.iter().map(Vec::as_slice), |db, ttl| {
+            db.apply_batch(&values)?;
+            ttl.apply_batch(&expiries)?;
+            Ok(())
+        })?;
+        if !keys.is_empty() {
+            self.after_write()?;
+        }
+        Ok(())
+    }
+
+    // Applies each chunk as a plain sled batch, outside of any transaction,
+    // and flushes once at the end.
+    fn bulk_load<I>(&self, pairs: I) -> Result<u64>
+    where
+        I: IntoIterator<Item = Result<(Vec<u8>, Vec<u8>)>>,
+    {
+        let mut loaded = 0;
+        let mut chunk = Vec::new();
+        for pair in pairs {
+            chunk.push(pair?);
+            loaded += 1;
+            if loaded % LOAD_CHUNK == 0 {
+                self.load_chunk(mem::take(&mut chunk))?;
+            }
+        }
+        self.load_chunk(chunk)?;
+        if self.durability != Durability::None {
+            self.engine.flush()?;
+        }
+        Ok(loaded)
+    }
+
+    // Copies the live pairs of a snapshot into a new database, so writes
+    // carry on during the copy.
+    fn backup_to(&self, path: &Path) -> Result<()> {
+        create_empty_dir(path)?;
+        let backup = Config::new().path(path).open()?;
+        let backup_ttl = backup.open_tree(TTL_TREE)?;
+        let snapshot = self.snapshot()?;
+        for entry in snapshot.entries(..) {
+            let (key, (value, expires_at)) = entry?;
+            if let Some(expires_at) = expires_at {
+                backup_ttl.insert(&key, &expires_at.to_be_bytes())?;
+            }
+            backup.insert(key, value)?;
+        }
+        backup.flush()?;
+        Manifest::new("sled")?.write(path)
+    }
+
+    fn write_batch_unless_written(
+        &self,
+        snapshot: &SledSnapshot,
+        read: Vec<Vec<u8>>,
+        batch: WriteBatch,
+    ) -> Result<bool> {
+        let (keys, values, expiries) = split_batch(batch);
+        let written =
+            self.changing_unless_written(snapshot, &read, keys.iter().map(Vec::as_slice), || {
+                self.transaction(|db, ttl| {
+                    db.apply_batch(&values)?;
+                    ttl.apply_batch(&expiries)?;
+                    Ok(())
+                })
+            })?;
+        if written.is_some() && !keys.is_empty() {
+            self.after_write()?;
+        }
+        Ok(written.is_some())
+    }
+}
+
+// The keys a batch writes, and its writes to the value and expiry trees.
+// Setting keys clears their expiry, as `set` does.
+fn split_batch(batch: WriteBatch) -> (Vec<Vec<u8>>, Batch, Batch) {
+    let mut keys = Vec::new();
+    let mut values = Batch::default();
+    let mut expiries = Batch::default();
+    for op in batch.into_ops() {
+        match op {
+            BatchOp::Set { key, value } => {
+                expiries.remove(&key[..]);
+                values.insert(&key[..], value);
+                keys.push(key);
+            }
+            BatchOp::Remove { key } => {
+                expiries.remove(&key[..]);
+                values.remove(&key[..]);
+                keys.push(key);
             }
-            None => Ok(None),
         }
     }
+    (keys, values, expiries)
+}
+
+// A value and its expiry time.
+type Entry = (IVec, Option<u64>);
+
+// What keys changed since a snapshot held before, `None` if they were absent.
+type Saved = BTreeMap<Vec<u8>, Option<Entry>>;
+
+// The pairs of a `SledKvsEngine` at one moment. Keys changed since then are
+// read from what the writes saved, the others from the trees.
+pub struct SledSnapshot {
+    engine: Db,
+    ttl: Tree,
+    saved: Arc<Mutex<Saved>>,
+    taken_at: u64,
+}
 
-    fn remove(&mut self, key: String) -> Result<()> {
-        let value = self.engine
-            .del(key.clone())?;
-        self.engine.flush()?;
-        if let None = value {
-            return Err(KvsError::Remove(key).into());
+impl SledSnapshot {
+    // The entry of `key` as of the snapshot. Holding `saved` keeps writes
+    // from changing the key meanwhile.
+    fn entry(&self, saved: &Saved, key: &[u8]) -> Result<Option<Entry>> {
+        match saved.get(key) {
+            Some(entry) => Ok(entry.clone()),
+            None => read_entry(&self.engine, &self.ttl, key),
+        }
+    }
+
+    // `entry`, unless it had expired when the snapshot was taken.
+    fn live(&self, entry: Option<Entry>) -> Option<Entry> {
+        entry.filter(|(_, expires_at)| !matches!(*expires_at, Some(at) if at <= self.taken_at))
+    }
+
+    fn entries<R: RangeBounds<Vec<u8>>>(&self, range: R) -> SnapshotIter<'_> {
+        let (start, end) = (range.start_bound().cloned(), range.end_bound().cloned());
+        let done = is_empty_range(&start, &end);
+        let keys: Box<dyn Iterator<Item = sled::Result<IVec>>> = match done {
+            true => Box::new(std::iter::empty()),
+            false => Box::new(self.engine.range((start.clone(), end.clone())).keys()),
+        };
+        SnapshotIter {
+            snapshot: self,
+            keys,
+            from: start,
+            end,
+            ready: VecDeque::new(),
+            done,
+        }
+    }
+}
+
+impl KvsSnapshot for SledSnapshot {
+    fn get(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        let saved = self.saved.lock().unwrap();
+        let entry = self.live(self.entry(&saved, &key)?);
+        Ok(entry.map(|(value, _)| value.to_vec()))
+    }
+
+    fn ttl(&self, key: Vec<u8>) -> Result<Option<Duration>> {
+        let saved = self.saved.lock().unwrap();
+        match self.live(self.entry(&saved, &key)?) {
+            Some((_, expires_at)) => Ok(expires_at.map(expiry::remaining)),
+            None => Err(KvsError::Get(String::from_utf8_lossy(&key).into_owned()).into()),
+        }
+    }
+
+    fn scan<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Result<KvsIter<'_>> {
+        let iter = self
+            .entries(range)
+            .map(|entry| entry.map(|(key, (value, _))| (key, value.to_vec())));
+        Ok(Box::new(iter))
+    }
+}
+
+// The live entries of a snapshot in key order. Keys removed since the
+// snapshot are gone from the trees, so the saved keys are merged in.
+struct SnapshotIter<'a> {
+    snapshot: &'a SledSnapshot,
+    keys: Box<dyn Iterator<Item = sled::Result<IVec>> + 'a>,
+    // Saved keys from here on are yet to be merged.
+    from: Bound<Vec<u8>>,
+    end: Bound<Vec<u8>>,
+    ready: VecDeque<(Vec<u8>, Entry)>,
+    done: bool,
+}
+
+impl SnapshotIter<'_> {
+    // Queue the saved keys before the next key in the trees, and that key.
+    fn advance(&mut self) -> Result<()> {
+        let key = self.keys.next().transpose()?;
+        let saved = self.snapshot.saved.lock().unwrap();
+        let upto = match key {
+            Some(ref key) => Bound::Excluded(key.to_vec()),
+            None => {
+                self.done = true;
+                self.end.clone()
+            }
+        };
+        if !is_empty_range(&self.from, &upto) {
+            for (key, entry) in saved.range((self.from.clone(), upto)) {
+                if let Some(entry) = self.snapshot.live(entry.clone()) {
+                    self.ready.push_back((key.clone(), entry));
+                }
+            }
+        }
+        if let Some(key) = key {
+            if let Some(entry) = self.snapshot.live(self.snapshot.entry(&saved, &key)?) {
+                self.ready.push_back((key.to_vec(), entry));
+            }
+            self.from = Bound::Excluded(key.to_vec());
         }
         Ok(())
     }
 }
+
+impl Iterator for SnapshotIter<'_> {
+    type Item = Result<(Vec<u8>, Entry)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(entry) = self.ready.pop_front() {
+                return Some(Ok(entry));
+            }
+            if self.done {
+                return None;
+            }
+            if let Err(e) = self.advance() {
+                self.done = true;
+                return Some(Err(e));
+            }
+        }
+    }
+}
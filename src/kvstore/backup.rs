@@ -0,0 +1,53 @@
+use {
+    super::{
+        format::encode_record,
+        hint::{write_hint, HintEntry},
+        log::{compaction_path, log_path, BufWriterWithPos, Command},
+        snapshot::KvStoreSnapshot,
+    },
+    crate::{KvsError, Manifest, Result},
+    std::{fs, io::Write, path::Path},
+};
+
+// Write the live keys of `snapshot` to a new store at `dir` as a single
+// compacted log with its hint file, and a manifest of its own. The log only
+// appears under its own name once complete, so an interrupted backup never
+// looks like a store.
+pub(super) fn write_backup(snapshot: &KvStoreSnapshot, dir: &Path) -> Result<()> {
+    create_empty_dir(dir)?;
+    let tmp_path = compaction_path(dir, 1);
+    let file = fs::OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .open(&tmp_path)?;
+    let mut writer = BufWriterWithPos::new(file)?;
+    let mut hints = Vec::new();
+    for cmd in snapshot.commands() {
+        let cmd = cmd?;
+        let offset = writer.pos;
+        writer.write_all(&encode_record(&cmd)?)?;
+        if let Command::Set { key, .. } = cmd {
+            hints.push(HintEntry {
+                key,
+                offset,
+                length: writer.pos - offset,
+                tombstone: false,
+            });
+        }
+    }
+    writer.flush()?;
+    writer.get_ref().sync_all()?;
+    fs::rename(&tmp_path, log_path(dir, 1))?;
+    write_hint(dir, 1, writer.pos, &hints)?;
+    Manifest::new("kvs")?.write(dir)
+}
+
+// Backups never overwrite anything.
+pub fn create_empty_dir(dir: &Path) -> Result<()> {
+    if dir.exists() && fs::read_dir(dir)?.next().is_some() {
+        return Err(KvsError::StoreExists(dir.display().to_string()).into());
+    }
+    fs::create_dir_all(dir)?;
+    Ok(())
+}
@@ -0,0 +1,207 @@
+use {
+    super::{
+        format::{encode_record, scan_record, Scan},
+        generation::{Generation, Generations},
+        hint::{read_hint, write_hint, HintEntry},
+        log::{
+            compaction_path, log_path, read_all_log_idx_and_sort, BufWriterWithPos, Command, Record,
+        },
+        open_for_scan,
+        reader::KvStoreReader,
+        snapshot::{Pins, Versions},
+        Index,
+    },
+    crate::{expiry, KvsError, Result},
+    std::{
+        collections::BTreeSet,
+        fs,
+        io::{Seek, Write},
+        path::{Path, PathBuf},
+        sync::{Arc, Mutex},
+    },
+};
+
+// Merges the logs `inputs`, all older than `compaction_id`, into the log
+// `compaction_id`. The writer has already moved on to a newer log, so the
+// inputs are immutable. Logs left out keep their records where they are.
+pub(super) struct Compaction {
+    pub path: Arc<PathBuf>,
+    pub reader: KvStoreReader,
+    pub index: Arc<Index>,
+    pub compaction_id: u64,
+    pub inputs: Vec<u64>,
+    pub pins: Arc<Pins>,
+    pub versions: Arc<Versions>,
+    pub generations: Arc<Mutex<Generations>>,
+}
+
+impl Compaction {
+    pub fn run(self) -> Result<()> {
+        let compaction_id = self.compaction_id;
+        let inputs: BTreeSet<u64> = self.inputs.iter().cloned().collect();
+        // A record that an input removed may live on in an older log that is
+        // not merged, so the removal has to outlive the input.
+        let oldest_kept = read_all_log_idx_and_sort(&self.path)?
+            .into_iter()
+            .find(|log_id| !inputs.contains(log_id));
+        let shadows_kept = |log_id: u64| oldest_kept.is_some_and(|kept| kept < log_id);
+        let mut removed = BTreeSet::new();
+        for &log_id in inputs.iter().filter(|&&log_id| shadows_kept(log_id)) {
+            removed.extend(tombstones(&self.path, log_id)?);
+        }
+
+        let live: Vec<(Vec<u8>, Record)> = {
+            let index = self.index.read().unwrap();
+            // Keys set again since then need no tombstone.
+            removed.retain(|key| !index.contains_key(key));
+            index
+                .iter()
+                .filter(|(_, rcd)| inputs.contains(&rcd.log_id))
+                .map(|(key, &rcd)| (key.clone(), rcd))
+                .collect()
+        };
+        if live.is_empty() && removed.is_empty() {
+            return self.finish(Vec::new(), Vec::new(), None);
+        }
+
+        // Copy without holding any lock: gets and sets carry on meanwhile.
+        let tmp_path = compaction_path(&self.path, compaction_id);
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&tmp_path)?;
+        let mut compaction_writer = BufWriterWithPos::new(file)?;
+        let mut moved = Vec::with_capacity(live.len());
+        let mut expired = Vec::new();
+        let mut hints = Vec::with_capacity(live.len() + removed.len());
+        for (key, rcd) in live {
+            // Records in an older format are rewritten in the current one.
+            let cmd = self.reader.read_command(rcd)?;
+            if let Command::Set {
+                expires_at: Some(expires_at),
+                ..
+            } = cmd
+            {
+                if expiry::is_expired(expires_at) {
+                    if shadows_kept(rcd.log_id) {
+                        removed.insert(key.clone());
+                    }
+                    expired.push((key, rcd));
+                    continue;
+                }
+            }
+            let new_rcd = write_record(&mut compaction_writer, &cmd, compaction_id)?;
+            hints.push(HintEntry {
+                key: key.clone(),
+                offset: new_rcd.offset,
+                length: new_rcd.length,
+                tombstone: false,
+            });
+            moved.push((key, rcd, new_rcd));
+        }
+        for key in removed {
+            let cmd = Command::Remove { key: key.clone() };
+            let new_rcd = write_record(&mut compaction_writer, &cmd, compaction_id)?;
+            hints.push(HintEntry {
+                key,
+                offset: new_rcd.offset,
+                length: new_rcd.length,
+                tombstone: true,
+            });
+        }
+        compaction_writer.flush()?;
+        if hints.is_empty() {
+            // Every key had expired.
+            drop(compaction_writer);
+            fs::remove_file(&tmp_path)?;
+            return self.finish(moved, expired, None);
+        }
+        compaction_writer.get_ref().sync_all()?;
+        fs::rename(&tmp_path, log_path(&self.path, compaction_id))?;
+        let total = compaction_writer.pos;
+        write_hint(&self.path, compaction_id, total, &hints)?;
+        self.finish(moved, expired, Some(total))
+    }
+
+    // Swap all entries at once, skipping keys that were overwritten or removed
+    // while copying, then retire the inputs. Expired keys are dropped, as no
+    // older record of theirs survives without a tombstone. The tombstones
+    // written count as live, so they do not make the output look worth merging
+    // again.
+    fn finish(
+        &self,
+        moved: Vec<(Vec<u8>, Record, Record)>,
+        expired: Vec<(Vec<u8>, Record)>,
+        output_size: Option<u64>,
+    ) -> Result<()> {
+        {
+            let mut index = self.index.write().unwrap();
+            let mut stale = 0;
+            for (key, old_rcd, new_rcd) in moved {
+                match index.get_mut(&key) {
+                    Some(rcd) if *rcd == old_rcd => *rcd = new_rcd,
+                    _ => stale += new_rcd.length,
+                }
+            }
+            // Snapshots taken before the keys expired still see them.
+            let dropped: Vec<_> = expired
+                .into_iter()
+                .filter(|(key, old_rcd)| index.get(key) == Some(old_rcd))
+                .collect();
+            for (key, _) in &dropped {
+                index.remove(key);
+            }
+            if !dropped.is_empty() {
+                self.versions
+                    .written(dropped.iter().map(|(key, rcd)| (&key[..], Some(*rcd))));
+            }
+            let output = output_size.map(|total| (self.compaction_id, Generation { total, stale }));
+            self.generations
+                .lock()
+                .unwrap()
+                .merged(&self.inputs, output);
+        }
+
+        self.reader.logs_merged();
+        self.pins.retire(&self.path, &self.inputs)
+    }
+}
+
+fn write_record<W: Write + Seek>(
+    writer: &mut BufWriterWithPos<W>,
+    cmd: &Command,
+    log_id: u64,
+) -> Result<Record> {
+    let offset = writer.pos;
+    writer.write_all(&encode_record(cmd)?)?;
+    Ok(Record {
+        log_id,
+        offset,
+        length: writer.pos - offset,
+    })
+}
+
+// The keys that a log removes.
+fn tombstones(path: &Path, log_id: u64) -> Result<Vec<Vec<u8>>> {
+    let size = fs::metadata(log_path(path, log_id))?.len();
+    if let Some(entries) = read_hint(path, log_id, size)? {
+        let keys = entries.into_iter().filter(|entry| entry.tombstone);
+        return Ok(keys.map(|entry| entry.key).collect());
+    }
+    let (mut reader, format, size) = open_for_scan(path, log_id, 0)?;
+    let mut offset = 0;
+    let mut keys = Vec::new();
+    loop {
+        match scan_record(&mut reader, format, size.saturating_sub(offset))? {
+            Scan::Record(cmd, length) => {
+                if let Command::Remove { key } = cmd {
+                    keys.push(key);
+                }
+                offset += length;
+            }
+            Scan::End => return Ok(keys),
+            Scan::Bad => return Err(KvsError::Corruption { log_id, offset }.into()),
+        }
+    }
+}
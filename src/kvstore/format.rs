@@ -0,0 +1,318 @@
+use {
+    super::log::Command,
+    crate::{KvsError, Result},
+    serde::Deserialize,
+    serde_json::Deserializer,
+    std::{
+        convert::TryFrom,
+        io::{self, Read},
+    },
+};
+
+// Current records are binary:
+//
+//   magic: [u8; 4] | version: u8 | kind: u8 | key_len: u32 LE | value_len: u32 LE
+//   | crc32: u32 LE | key | value
+//
+// The checksum covers everything after the magic except the checksum itself.
+// Version 2 added the batch markers, whose value is the batch size as u32 LE
+// for a begin and empty for a commit. Version 3 added sets with an expiry,
+// whose value starts with the expiry time as u64 LE.
+const MAGIC: [u8; 4] = [0x89, b'K', b'V', b'S'];
+pub(crate) const FORMAT_VERSION: u8 = 3;
+const HEADER_LEN: usize = 18;
+
+const KIND_SET: u8 = 0;
+const KIND_REMOVE: u8 = 1;
+const KIND_BATCH_BEGIN: u8 = 2;
+const KIND_BATCH_COMMIT: u8 = 3;
+const KIND_SET_EXPIRING: u8 = 4;
+
+// Older logs were written as bare JSON commands, then as JSON framed by
+// `[payload length: u32 LE][crc32 of payload: u32 LE]`.
+const FRAMED_JSON_HEADER_LEN: usize = 8;
+
+// JSON logs could only hold string keys and values.
+#[derive(Deserialize)]
+enum JsonCommand {
+    Set { key: String, value: String },
+    Remove { key: String },
+}
+
+impl From<JsonCommand> for Command {
+    fn from(cmd: JsonCommand) -> Command {
+        match cmd {
+            JsonCommand::Set { key, value } => Command::Set {
+                key: key.into_bytes(),
+                value: value.into_bytes(),
+                expires_at: None,
+            },
+            JsonCommand::Remove { key } => Command::Remove {
+                key: key.into_bytes(),
+            },
+        }
+    }
+}
+
+// On-disk encoding of a whole log. A log is never appended to in an older
+// format; compaction rewrites its live records as `Binary`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(super) enum LogFormat {
+    Json,
+    FramedJson,
+    Binary,
+}
+
+impl LogFormat {
+    // Tell the format from the first bytes of a log. Empty logs are `Binary`.
+    pub fn detect(prefix: &[u8]) -> LogFormat {
+        if prefix.is_empty() || prefix.starts_with(&MAGIC) {
+            LogFormat::Binary
+        } else if prefix[0] == b'{' {
+            LogFormat::Json
+        } else {
+            LogFormat::FramedJson
+        }
+    }
+}
+
+// Outcome of decoding the next record of a log.
+pub(super) enum Scan {
+    // Clean end of the log.
+    End,
+    // A partial, mis-checksummed or undecodable record.
+    Bad,
+    // A valid command and the length of its record.
+    Record(Command, u64),
+}
+
+// Fail with `KvsError::TooLarge` if the key or value is 4 GiB or more, which
+// the length fields cannot hold.
+pub(super) fn encode_record(cmd: &Command) -> Result<Vec<u8>> {
+    let count;
+    let expiring;
+    let (kind, key, value) = match cmd {
+        Command::Set {
+            key,
+            value,
+            expires_at: None,
+        } => (KIND_SET, &key[..], &value[..]),
+        Command::Set {
+            key,
+            value,
+            expires_at: Some(expires_at),
+        } => {
+            expiring = [&expires_at.to_le_bytes()[..], value].concat();
+            (KIND_SET_EXPIRING, &key[..], &expiring[..])
+        }
+        Command::Remove { key } => (KIND_REMOVE, &key[..], &[][..]),
+        Command::BatchBegin { count: n } => {
+            count = n.to_le_bytes();
+            (KIND_BATCH_BEGIN, &[][..], &count[..])
+        }
+        Command::BatchCommit => (KIND_BATCH_COMMIT, &[][..], &[][..]),
+    };
+    let key_len = length_field("key", key.len())?;
+    let value_len = length_field("value", value.len())?;
+    let mut buf = Vec::with_capacity(HEADER_LEN + key.len() + value.len());
+    buf.extend_from_slice(&MAGIC);
+    buf.push(FORMAT_VERSION);
+    buf.push(kind);
+    buf.extend_from_slice(&key_len.to_le_bytes());
+    buf.extend_from_slice(&value_len.to_le_bytes());
+    let crc = checksum(&buf[MAGIC.len()..], key, value);
+    buf.extend_from_slice(&crc.to_le_bytes());
+    buf.extend_from_slice(key);
+    buf.extend_from_slice(value);
+    Ok(buf)
+}
+
+pub(super) fn length_field(what: &'static str, len: usize) -> Result<u32> {
+    u32::try_from(len).map_err(|_| KvsError::TooLarge { what, len }.into())
+}
+
+// Decode one whole record, as located by a `Record`, in whichever format it
+// was written.
+pub(super) fn decode_record(buf: &[u8]) -> Result<Option<Command>> {
+    match LogFormat::detect(buf) {
+        LogFormat::Binary => {
+            if buf.len() < HEADER_LEN {
+                return Ok(None);
+            }
+            let (header, body) = buf.split_at(HEADER_LEN);
+            let (key_len, value_len) = check_header(header)?;
+            if body.len() as u64 != key_len + value_len {
+                return Ok(None);
+            }
+            Ok(decode_body(header, body, key_len as usize))
+        }
+        LogFormat::FramedJson => {
+            if buf.len() < FRAMED_JSON_HEADER_LEN {
+                return Ok(None);
+            }
+            let (header, payload) = buf.split_at(FRAMED_JSON_HEADER_LEN);
+            let len = read_u32(&header[0..4]);
+            if payload.len() != len as usize || crc32fast::hash(payload) != read_u32(&header[4..8])
+            {
+                return Ok(None);
+            }
+            Ok(decode_json(payload))
+        }
+        LogFormat::Json => Ok(decode_json(buf)),
+    }
+}
+
+// Read the record at the current position of `reader`, which has `remaining`
+// bytes left before the end of the log as measured before scanning it. A log
+// still being written may have grown since: records past that end are left
+// for the next scan, and a record cut short is `Bad`.
+pub(super) fn scan_record<R: Read>(
+    reader: &mut R,
+    format: LogFormat,
+    remaining: u64,
+) -> Result<Scan> {
+    if remaining == 0 {
+        return Ok(Scan::End);
+    }
+    match format {
+        LogFormat::Binary => scan_binary(reader, remaining),
+        LogFormat::FramedJson => scan_framed_json(reader, remaining),
+        LogFormat::Json => scan_json(reader),
+    }
+}
+
+fn scan_binary<R: Read>(reader: &mut R, remaining: u64) -> Result<Scan> {
+    let mut header = [0u8; HEADER_LEN];
+    match read_full(reader, &mut header)? {
+        0 => return Ok(Scan::End),
+        HEADER_LEN => {}
+        _ => return Ok(Scan::Bad),
+    }
+    if !header.starts_with(&MAGIC) {
+        return Ok(Scan::Bad);
+    }
+    let (key_len, value_len) = check_header(&header)?;
+    let body_len = key_len + value_len;
+    if body_len > remaining.saturating_sub(HEADER_LEN as u64) {
+        return Ok(Scan::Bad);
+    }
+    let mut body = vec![0u8; body_len as usize];
+    if read_full(reader, &mut body)? < body.len() {
+        return Ok(Scan::Bad);
+    }
+    Ok(match decode_body(&header, &body, key_len as usize) {
+        Some(cmd) => Scan::Record(cmd, HEADER_LEN as u64 + body_len),
+        None => Scan::Bad,
+    })
+}
+
+fn scan_framed_json<R: Read>(reader: &mut R, remaining: u64) -> Result<Scan> {
+    let mut header = [0u8; FRAMED_JSON_HEADER_LEN];
+    match read_full(reader, &mut header)? {
+        0 => return Ok(Scan::End),
+        FRAMED_JSON_HEADER_LEN => {}
+        _ => return Ok(Scan::Bad),
+    }
+    let len = read_u32(&header[0..4]) as u64;
+    if len > remaining.saturating_sub(FRAMED_JSON_HEADER_LEN as u64) {
+        return Ok(Scan::Bad);
+    }
+    let mut payload = vec![0u8; len as usize];
+    if read_full(reader, &mut payload)? < payload.len() {
+        return Ok(Scan::Bad);
+    }
+    if crc32fast::hash(&payload) != read_u32(&header[4..8]) {
+        return Ok(Scan::Bad);
+    }
+    Ok(match decode_json(&payload) {
+        Some(cmd) => Scan::Record(cmd, FRAMED_JSON_HEADER_LEN as u64 + len),
+        None => Scan::Bad,
+    })
+}
+
+// Bare JSON has no length prefix, so let serde find where the command ends.
+fn scan_json<R: Read>(reader: &mut R) -> Result<Scan> {
+    let mut stream = Deserializer::from_reader(reader).into_iter::<JsonCommand>();
+    let scan = match stream.next() {
+        None => Scan::End,
+        Some(Ok(cmd)) => Scan::Record(cmd.into(), stream.byte_offset() as u64),
+        Some(Err(_)) => Scan::Bad,
+    };
+    Ok(scan)
+}
+
+// Validate the version and return the key and value lengths.
+fn check_header(header: &[u8]) -> Result<(u64, u64)> {
+    let version = header[4];
+    if version > FORMAT_VERSION {
+        return Err(KvsError::UnsupportedFormat(version).into());
+    }
+    Ok((
+        read_u32(&header[6..10]) as u64,
+        read_u32(&header[10..14]) as u64,
+    ))
+}
+
+fn decode_body(header: &[u8], body: &[u8], key_len: usize) -> Option<Command> {
+    let (key, value) = body.split_at(key_len);
+    if checksum(&header[MAGIC.len()..14], key, value) != read_u32(&header[14..18]) {
+        return None;
+    }
+    let key = key.to_vec();
+    match header[5] {
+        KIND_SET => Some(Command::Set {
+            key,
+            value: value.to_vec(),
+            expires_at: None,
+        }),
+        KIND_SET_EXPIRING if value.len() >= 8 => Some(Command::Set {
+            key,
+            value: value[8..].to_vec(),
+            expires_at: Some(read_u64(&value[..8])),
+        }),
+        KIND_REMOVE => Some(Command::Remove { key }),
+        KIND_BATCH_BEGIN if value.len() == 4 => Some(Command::BatchBegin {
+            count: read_u32(value),
+        }),
+        KIND_BATCH_COMMIT => Some(Command::BatchCommit),
+        _ => None,
+    }
+}
+
+fn decode_json(buf: &[u8]) -> Option<Command> {
+    serde_json::from_slice::<JsonCommand>(buf)
+        .ok()
+        .map(Command::from)
+}
+
+fn checksum(header: &[u8], key: &[u8], value: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(header);
+    hasher.update(key);
+    hasher.update(value);
+    hasher.finalize()
+}
+
+fn read_u32(buf: &[u8]) -> u32 {
+    u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]])
+}
+
+fn read_u64(buf: &[u8]) -> u64 {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&buf[..8]);
+    u64::from_le_bytes(bytes)
+}
+
+// Like `read_exact`, but report how much was read before EOF instead of failing.
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match reader.read(&mut buf[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(read)
+}
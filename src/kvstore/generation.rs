@@ -0,0 +1,80 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+// Bytes written to one log, and how many of them are stale: records since
+// overwritten or removed, tombstones and batch markers.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub(super) struct Generation {
+    pub total: u64,
+    pub stale: u64,
+}
+
+impl Generation {
+    fn garbage_ratio(self) -> f64 {
+        match self.total {
+            0 => 0.0,
+            total => self.stale as f64 / total as f64,
+        }
+    }
+}
+
+// The generation of every log, kept up to date by the writer and by
+// compaction, which replaces the generations it merges with its output's.
+// Stale bytes are charged, and generations replaced, under the index write
+// lock, so they always go to the log the index pointed to.
+#[derive(Debug, Default)]
+pub(super) struct Generations {
+    logs: BTreeMap<u64, Generation>,
+    // Logs in an older format, which are merged whatever their garbage.
+    legacy: BTreeSet<u64>,
+}
+
+impl Generations {
+    pub fn written(&mut self, log_id: u64, bytes: u64) {
+        self.logs.entry(log_id).or_default().total += bytes;
+    }
+
+    pub fn stale(&mut self, log_id: u64, bytes: u64) {
+        self.logs.entry(log_id).or_default().stale += bytes;
+    }
+
+    pub fn legacy(&mut self, log_id: u64) {
+        self.legacy.insert(log_id);
+    }
+
+    // Stale and total bytes over all logs.
+    pub fn sum(&self) -> Generation {
+        self.logs
+            .values()
+            .fold(Generation::default(), |sum, gen| Generation {
+                total: sum.total + gen.total,
+                stale: sum.stale + gen.stale,
+            })
+    }
+
+    // The logs below `end` to merge: legacy logs and those at least
+    // `threshold` stale, if there are any, along with those under `small`
+    // bytes, which would otherwise pile up as every compaction starts a new log.
+    pub fn candidates(&self, end: u64, threshold: f64, small: u64) -> Vec<u64> {
+        let logs = self.logs.range(..end);
+        let worth = |log_id: &u64, gen: &Generation| {
+            self.legacy.contains(log_id) || gen.stale > 0 && gen.garbage_ratio() >= threshold
+        };
+        if !logs.clone().any(|(log_id, gen)| worth(log_id, gen)) {
+            return Vec::new();
+        }
+        logs.filter(|(log_id, gen)| worth(log_id, gen) || gen.total < small)
+            .map(|(&log_id, _)| log_id)
+            .collect()
+    }
+
+    // Record that `inputs` were merged into `output`, if it was written.
+    pub fn merged(&mut self, inputs: &[u64], output: Option<(u64, Generation)>) {
+        for log_id in inputs {
+            self.logs.remove(log_id);
+            self.legacy.remove(log_id);
+        }
+        if let Some((log_id, gen)) = output {
+            self.logs.insert(log_id, gen);
+        }
+    }
+}
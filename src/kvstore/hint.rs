@@ -0,0 +1,137 @@
+use {
+    super::{format::length_field, log::Record},
+    crate::Result,
+    std::{
+        fs::{self, File},
+        io::{self, Write},
+        path::{Path, PathBuf},
+    },
+};
+
+// A hint file lists where each command of one log lives, so `open` can rebuild
+// the index without reading values. It covers exactly `log_size` bytes of the
+// log, so a log that was appended to after its hint is replayed instead:
+//
+//   magic: [u8; 4] | version: u8 | log_size: u64 LE
+//   | entries of tombstone: u8 | key_len: u32 LE | offset: u64 LE | length: u64 LE | key
+//   | crc32 of everything above: u32 LE
+const MAGIC: [u8; 4] = [0x89, b'K', b'V', b'H'];
+const VERSION: u8 = 2;
+const HEADER_LEN: usize = 13;
+const ENTRY_HEADER_LEN: usize = 21;
+
+pub(super) struct HintEntry {
+    pub key: Vec<u8>,
+    pub offset: u64,
+    pub length: u64,
+    pub tombstone: bool,
+}
+
+impl HintEntry {
+    pub fn record(&self, log_id: u64) -> Record {
+        Record {
+            log_id,
+            offset: self.offset,
+            length: self.length,
+        }
+    }
+}
+
+pub(super) fn hint_path(dir: &Path, log_id: u64) -> PathBuf {
+    dir.join(format!("{}.hint", log_id))
+}
+
+// Written next to the hint and renamed once synced, like compaction output.
+fn hint_tmp_path(dir: &Path, log_id: u64) -> PathBuf {
+    dir.join(format!("{}.hint.compact", log_id))
+}
+
+pub(super) fn write_hint(
+    dir: &Path,
+    log_id: u64,
+    log_size: u64,
+    entries: &[HintEntry],
+) -> Result<()> {
+    let mut buf = Vec::new();
+    buf.extend_from_slice(&MAGIC);
+    buf.push(VERSION);
+    buf.extend_from_slice(&log_size.to_le_bytes());
+    for entry in entries {
+        buf.push(entry.tombstone as u8);
+        buf.extend_from_slice(&length_field("key", entry.key.len())?.to_le_bytes());
+        buf.extend_from_slice(&entry.offset.to_le_bytes());
+        buf.extend_from_slice(&entry.length.to_le_bytes());
+        buf.extend_from_slice(&entry.key);
+    }
+    let crc = crc32fast::hash(&buf);
+    buf.extend_from_slice(&crc.to_le_bytes());
+
+    let tmp_path = hint_tmp_path(dir, log_id);
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(&buf)?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, hint_path(dir, log_id))?;
+    Ok(())
+}
+
+// Return `None` if the log has no usable hint: it is missing, damaged, of an
+// unknown version or written for a log of another size than `log_size`.
+pub(super) fn read_hint(dir: &Path, log_id: u64, log_size: u64) -> Result<Option<Vec<HintEntry>>> {
+    let buf = match fs::read(hint_path(dir, log_id)) {
+        Ok(buf) => buf,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    Ok(parse_hint(&buf, log_size))
+}
+
+fn parse_hint(buf: &[u8], log_size: u64) -> Option<Vec<HintEntry>> {
+    if buf.len() < HEADER_LEN + 4 {
+        return None;
+    }
+    let (body, crc) = buf.split_at(buf.len() - 4);
+    if crc32fast::hash(body) != u32::from_le_bytes([crc[0], crc[1], crc[2], crc[3]]) {
+        return None;
+    }
+    if !body.starts_with(&MAGIC) || body[MAGIC.len()] != VERSION {
+        return None;
+    }
+    if read_u64(&body[MAGIC.len() + 1..HEADER_LEN]) != log_size {
+        return None;
+    }
+
+    let mut entries = Vec::new();
+    let mut rest = &body[HEADER_LEN..];
+    while !rest.is_empty() {
+        if rest.len() < ENTRY_HEADER_LEN {
+            return None;
+        }
+        let tombstone = rest[0] != 0;
+        let key_len = read_u32(&rest[1..5]) as usize;
+        let offset = read_u64(&rest[5..13]);
+        let length = read_u64(&rest[13..21]);
+        rest = &rest[ENTRY_HEADER_LEN..];
+        if rest.len() < key_len || offset.checked_add(length)? > log_size {
+            return None;
+        }
+        let key = rest[..key_len].to_vec();
+        rest = &rest[key_len..];
+        entries.push(HintEntry {
+            key,
+            offset,
+            length,
+            tombstone,
+        });
+    }
+    Some(entries)
+}
+
+fn read_u32(buf: &[u8]) -> u32 {
+    u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]])
+}
+
+fn read_u64(buf: &[u8]) -> u64 {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&buf[..8]);
+    u64::from_le_bytes(bytes)
+}
@@ -0,0 +1,28 @@
+use {
+    crate::{KvsError, Result},
+    std::{
+        fs::{File, OpenOptions, TryLockError},
+        path::Path,
+    },
+};
+
+// Every writable open holds an exclusive advisory lock on this file in the
+// store directory until the store is closed. Read-only opens take no lock, so
+// they can run beside the writer.
+pub const LOCK_FILE: &str = "LOCK";
+
+// The file is left behind on close: deleting it could race with the next
+// process locking it.
+pub fn lock_dir(path: &Path) -> Result<File> {
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path.join(LOCK_FILE))?;
+    match file.try_lock() {
+        Ok(()) => Ok(file),
+        Err(TryLockError::WouldBlock) => Err(KvsError::Locked(path.display().to_string()).into()),
+        Err(TryLockError::Error(e)) => Err(e.into()),
+    }
+}
@@ -0,0 +1,145 @@
+use {
+    crate::Result,
+    std::{
+        ffi::OsStr,
+        fs::{self, File},
+        io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+        path::{Path, PathBuf},
+    },
+};
+
+#[derive(Clone, Debug)]
+pub enum Command {
+    // `expires_at` is in milliseconds since the Unix epoch.
+    Set {
+        key: Vec<u8>,
+        value: Vec<u8>,
+        expires_at: Option<u64>,
+    },
+    Remove {
+        key: Vec<u8>,
+    },
+    // The next `count` commands only take effect once `BatchCommit` follows.
+    BatchBegin {
+        count: u32,
+    },
+    BatchCommit,
+}
+
+// Where the latest command of a key lives on disk. `length` covers the header.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(super) struct Record {
+    pub log_id: u64,
+    pub offset: u64,
+    pub length: u64,
+}
+
+pub(super) fn gen_log_name(log_id: u64) -> String {
+    format!("{}.log", log_id)
+}
+
+pub(super) fn log_path(dir: &Path, log_id: u64) -> PathBuf {
+    dir.join(gen_log_name(log_id))
+}
+
+pub(super) fn read_all_log_idx_and_sort(path: &Path) -> Result<Vec<u64>> {
+    let mut ids: Vec<u64> = fs::read_dir(path)?
+        .flat_map(|d| -> Result<_> { Ok(d?.path()) })
+        .filter(|path| path.is_file() && path.extension() == Some("log".as_ref()))
+        .flat_map(|path| {
+            path.file_name()
+                .and_then(OsStr::to_str)
+                .map(|s| s.trim_end_matches(".log"))
+                .map(str::parse::<u64>)
+        })
+        .flatten()
+        .filter(|&log_id| !retired_path(path, log_id).exists())
+        .collect();
+    ids.sort_unstable();
+    Ok(ids)
+}
+
+// Marks a log that compaction merged but a snapshot still reads. The log only
+// serves that snapshot: it is no longer part of the store, and the next
+// writable open deletes it if it is still there.
+pub(super) fn retired_path(dir: &Path, log_id: u64) -> PathBuf {
+    dir.join(format!("{}.retired", log_id))
+}
+
+// Compaction output is written under this name and renamed once complete, so
+// a crash never leaves a half-written log behind.
+pub(super) fn compaction_path(dir: &Path, log_id: u64) -> PathBuf {
+    dir.join(format!("{}.compact", log_id))
+}
+
+// Open a log for appending, creating it if needed.
+pub(super) fn open_log(dir: &Path, log_id: u64) -> Result<BufWriterWithPos<File>> {
+    let file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(log_path(dir, log_id))?;
+    BufWriterWithPos::new(file)
+}
+
+pub(super) struct BufReaderWithPos<R: Read + Seek> {
+    reader: BufReader<R>,
+    pub pos: u64,
+}
+
+impl<R: Read + Seek> BufReaderWithPos<R> {
+    pub fn new(mut inner: R) -> Result<Self> {
+        let pos = inner.stream_position()?;
+        Ok(BufReaderWithPos {
+            reader: BufReader::new(inner),
+            pos,
+        })
+    }
+}
+
+impl<R: Read + Seek> Read for BufReaderWithPos<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.reader.read(buf)?;
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+impl<R: Read + Seek> Seek for BufReaderWithPos<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.pos = self.reader.seek(pos)?;
+        Ok(self.pos)
+    }
+}
+
+pub(super) struct BufWriterWithPos<W: Write + Seek> {
+    writer: BufWriter<W>,
+    pub pos: u64,
+}
+
+impl<W: Write + Seek> BufWriterWithPos<W> {
+    pub fn new(mut inner: W) -> Result<Self> {
+        let pos = inner.seek(SeekFrom::End(0))?;
+        Ok(BufWriterWithPos {
+            writer: BufWriter::new(inner),
+            pos,
+        })
+    }
+}
+
+impl<W: Write + Seek> BufWriterWithPos<W> {
+    pub fn get_ref(&self) -> &W {
+        self.writer.get_ref()
+    }
+}
+
+impl<W: Write + Seek> Write for BufWriterWithPos<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.writer.write(buf)?;
+        self.pos += len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
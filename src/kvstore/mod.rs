@@ -0,0 +1,701 @@
+use {
+    crate::{
+        counter,
+        errors::{KvsError, Result},
+        expiry, CasOutcome, Durability, KvsEngine, KvsIter, WriteBatch,
+    },
+    std::{
+        collections::BTreeMap,
+        ffi::OsStr,
+        fs::{self, File},
+        io::{self, BufReader, Read, Seek, SeekFrom},
+        ops::RangeBounds,
+        path::{Path, PathBuf},
+        sync::{atomic::AtomicU64, Arc, Mutex, MutexGuard, RwLock},
+        time::Duration,
+    },
+};
+
+mod backup;
+mod compaction;
+mod format;
+mod generation;
+mod hint;
+mod lock;
+mod log;
+mod options;
+mod reader;
+mod scan;
+mod snapshot;
+mod tail;
+mod writer;
+
+pub use self::{
+    backup::create_empty_dir,
+    lock::{lock_dir, LOCK_FILE},
+    log::Command,
+    options::{CompactionTrigger, Options},
+    snapshot::KvStoreSnapshot,
+};
+
+pub(crate) use self::format::FORMAT_VERSION;
+
+use self::{
+    backup::write_backup,
+    format::{scan_record, LogFormat, Scan},
+    generation::Generations,
+    hint::{hint_path, read_hint},
+    log::{log_path, open_log, read_all_log_idx_and_sort, Record},
+    reader::KvStoreReader,
+    scan::RangeIter,
+    snapshot::{remove_log, Pins, Versions},
+    tail::{settled_log_ids, Tail},
+    writer::{KvStoreWriter, Syncer},
+};
+
+type Index = RwLock<BTreeMap<Vec<u8>, Record>>;
+
+// Pairs written at once by `bulk_load`.
+const LOAD_CHUNK: usize = 1024;
+
+// Handles are cheap to clone and all clones share one store. Reads hold the
+// index lock only for the lookup and then use the clone's own file handles, so
+// a get never waits for a set's disk write; writes are serialized through the
+// writer.
+#[derive(Clone)]
+pub struct KvStore {
+    index: Arc<Index>,
+    reader: KvStoreReader,
+    // `None` when opened read-only.
+    writer: Option<Arc<Mutex<KvStoreWriter>>>,
+    syncer: Option<Arc<Syncer>>,
+    versions: Arc<Versions>,
+    // How far a read-only store has read the logs. `None` when writable.
+    tail: Option<Arc<Mutex<Tail>>>,
+}
+
+impl KvsEngine for KvStore {
+    type Snapshot = KvStoreSnapshot;
+
+    fn set(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        let ticket = self.writer()?.set(key, value, None)?;
+        self.wait_durable(ticket)
+    }
+
+    fn get(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        Ok(self.lookup(&key)?.map(|(value, _)| value))
+    }
+
+    fn remove(&self, key: Vec<u8>) -> Result<()> {
+        let mut writer = self.writer()?;
+        // An expired key is already gone as far as callers can tell.
+        if self.lookup(&key)?.is_none() {
+            return Err(KvsError::Remove(String::from_utf8_lossy(&key).into_owned()).into());
+        }
+        let ticket = writer.remove(key)?;
+        drop(writer);
+        self.wait_durable(ticket)
+    }
+
+    fn set_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        let ticket = self
+            .writer()?
+            .set(key, value, Some(expiry::deadline(ttl)))?;
+        self.wait_durable(ticket)
+    }
+
+    // Changing the expiry rewrites the value with the new expiry time.
+    fn expire(&self, key: Vec<u8>, ttl: Duration) -> Result<()> {
+        self.set_expiry(key, Some(expiry::deadline(ttl)))
+    }
+
+    fn ttl(&self, key: Vec<u8>) -> Result<Option<Duration>> {
+        match self.lookup(&key)? {
+            Some((_, expires_at)) => Ok(expires_at.map(expiry::remaining)),
+            None => Err(KvsError::Get(String::from_utf8_lossy(&key).into_owned()).into()),
+        }
+    }
+
+    fn persist(&self, key: Vec<u8>) -> Result<()> {
+        self.set_expiry(key, None)
+    }
+
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        let ticket = self.writer()?.write_batch(batch)?;
+        self.wait_durable(ticket)
+    }
+
+    // Every write records its keys under the writer lock, so holding it
+    // keeps them from being written between the check and the write.
+    fn write_batch_unless_written(
+        &self,
+        snapshot: &KvStoreSnapshot,
+        read: Vec<Vec<u8>>,
+        batch: WriteBatch,
+    ) -> Result<bool> {
+        let mut writer = self.writer()?;
+        if !Arc::ptr_eq(&snapshot.versions, &self.versions)
+            || self.versions.written_since(snapshot.version, &read)
+        {
+            return Ok(false);
+        }
+        let ticket = writer.write_batch(batch)?;
+        drop(writer);
+        self.wait_durable(ticket)?;
+        Ok(true)
+    }
+
+    // Holding the writer lock keeps the value from changing between the
+    // comparison and the write.
+    fn compare_and_swap(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<CasOutcome> {
+        let mut writer = self.writer()?;
+        let current = self.get(key.clone())?;
+        if current != expected {
+            return Ok(CasOutcome::Conflict { current });
+        }
+        let ticket = match (new, current) {
+            (Some(value), _) => writer.set(key, value, None)?,
+            (None, Some(_)) => writer.remove(key)?,
+            (None, None) => None,
+        };
+        drop(writer);
+        self.wait_durable(ticket)?;
+        Ok(CasOutcome::Swapped)
+    }
+
+    fn incr_by(&self, key: Vec<u8>, delta: i64) -> Result<i64> {
+        let mut writer = self.writer()?;
+        let (current, expires_at) = match self.lookup(&key)? {
+            Some((value, expires_at)) => (Some(value), expires_at),
+            None => (None, None),
+        };
+        let value = counter::add(&key, current.as_deref(), delta)?;
+        let ticket = writer.set(key, counter::encode(value), expires_at)?;
+        drop(writer);
+        self.wait_durable(ticket)?;
+        Ok(value)
+    }
+
+    fn append(&self, key: Vec<u8>, value: Vec<u8>) -> Result<u64> {
+        let mut writer = self.writer()?;
+        let (mut current, expires_at) = self.lookup(&key)?.unwrap_or_default();
+        current.extend_from_slice(&value);
+        let len = current.len() as u64;
+        let ticket = writer.set(key, current, expires_at)?;
+        drop(writer);
+        self.wait_durable(ticket)?;
+        Ok(len)
+    }
+
+    fn scan<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Result<KvsIter<'_>> {
+        let (start, end) = (range.start_bound().cloned(), range.end_bound().cloned());
+        Ok(Box::new(RangeIter::new(self.clone(), start, end)))
+    }
+
+    // Holds the writer lock for the whole load, in chunks that each take one
+    // write and one index lock.
+    fn bulk_load<I>(&self, pairs: I) -> Result<u64>
+    where
+        I: IntoIterator<Item = Result<(Vec<u8>, Vec<u8>)>>,
+    {
+        let mut writer = self.writer()?;
+        let mut loaded = 0;
+        let mut chunk = Vec::with_capacity(LOAD_CHUNK);
+        for pair in pairs {
+            chunk.push(pair?);
+            if chunk.len() == LOAD_CHUNK {
+                loaded += chunk.len() as u64;
+                writer.load(std::mem::take(&mut chunk))?;
+            }
+        }
+        loaded += chunk.len() as u64;
+        writer.load(chunk)?;
+        writer.finish_load()?;
+        Ok(loaded)
+    }
+
+    // Copies only what a snapshot sees, so the backup is consistent and
+    // compacted while writes carry on.
+    fn backup_to(&self, path: &Path) -> Result<()> {
+        write_backup(&self.snapshot()?, path)
+    }
+
+    // Shares the index, so taking a snapshot copies nothing. Instead, every
+    // write made while it is open keeps the records it replaced until then.
+    fn snapshot(&self) -> Result<KvStoreSnapshot> {
+        Ok(KvStoreSnapshot::new(
+            Arc::clone(&self.index),
+            self.reader.clone(),
+            expiry::now_millis(),
+            Arc::clone(&self.versions),
+        ))
+    }
+}
+
+impl KvStore {
+    pub fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
+        KvStore::open_with(path, Options::default())
+    }
+
+    pub fn open_with(path: impl Into<PathBuf>, options: Options) -> Result<KvStore> {
+        let path: PathBuf = path.into();
+        if !path.exists() {
+            if options.read_only || !options.create_if_missing {
+                return Err(KvsError::StoreNotFound(path.display().to_string()).into());
+            }
+            fs::create_dir_all(path.clone())?;
+        }
+        // Taken before reading the logs, since a writable open may truncate
+        // a torn tail off the active log.
+        let lock = match options.read_only {
+            true => None,
+            false => Some(lock_dir(&path)?),
+        };
+        if lock.is_some() {
+            remove_retired_logs(&path)?;
+        }
+        let path = Arc::new(path);
+
+        let mut index = BTreeMap::new();
+        let mut generations = Generations::default();
+        let ids = match lock {
+            Some(_) => read_all_log_idx_and_sort(&path)?,
+            None => settled_log_ids(&path)?,
+        };
+        let mut active_format = LogFormat::Binary;
+        let mut end = 0;
+        for (i, &id) in ids.iter().enumerate() {
+            let is_active = i + 1 == ids.len();
+            let read_only = options.read_only;
+            (active_format, end) = replay(
+                &path,
+                id,
+                0,
+                is_active,
+                read_only,
+                &mut index,
+                &mut generations,
+            )?;
+            generations.written(id, end);
+            if active_format != LogFormat::Binary {
+                generations.legacy(id);
+            }
+        }
+        let index = Arc::new(RwLock::new(index));
+        let reader = KvStoreReader::new(Arc::clone(&path), Arc::new(AtomicU64::new(0)));
+        let pins = Arc::new(Pins::default());
+        let versions = Arc::new(Versions::new(Arc::clone(&path), Arc::clone(&pins)));
+        let lock = match lock {
+            Some(lock) => lock,
+            None => {
+                return Ok(KvStore {
+                    index,
+                    reader,
+                    writer: None,
+                    syncer: None,
+                    versions,
+                    tail: Some(Arc::new(Mutex::new(Tail::new(ids, end)))),
+                })
+            }
+        };
+
+        remove_compaction_leftovers(&path)?;
+        let mut log_pointer = ids.last().cloned().unwrap_or(1);
+        // Never append current records to a log written in an older format,
+        // nor to one a hint file describes.
+        if active_format != LogFormat::Binary || hint_path(&path, log_pointer).exists() {
+            log_pointer += 1;
+        }
+        let active = open_log(&path, log_pointer)?;
+        let syncer = Arc::new(Syncer::new(active.get_ref())?);
+        if let Durability::Interval(period) = options.durability {
+            Syncer::spawn_interval(Arc::downgrade(&syncer), period);
+        }
+        let writer = KvStoreWriter {
+            path: Arc::clone(&path),
+            reader: reader.clone(),
+            writer: active,
+            log_pointer,
+            index: Arc::clone(&index),
+            compaction: None,
+            compaction_error: None,
+            options,
+            syncer: Arc::clone(&syncer),
+            pins: Arc::clone(&pins),
+            versions: Arc::clone(&versions),
+            _lock: lock,
+            generations: Arc::new(Mutex::new(generations)),
+        };
+        Ok(KvStore {
+            index,
+            reader,
+            writer: Some(Arc::new(Mutex::new(writer))),
+            syncer: Some(syncer),
+            versions,
+            tail: None,
+        })
+    }
+
+    // Catch a read-only store up with what a writer has appended since it was
+    // opened or last refreshed. Until then it serves what it saw, except that a
+    // read finding its log compacted away refreshes by itself. If compaction
+    // has replaced logs, the index is rebuilt from scratch. While snapshots
+    // are open, it also compares the whole index before and after, to keep
+    // what they need. A writable store is always current, so this does
+    // nothing.
+    pub fn refresh(&self) -> Result<()> {
+        let mut tail = match self.tail {
+            Some(ref tail) => tail.lock().unwrap(),
+            None => return Ok(()),
+        };
+        let path = self.reader.path();
+        loop {
+            let ids = settled_log_ids(&path)?;
+            let res = if tail.extends_to(&ids) {
+                let mut index = self.index.write().unwrap();
+                // Replaying does not tell which records it replaced, so open
+                // snapshots need the index as it was to compare with.
+                let before = self.versions.any_open().then(|| index.clone());
+                let res = tail.catch_up(&path, ids, &mut index);
+                if let Some(before) = before {
+                    self.versions.written(replaced(&before, &index));
+                }
+                res
+            } else {
+                let mut fresh = Tail::new(Vec::new(), 0);
+                let mut rebuilt = BTreeMap::new();
+                fresh.catch_up(&path, ids, &mut rebuilt).map(|()| {
+                    let mut index = self.index.write().unwrap();
+                    if self.versions.any_open() {
+                        self.versions.written(replaced(&index, &rebuilt));
+                    }
+                    *index = rebuilt;
+                    self.reader.logs_merged();
+                    *tail = fresh;
+                })
+            };
+            match res {
+                // A compaction deleted a log while it was read. The logs on
+                // disk no longer extend those replayed, so start over.
+                Err(e)
+                    if e.downcast_ref::<io::Error>()
+                        .is_some_and(|e| e.kind() == io::ErrorKind::NotFound) => {}
+                res => return res,
+            }
+        }
+    }
+
+    // Merge the logs that are stale enough now, whatever the compaction
+    // trigger, and wait for it. Compactions started by writes run in the
+    // background and never fail the write; this reports the error of any that
+    // failed since the last call.
+    pub fn compact(&self) -> Result<()> {
+        self.writer()?.compact_now()
+    }
+
+    pub fn gen_log_name(log_id: u64) -> String {
+        log::gen_log_name(log_id)
+    }
+
+    fn writer(&self) -> Result<MutexGuard<'_, KvStoreWriter>> {
+        match self.writer {
+            Some(ref writer) => Ok(writer.lock().unwrap()),
+            None => Err(KvsError::ReadOnly.into()),
+        }
+    }
+
+    fn set_expiry(&self, key: Vec<u8>, expires_at: Option<u64>) -> Result<()> {
+        let mut writer = self.writer()?;
+        let ticket = match self.lookup(&key)? {
+            Some((_, current)) if current == expires_at => None,
+            Some((value, _)) => writer.set(key, value, expires_at)?,
+            None => return Err(KvsError::Get(String::from_utf8_lossy(&key).into_owned()).into()),
+        };
+        drop(writer);
+        self.wait_durable(ticket)
+    }
+
+    // The value of `key` and its expiry time, unless it is absent or expired.
+    fn lookup(&self, key: &[u8]) -> Result<Option<(Vec<u8>, Option<u64>)>> {
+        let rcd = match self.index.read().unwrap().get(key) {
+            Some(&rcd) => rcd,
+            None => return Ok(None),
+        };
+        self.read_entry(key, rcd)
+    }
+
+    fn read_value(&self, key: &[u8], rcd: Record) -> Result<Option<Vec<u8>>> {
+        Ok(self.read_entry(key, rcd)?.map(|(value, _)| value))
+    }
+
+    // Read the entry of `key` that the index pointed to with `rcd`. Return
+    // `None` if the key has expired or been removed since.
+    fn read_entry(&self, key: &[u8], mut rcd: Record) -> Result<Option<(Vec<u8>, Option<u64>)>> {
+        let mut refreshed = false;
+        loop {
+            match self.reader.read_command(rcd) {
+                Ok(Command::Set {
+                    expires_at: Some(expires_at),
+                    ..
+                }) if expiry::is_expired(expires_at) => return Ok(None),
+                Ok(Command::Set {
+                    value, expires_at, ..
+                }) => return Ok(Some((value, expires_at))),
+                Ok(Command::Remove { .. }) => {
+                    return Err(KvsError::UnKnownOperation("remove".to_string()).into())
+                }
+                Ok(_) => return Err(KvsError::UnKnownOperation("batch marker".to_string()).into()),
+                // Compaction may have moved the record and deleted its log
+                // between the index lookup and the read.
+                Err(e) => {
+                    // A read-only store only learns of the move once refreshed.
+                    if self.tail.is_some() && !refreshed {
+                        refreshed = true;
+                        self.refresh()?;
+                    }
+                    match self.index.read().unwrap().get(key) {
+                        Some(&moved) if moved != rcd => rcd = moved,
+                        Some(_) => return Err(e),
+                        None => return Ok(None),
+                    }
+                }
+            }
+        }
+    }
+
+    // Wait for group commit outside of the writer lock, so that other writers
+    // can join the same sync.
+    fn wait_durable(&self, ticket: Option<u64>) -> Result<()> {
+        match (ticket, &self.syncer) {
+            (Some(ticket), Some(syncer)) => syncer.wait(ticket),
+            _ => Ok(()),
+        }
+    }
+}
+
+// Rebuild the index from the hint file of a log, if it has a usable one.
+fn load_hint(
+    path: &Path,
+    log_id: u64,
+    index: &mut BTreeMap<Vec<u8>, Record>,
+    generations: &mut Generations,
+) -> Result<bool> {
+    let size = fs::metadata(log_path(path, log_id))?.len();
+    let entries = match read_hint(path, log_id, size)? {
+        Some(entries) => entries,
+        None => return Ok(false),
+    };
+    for entry in entries {
+        let rcd = entry.record(log_id);
+        let old = if entry.tombstone {
+            generations.stale(log_id, rcd.length);
+            index.remove(&entry.key)
+        } else {
+            index.insert(entry.key, rcd)
+        };
+        if let Some(old) = old {
+            generations.stale(old.log_id, old.length);
+        }
+    }
+    Ok(true)
+}
+
+// Replay a log into the index from offset `start`, through its hint file when
+// reading it whole. Return its format and where its last complete record ends.
+fn replay(
+    path: &Path,
+    log_id: u64,
+    start: u64,
+    is_active: bool,
+    read_only: bool,
+    index: &mut BTreeMap<Vec<u8>, Record>,
+    generations: &mut Generations,
+) -> Result<(LogFormat, u64)> {
+    if start == 0 && load_hint(path, log_id, index, generations)? {
+        let size = fs::metadata(log_path(path, log_id))?.len();
+        return Ok((LogFormat::Binary, size));
+    }
+    build(
+        path,
+        log_id,
+        start,
+        index,
+        generations,
+        is_active,
+        read_only,
+    )
+}
+
+// Replay one log into the index from offset `start`. A bad record or an
+// unfinished batch at the end of the active log is a torn write from a crash
+// and is cut off; when read-only it is left alone, as it may be a write still
+// in progress. Anywhere else it is corruption.
+fn build(
+    path: &Path,
+    log_id: u64,
+    start: u64,
+    index: &mut BTreeMap<Vec<u8>, Record>,
+    generations: &mut Generations,
+    is_active: bool,
+    read_only: bool,
+) -> Result<(LogFormat, u64)> {
+    let (mut reader, format, size) = open_for_scan(path, log_id, start)?;
+    let mut offset = start;
+    let mut batch: Option<OpenBatch> = None;
+    loop {
+        let scan = scan_record(&mut reader, format, size.saturating_sub(offset))?;
+        // Everything from the start of an unfinished batch is torn.
+        let valid_len = batch.as_ref().map_or(offset, |batch| batch.start);
+        match scan {
+            Scan::Record(cmd, length) => {
+                let rcd = Record {
+                    log_id,
+                    offset,
+                    length,
+                };
+                offset += length;
+                match (cmd, &mut batch) {
+                    (Command::BatchBegin { count }, None) => {
+                        generations.stale(log_id, length);
+                        batch = Some(OpenBatch {
+                            start: rcd.offset,
+                            count,
+                            cmds: Vec::new(),
+                        });
+                    }
+                    (Command::BatchCommit, Some(open))
+                        if open.cmds.len() == open.count as usize =>
+                    {
+                        generations.stale(log_id, length);
+                        for (cmd, rcd) in batch.take().unwrap().cmds {
+                            apply(index, generations, cmd, rcd);
+                        }
+                    }
+                    (cmd @ Command::Set { .. }, Some(open))
+                    | (cmd @ Command::Remove { .. }, Some(open)) => open.cmds.push((cmd, rcd)),
+                    (cmd @ Command::Set { .. }, None) | (cmd @ Command::Remove { .. }, None) => {
+                        apply(index, generations, cmd, rcd)
+                    }
+                    _ => {
+                        return Err(KvsError::Corruption {
+                            log_id,
+                            offset: rcd.offset,
+                        }
+                        .into())
+                    }
+                }
+            }
+            Scan::End if batch.is_none() => return Ok((format, offset)),
+            Scan::End | Scan::Bad if is_active && read_only => return Ok((format, valid_len)),
+            Scan::End | Scan::Bad if is_active => {
+                let file = fs::OpenOptions::new()
+                    .write(true)
+                    .open(log_path(path, log_id))?;
+                file.set_len(valid_len)?;
+                file.sync_all()?;
+                return Ok((format, valid_len));
+            }
+            Scan::End | Scan::Bad => {
+                return Err(KvsError::Corruption {
+                    log_id,
+                    offset: valid_len,
+                }
+                .into())
+            }
+        }
+    }
+}
+
+// Open a log to read its records from offset `start`, and tell its format and
+// size.
+fn open_for_scan(
+    path: &Path,
+    log_id: u64,
+    start: u64,
+) -> Result<(BufReader<File>, LogFormat, u64)> {
+    let mut file = File::open(log_path(path, log_id))?;
+    let size = file.metadata()?.len();
+    let mut prefix = [0u8; 4];
+    let read = file.read(&mut prefix)?;
+    let format = LogFormat::detect(&prefix[..read]);
+    file.seek(SeekFrom::Start(start))?;
+    Ok((BufReader::new(file), format, size))
+}
+
+// A batch whose commit marker has not been read yet.
+struct OpenBatch {
+    start: u64,
+    count: u32,
+    cmds: Vec<(Command, Record)>,
+}
+
+fn apply(
+    index: &mut BTreeMap<Vec<u8>, Record>,
+    generations: &mut Generations,
+    cmd: Command,
+    rcd: Record,
+) {
+    let old = match cmd {
+        Command::Set { key, .. } => index.insert(key, rcd),
+        Command::Remove { key } => {
+            generations.stale(rcd.log_id, rcd.length);
+            index.remove(&key)
+        }
+        _ => None,
+    };
+    if let Some(old) = old {
+        generations.stale(old.log_id, old.length);
+    }
+}
+
+// The records of `before` that `after` no longer has, and the keys `after`
+// added, as `None`.
+fn replaced<'a>(
+    before: &'a BTreeMap<Vec<u8>, Record>,
+    after: &'a BTreeMap<Vec<u8>, Record>,
+) -> impl Iterator<Item = (&'a [u8], Option<Record>)> {
+    let changed = before
+        .iter()
+        .filter(move |&(key, rcd)| after.get(key) != Some(rcd))
+        .map(|(key, &rcd)| (&key[..], Some(rcd)));
+    let added = after
+        .keys()
+        .filter(move |&key| !before.contains_key(key))
+        .map(|key| (&key[..], None));
+    changed.chain(added)
+}
+
+// Logs that snapshots still read when the store was last closed. Compaction
+// has merged them, so replaying them could bring back removed keys.
+fn remove_retired_logs(path: &Path) -> Result<()> {
+    for entry in fs::read_dir(path)? {
+        let path = entry?.path();
+        if path.is_file() && path.extension() == Some("retired".as_ref()) {
+            let log_id = path
+                .file_stem()
+                .and_then(OsStr::to_str)
+                .map(str::parse::<u64>);
+            match log_id {
+                Some(Ok(log_id)) => remove_log(path.parent().unwrap(), log_id)?,
+                _ => fs::remove_file(path)?,
+            }
+        }
+    }
+    Ok(())
+}
+
+// Output of a compaction interrupted by a crash. Its inputs were never deleted.
+fn remove_compaction_leftovers(path: &Path) -> Result<()> {
+    for entry in fs::read_dir(path)? {
+        let path = entry?.path();
+        if path.is_file() && path.extension() == Some("compact".as_ref()) {
+            fs::remove_file(path)?;
+        }
+    }
+    Ok(())
+}
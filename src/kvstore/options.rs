@@ -0,0 +1,84 @@
+use crate::Durability;
+
+const MB: u64 = 1024 * 1024;
+
+// When to merge old logs and drop the bytes of overwritten and removed records.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CompactionTrigger {
+    // Compact once this many stale bytes have piled up.
+    Absolute(u64),
+    // Compact once stale bytes make up more than `ratio` of all log bytes, but
+    // only when there are at least `min_bytes` of them.
+    StaleRatio { ratio: f64, min_bytes: u64 },
+}
+
+// Settings for `KvStore::open_with`, built like:
+//
+//     Options::new().max_log_size(64 * 1024 * 1024).durability(Durability::EveryWrite)
+#[derive(Debug, Clone)]
+pub struct Options {
+    pub(super) compaction_trigger: CompactionTrigger,
+    pub(super) max_log_size: u64,
+    pub(super) merge_threshold: f64,
+    pub(super) durability: Durability,
+    pub(super) read_only: bool,
+    pub(super) create_if_missing: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            compaction_trigger: CompactionTrigger::Absolute(MB),
+            max_log_size: 8 * MB,
+            merge_threshold: 0.5,
+            durability: Durability::None,
+            read_only: false,
+            create_if_missing: true,
+        }
+    }
+}
+
+impl Options {
+    pub fn new() -> Self {
+        Options::default()
+    }
+
+    pub fn compaction_trigger(mut self, trigger: CompactionTrigger) -> Self {
+        self.compaction_trigger = trigger;
+        self
+    }
+
+    // Once the trigger fires, merge only the logs of which at least `ratio`
+    // is stale, along with logs under a quarter of `max_log_size`. Logs that
+    // are mostly live are left alone.
+    pub fn merge_threshold(mut self, ratio: f64) -> Self {
+        self.merge_threshold = ratio;
+        self
+    }
+
+    // Start a new log once the active one grows past `bytes`.
+    pub fn max_log_size(mut self, bytes: u64) -> Self {
+        self.max_log_size = bytes;
+        self
+    }
+
+    pub fn durability(mut self, durability: Durability) -> Self {
+        self.durability = durability;
+        self
+    }
+
+    // Open without ever creating, writing, compacting or deleting files, and
+    // without taking the directory lock, so a writer may run alongside. Writes
+    // fail with `KvsError::ReadOnly`, and `KvStore::refresh` picks up what the
+    // writer has appended since.
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    // Create the directory if it does not exist yet. Ignored when read-only.
+    pub fn create_if_missing(mut self, create: bool) -> Self {
+        self.create_if_missing = create;
+        self
+    }
+}
@@ -0,0 +1,105 @@
+use {
+    super::{
+        format::decode_record,
+        log::{log_path, BufReaderWithPos, Command, Record},
+    },
+    crate::{KvsError, Result},
+    std::{
+        cell::{Cell, RefCell},
+        collections::{btree_map::Entry, BTreeMap},
+        fs::File,
+        io::{self, Read, Seek, SeekFrom},
+        path::PathBuf,
+        sync::{
+            atomic::{AtomicU64, Ordering},
+            Arc,
+        },
+    },
+};
+
+// Every clone of `KvStore` owns its own file handles, so reads never contend
+// with each other or with the writer.
+pub(super) struct KvStoreReader {
+    path: Arc<PathBuf>,
+    // Counts the compactions that merged logs away, shared by all clones. A
+    // clone seeing a new count drops its handles, which may be of deleted logs.
+    merges: Arc<AtomicU64>,
+    seen: Cell<u64>,
+    readers: RefCell<BTreeMap<u64, BufReaderWithPos<File>>>,
+}
+
+impl KvStoreReader {
+    pub fn new(path: Arc<PathBuf>, merges: Arc<AtomicU64>) -> Self {
+        KvStoreReader {
+            path,
+            seen: Cell::new(merges.load(Ordering::SeqCst)),
+            merges,
+            readers: RefCell::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn path(&self) -> Arc<PathBuf> {
+        Arc::clone(&self.path)
+    }
+
+    // Called once merged logs are no longer in the index, before they are
+    // deleted.
+    pub fn logs_merged(&self) {
+        self.merges.fetch_add(1, Ordering::SeqCst);
+    }
+
+    // Drop every handle after a compaction. Those of logs that remain are
+    // opened again when next read.
+    fn close_stale_handles(&self) {
+        let merges = self.merges.load(Ordering::SeqCst);
+        if merges != self.seen.get() {
+            self.seen.set(merges);
+            self.readers.borrow_mut().clear();
+        }
+    }
+
+    // Hand `f` a reader limited to the bytes of `rcd`.
+    pub fn read_and<F, R>(&self, rcd: Record, f: F) -> Result<R>
+    where
+        F: FnOnce(io::Take<&mut BufReaderWithPos<File>>) -> Result<R>,
+    {
+        self.close_stale_handles();
+
+        let mut readers = self.readers.borrow_mut();
+        let reader = match readers.entry(rcd.log_id) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                let file = File::open(log_path(&self.path, rcd.log_id))?;
+                entry.insert(BufReaderWithPos::new(file)?)
+            }
+        };
+        reader.seek(SeekFrom::Start(rcd.offset))?;
+        f(reader.take(rcd.length))
+    }
+
+    pub fn read_command(&self, rcd: Record) -> Result<Command> {
+        let buf = self.read_and(rcd, |mut reader| {
+            let mut buf = Vec::with_capacity(rcd.length as usize);
+            reader.read_to_end(&mut buf)?;
+            Ok(buf)
+        })?;
+        decode_record(&buf)?.ok_or_else(|| {
+            KvsError::Corruption {
+                log_id: rcd.log_id,
+                offset: rcd.offset,
+            }
+            .into()
+        })
+    }
+}
+
+impl Clone for KvStoreReader {
+    fn clone(&self) -> Self {
+        KvStoreReader {
+            path: Arc::clone(&self.path),
+            merges: Arc::clone(&self.merges),
+            seen: Cell::new(self.merges.load(Ordering::SeqCst)),
+            readers: RefCell::new(BTreeMap::new()),
+        }
+    }
+}
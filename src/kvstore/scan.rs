@@ -0,0 +1,70 @@
+use {
+    super::{log::Record, KvStore},
+    crate::{kvsengine::is_empty_range, Result},
+    std::{collections::VecDeque, ops::Bound},
+};
+
+// Keys are looked up this many at a time, so a long scan never holds the index
+// lock for long.
+pub(super) const BATCH: usize = 128;
+
+// Walks the index in key order. Keys set or removed while scanning may or may
+// not show up, but every key that exists throughout the scan does.
+pub(super) struct RangeIter {
+    store: KvStore,
+    next: Bound<Vec<u8>>,
+    end: Bound<Vec<u8>>,
+    batch: VecDeque<(Vec<u8>, Record)>,
+    done: bool,
+}
+
+impl RangeIter {
+    pub fn new(store: KvStore, start: Bound<Vec<u8>>, end: Bound<Vec<u8>>) -> Self {
+        RangeIter {
+            store,
+            next: start,
+            end,
+            batch: VecDeque::new(),
+            done: false,
+        }
+    }
+
+    fn fill(&mut self) {
+        if is_empty_range(&self.next, &self.end) {
+            self.done = true;
+            return;
+        }
+        let index = self.store.index.read().unwrap();
+        self.batch.extend(
+            index
+                .range((self.next.clone(), self.end.clone()))
+                .take(BATCH)
+                .map(|(key, &rcd)| (key.clone(), rcd)),
+        );
+        match self.batch.back() {
+            Some((key, _)) if self.batch.len() == BATCH => self.next = Bound::Excluded(key.clone()),
+            _ => self.done = true,
+        }
+    }
+}
+
+impl Iterator for RangeIter {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some((key, rcd)) = self.batch.pop_front() {
+                match self.store.read_value(&key, rcd) {
+                    Ok(Some(value)) => return Some(Ok((key, value))),
+                    // Removed since the batch was taken.
+                    Ok(None) => continue,
+                    Err(e) => return Some(Err(e)),
+                }
+            }
+            if self.done {
+                return None;
+            }
+            self.fill();
+        }
+    }
+}
@@ -0,0 +1,417 @@
+use {
+    super::{
+        hint::hint_path,
+        log::{log_path, retired_path, Command, Record},
+        reader::KvStoreReader,
+        scan::BATCH,
+        Index,
+    },
+    crate::{expiry, kvsengine::is_empty_range, KvsError, KvsIter, KvsSnapshot, Result},
+    std::{
+        collections::{BTreeMap, BTreeSet, VecDeque},
+        fs::{self, File},
+        io,
+        ops::{Bound, RangeBounds},
+        path::{Path, PathBuf},
+        sync::{Arc, Mutex},
+        time::Duration,
+    },
+};
+
+// Counts the records kept for snapshots in each log. Compaction retires the
+// logs it has merged, but a retired log is only deleted once no snapshot
+// needs a record of it.
+#[derive(Default)]
+pub(super) struct Pins {
+    inner: Mutex<PinsInner>,
+}
+
+#[derive(Default)]
+struct PinsInner {
+    readers: BTreeMap<u64, usize>,
+    retired: BTreeSet<u64>,
+}
+
+impl Pins {
+    fn pin<I: IntoIterator<Item = u64>>(&self, logs: I) {
+        let mut inner = self.inner.lock().unwrap();
+        for log_id in logs {
+            *inner.readers.entry(log_id).or_insert(0) += 1;
+        }
+    }
+
+    fn unpin<I: IntoIterator<Item = u64>>(&self, path: &Path, logs: I) -> Result<()> {
+        let mut doomed = Vec::new();
+        {
+            let mut inner = self.inner.lock().unwrap();
+            for log_id in logs {
+                let readers = inner.readers.get_mut(&log_id).unwrap();
+                *readers -= 1;
+                if *readers == 0 {
+                    inner.readers.remove(&log_id);
+                    if inner.retired.remove(&log_id) {
+                        doomed.push(log_id);
+                    }
+                }
+            }
+        }
+        doomed
+            .into_iter()
+            .try_for_each(|log_id| remove_log(path, log_id))
+    }
+
+    // Delete the logs that compaction made obsolete, or leave them to the
+    // last snapshot still reading them. The output may have dropped tombstones
+    // for keys those logs set, so logs left behind are marked retired before
+    // any log is deleted, and a crash cannot bring their keys back.
+    pub fn retire(&self, path: &Path, logs: &[u64]) -> Result<()> {
+        let mut doomed = Vec::new();
+        {
+            let mut inner = self.inner.lock().unwrap();
+            for &log_id in logs {
+                if inner.readers.contains_key(&log_id) {
+                    File::create(retired_path(path, log_id))?.sync_all()?;
+                    inner.retired.insert(log_id);
+                } else {
+                    doomed.push(log_id);
+                }
+            }
+        }
+        doomed
+            .into_iter()
+            .try_for_each(|log_id| remove_log(path, log_id))
+    }
+}
+
+// Versions of the store, which give snapshots a view of one moment without
+// copying the index. Every write makes a new version. While snapshots are
+// open, a write keeps the records it replaced, tagged with its version, until
+// every snapshot taken before that version is gone. A snapshot reads a key
+// from the first record kept since its version, or from the index if no write
+// replaced it since. Kept records pin their logs against compaction.
+// Versions only live in memory: nothing compares them across a restart.
+pub(super) struct Versions {
+    path: Arc<PathBuf>,
+    pins: Arc<Pins>,
+    inner: Mutex<VersionsInner>,
+}
+
+#[derive(Default)]
+struct VersionsInner {
+    current: u64,
+    // How many open snapshots were taken at each version.
+    open: BTreeMap<u64, usize>,
+    // The records writes replaced, oldest first, with the version of the
+    // write. `None` if the key was absent before it.
+    kept: BTreeMap<Vec<u8>, Vec<(u64, Option<Record>)>>,
+}
+
+impl Versions {
+    pub fn new(path: Arc<PathBuf>, pins: Arc<Pins>) -> Self {
+        Versions {
+            path,
+            pins,
+            inner: Mutex::default(),
+        }
+    }
+
+    // Register a snapshot taken now and return its version.
+    pub fn open(&self) -> u64 {
+        let mut inner = self.inner.lock().unwrap();
+        let version = inner.current;
+        *inner.open.entry(version).or_insert(0) += 1;
+        version
+    }
+
+    // Drop the records that only snapshots older than the remaining ones kept.
+    fn close(&self, version: u64) -> Result<()> {
+        let mut released = Vec::new();
+        {
+            let mut inner = self.inner.lock().unwrap();
+            let count = inner.open.get_mut(&version).unwrap();
+            *count -= 1;
+            if *count == 0 {
+                inner.open.remove(&version);
+            }
+            let oldest = inner.open.keys().next().cloned();
+            inner.kept.retain(|_, kept| {
+                kept.retain(|&(written, rcd)| {
+                    let needed = oldest.is_some_and(|oldest| written > oldest);
+                    if !needed {
+                        released.extend(rcd.map(|rcd| rcd.log_id));
+                    }
+                    needed
+                });
+                !kept.is_empty()
+            });
+        }
+        self.pins.unpin(&self.path, released)
+    }
+
+    pub fn any_open(&self) -> bool {
+        !self.inner.lock().unwrap().open.is_empty()
+    }
+
+    // Record a write that replaced the record each key had in the index.
+    // Callers still hold the index write lock, so a snapshot that finds the
+    // new record in the index also finds the one it replaced here, and a
+    // compaction cannot retire its log before it is pinned.
+    pub fn written<'k, I>(&self, replaced: I)
+    where
+        I: IntoIterator<Item = (&'k [u8], Option<Record>)>,
+    {
+        let mut inner = self.inner.lock().unwrap();
+        inner.current += 1;
+        if inner.open.is_empty() {
+            return;
+        }
+        let version = inner.current;
+        let mut pinned = Vec::new();
+        for (key, rcd) in replaced {
+            let kept = inner.kept.entry(key.to_vec()).or_default();
+            // A batch writing a key twice replaced what it had before the batch.
+            if kept.last().map(|&(written, _)| written) != Some(version) {
+                kept.push((version, rcd));
+                pinned.extend(rcd.map(|rcd| rcd.log_id));
+            }
+        }
+        self.pins.pin(pinned);
+    }
+
+    // The record `key` had at `version`, if a write replaced it since.
+    fn replaced_since(&self, version: u64, key: &[u8]) -> Option<Option<Record>> {
+        let inner = self.inner.lock().unwrap();
+        first_since(inner.kept.get(key)?, version)
+    }
+
+    // The same for every key in a range that was written since `version`.
+    fn replaced_in(
+        &self,
+        version: u64,
+        range: (Bound<Vec<u8>>, Bound<Vec<u8>>),
+    ) -> Vec<(Vec<u8>, Option<Record>)> {
+        let inner = self.inner.lock().unwrap();
+        inner
+            .kept
+            .range(range)
+            .filter_map(|(key, kept)| Some((key.clone(), first_since(kept, version)?)))
+            .collect()
+    }
+
+    // Whether a write since the snapshot at `version` touched a key in `keys`.
+    pub fn written_since(&self, version: u64, keys: &[Vec<u8>]) -> bool {
+        let inner = self.inner.lock().unwrap();
+        keys.iter().any(|key| {
+            inner
+                .kept
+                .get(key)
+                .and_then(|kept| kept.last())
+                .is_some_and(|&(written, _)| written > version)
+        })
+    }
+}
+
+fn first_since(kept: &[(u64, Option<Record>)], version: u64) -> Option<Option<Record>> {
+    kept.iter()
+        .find(|&&(written, _)| written > version)
+        .map(|&(_, rcd)| rcd)
+}
+
+// Delete a log along with its hint and retirement marker, if any. The marker
+// goes last, so a crash never leaves the log without it.
+pub(super) fn remove_log(path: &Path, log_id: u64) -> Result<()> {
+    for file in [
+        log_path(path, log_id),
+        hint_path(path, log_id),
+        retired_path(path, log_id),
+    ] {
+        match fs::remove_file(file) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+            res => res?,
+        }
+    }
+    Ok(())
+}
+
+// A view of the store at one moment. It shares the index with the store and
+// reads the keys written since from the records those writes replaced, so
+// taking one copies nothing. Keys count as expired as of that moment too.
+pub struct KvStoreSnapshot {
+    index: Arc<Index>,
+    reader: KvStoreReader,
+    taken_at: u64,
+    pub(super) versions: Arc<Versions>,
+    pub(super) version: u64,
+}
+
+impl KvStoreSnapshot {
+    pub(super) fn new(
+        index: Arc<Index>,
+        reader: KvStoreReader,
+        taken_at: u64,
+        versions: Arc<Versions>,
+    ) -> Self {
+        KvStoreSnapshot {
+            index,
+            reader,
+            taken_at,
+            version: versions.open(),
+            versions,
+        }
+    }
+
+    // The set command of every key that had not expired, in key order.
+    pub(super) fn commands(&self) -> impl Iterator<Item = Result<Command>> + '_ {
+        Entries::new(self, Bound::Unbounded, Bound::Unbounded)
+            .map(|entry| entry.map(|(_, cmd)| cmd))
+    }
+
+    // Where the command `key` had at the snapshot lives, if it existed then.
+    // The index is read first: a write found there has already kept the
+    // record it replaced.
+    fn locate(&self, key: &[u8]) -> Option<Record> {
+        let current = self.index.read().unwrap().get(key).cloned();
+        match self.versions.replaced_since(self.version, key) {
+            Some(kept) => kept,
+            None => current,
+        }
+    }
+
+    fn read_live(&self, key: &[u8], mut rcd: Record) -> Result<Option<Command>> {
+        loop {
+            match self.reader.read_command(rcd) {
+                Ok(Command::Set {
+                    expires_at: Some(expires_at),
+                    ..
+                }) if expires_at <= self.taken_at => return Ok(None),
+                Ok(cmd @ Command::Set { .. }) => return Ok(Some(cmd)),
+                Ok(_) => return Err(KvsError::UnKnownOperation("remove".to_string()).into()),
+                // Compaction may have moved a record the index pointed to.
+                // Kept records stay where they are.
+                Err(e) => match self.locate(key) {
+                    Some(moved) if moved != rcd => rcd = moved,
+                    Some(_) => return Err(e),
+                    None => return Ok(None),
+                },
+            }
+        }
+    }
+}
+
+impl KvsSnapshot for KvStoreSnapshot {
+    fn get(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        let rcd = match self.locate(&key) {
+            Some(rcd) => rcd,
+            None => return Ok(None),
+        };
+        match self.read_live(&key, rcd)? {
+            Some(Command::Set { value, .. }) => Ok(Some(value)),
+            _ => Ok(None),
+        }
+    }
+
+    fn ttl(&self, key: Vec<u8>) -> Result<Option<Duration>> {
+        let cmd = match self.locate(&key) {
+            Some(rcd) => self.read_live(&key, rcd)?,
+            None => None,
+        };
+        match cmd {
+            Some(Command::Set { expires_at, .. }) => Ok(expires_at.map(expiry::remaining)),
+            _ => Err(KvsError::Get(String::from_utf8_lossy(&key).into_owned()).into()),
+        }
+    }
+
+    fn scan<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Result<KvsIter<'_>> {
+        let (start, end) = (range.start_bound().cloned(), range.end_bound().cloned());
+        let iter = Entries::new(self, start, end).filter_map(|entry| match entry {
+            Ok((key, Command::Set { value, .. })) => Some(Ok((key, value))),
+            Ok(_) => None,
+            Err(e) => Some(Err(e)),
+        });
+        Ok(Box::new(iter))
+    }
+}
+
+impl Drop for KvStoreSnapshot {
+    fn drop(&mut self) {
+        // A retired log that fails to be removed here is removed by the next
+        // writable open of the store.
+        let _ = self.versions.close(self.version);
+    }
+}
+
+// Walks the keys of a snapshot in order, a batch at a time like `RangeIter`:
+// a batch from the index, corrected by the records kept for the same keys.
+struct Entries<'a> {
+    snapshot: &'a KvStoreSnapshot,
+    next: Bound<Vec<u8>>,
+    end: Bound<Vec<u8>>,
+    batch: VecDeque<(Vec<u8>, Record)>,
+    done: bool,
+}
+
+impl<'a> Entries<'a> {
+    fn new(snapshot: &'a KvStoreSnapshot, start: Bound<Vec<u8>>, end: Bound<Vec<u8>>) -> Self {
+        Entries {
+            snapshot,
+            next: start,
+            end,
+            batch: VecDeque::new(),
+            done: false,
+        }
+    }
+
+    fn fill(&mut self) {
+        if is_empty_range(&self.next, &self.end) {
+            self.done = true;
+            return;
+        }
+        let mut batch: BTreeMap<Vec<u8>, Record> = {
+            let index = self.snapshot.index.read().unwrap();
+            index
+                .range((self.next.clone(), self.end.clone()))
+                .take(BATCH)
+                .map(|(key, &rcd)| (key.clone(), rcd))
+                .collect()
+        };
+        let full = batch.len() == BATCH;
+        let upto = match batch.keys().next_back() {
+            Some(last) if full => Bound::Included(last.clone()),
+            _ => self.end.clone(),
+        };
+        let versions = &self.snapshot.versions;
+        for (key, kept) in
+            versions.replaced_in(self.snapshot.version, (self.next.clone(), upto.clone()))
+        {
+            match kept {
+                Some(rcd) => batch.insert(key, rcd),
+                None => batch.remove(&key),
+            };
+        }
+        match upto {
+            Bound::Included(last) if full => self.next = Bound::Excluded(last),
+            _ => self.done = true,
+        }
+        self.batch.extend(batch);
+    }
+}
+
+impl Iterator for Entries<'_> {
+    type Item = Result<(Vec<u8>, Command)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some((key, rcd)) = self.batch.pop_front() {
+                match self.snapshot.read_live(&key, rcd) {
+                    Ok(Some(cmd)) => return Some(Ok((key, cmd))),
+                    Ok(None) => continue,
+                    Err(e) => return Some(Err(e)),
+                }
+            }
+            if self.done {
+                return None;
+            }
+            self.fill();
+        }
+    }
+}
@@ -0,0 +1,68 @@
+use {
+    super::{
+        generation::Generations,
+        log::{read_all_log_idx_and_sort, Record},
+        replay,
+    },
+    crate::Result,
+    std::{collections::BTreeMap, path::Path},
+};
+
+// The logs a read-only store has replayed, and where the last complete record
+// of the newest one ends, so that a refresh only reads what was appended.
+pub(super) struct Tail {
+    logs: Vec<u64>,
+    end: u64,
+}
+
+impl Tail {
+    pub fn new(logs: Vec<u64>, end: u64) -> Self {
+        Tail { logs, end }
+    }
+
+    // Whether the logs now on disk only add to those replayed. Compaction
+    // deletes logs, and writes its output under an id below the active log.
+    pub fn extends_to(&self, ids: &[u64]) -> bool {
+        ids.starts_with(&self.logs)
+    }
+
+    // Replay the rest of the newest log and every log after it.
+    pub fn catch_up(
+        &mut self,
+        path: &Path,
+        ids: Vec<u64>,
+        index: &mut BTreeMap<Vec<u8>, Record>,
+    ) -> Result<()> {
+        let mut end = self.end;
+        // Read-only stores keep no count of stale bytes.
+        let mut generations = Generations::default();
+        let resume = self.logs.len().saturating_sub(1);
+        for (i, &id) in ids.iter().enumerate().skip(resume) {
+            let start = if i + 1 == self.logs.len() {
+                self.end
+            } else {
+                0
+            };
+            let is_active = i + 1 == ids.len();
+            end = replay(path, id, start, is_active, true, index, &mut generations)?.1;
+        }
+        self.logs = ids;
+        self.end = end;
+        Ok(())
+    }
+}
+
+// The logs on disk, as seen by a store that does not hold the lock. Listing a
+// directory is not atomic: a listing taken while a compaction renames its
+// output and deletes its inputs can miss all of them, so only trust one that
+// the next listing agrees with.
+pub(super) fn settled_log_ids(path: &Path) -> Result<Vec<u64>> {
+    let mut ids = read_all_log_idx_and_sort(path)?;
+    loop {
+        let again = read_all_log_idx_and_sort(path)?;
+        if again == ids {
+            return Ok(ids);
+        }
+        ids = again;
+    }
+}
@@ -0,0 +1,408 @@
+use {
+    super::{
+        compaction::Compaction,
+        format::encode_record,
+        generation::Generations,
+        log::{open_log, BufWriterWithPos, Command, Record},
+        options::{CompactionTrigger, Options},
+        reader::KvStoreReader,
+        snapshot::{Pins, Versions},
+        Index,
+    },
+    crate::{durability::GroupCommit, BatchOp, Durability, KvsError, Result, WriteBatch},
+    failure::Error,
+    std::{
+        fs::File,
+        io::Write,
+        path::PathBuf,
+        sync::{Arc, Mutex, Weak},
+        thread::{self, JoinHandle},
+        time::Duration,
+    },
+};
+
+// Syncs the active log from outside the writer lock, for group commit and
+// interval durability.
+pub(super) struct Syncer {
+    active: Mutex<File>,
+    pub group: GroupCommit,
+}
+
+impl Syncer {
+    pub fn new(active: &File) -> Result<Self> {
+        Ok(Syncer {
+            active: Mutex::new(active.try_clone()?),
+            group: GroupCommit::new(),
+        })
+    }
+
+    pub fn sync(&self) -> Result<()> {
+        self.active.lock().unwrap().sync_data()?;
+        Ok(())
+    }
+
+    // Block until the write of `ticket` is durable.
+    pub fn wait(&self, ticket: u64) -> Result<()> {
+        self.group.wait(ticket, || self.sync())
+    }
+
+    fn replace(&self, active: &File) -> Result<()> {
+        *self.active.lock().unwrap() = active.try_clone()?;
+        Ok(())
+    }
+
+    // Sync the active log every `period` until the store is closed.
+    pub fn spawn_interval(syncer: Weak<Syncer>, period: Duration) {
+        thread::spawn(move || loop {
+            thread::sleep(period);
+            match syncer.upgrade() {
+                // A failed sync is retried on the next tick.
+                Some(syncer) => drop(syncer.sync()),
+                None => return,
+            }
+        });
+    }
+}
+
+// All mutations go through the single writer, which `KvStore` keeps behind a mutex.
+pub(super) struct KvStoreWriter {
+    pub path: Arc<PathBuf>,
+    pub reader: KvStoreReader,
+    pub writer: BufWriterWithPos<File>,
+    pub log_pointer: u64,
+    pub index: Arc<Index>,
+    pub compaction: Option<JoinHandle<Result<()>>>,
+    // The error of a background compaction, kept for `compact_now` to report
+    // since the write that found it had already happened.
+    pub compaction_error: Option<Error>,
+    pub options: Options,
+    pub syncer: Arc<Syncer>,
+    pub pins: Arc<Pins>,
+    pub versions: Arc<Versions>,
+    // The directory lock, released when the last handle to the store drops.
+    pub _lock: File,
+    pub generations: Arc<Mutex<Generations>>,
+}
+
+// Writes return the group commit ticket to wait for, if any, once the writer
+// lock is released.
+impl KvStoreWriter {
+    pub fn set(
+        &mut self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        expires_at: Option<u64>,
+    ) -> Result<Option<u64>> {
+        let cmd = Command::Set {
+            key: key.clone(),
+            value,
+            expires_at,
+        };
+        let (rcd, ticket) = self.write_log(&cmd)?;
+        {
+            let mut index = self.index.write().unwrap();
+            let old = index.insert(key.clone(), rcd);
+            if let Some(old) = old {
+                self.generations
+                    .lock()
+                    .unwrap()
+                    .stale(old.log_id, old.length);
+            }
+            self.versions.written([(&key[..], old)]);
+        }
+        self.after_write()?;
+        Ok(ticket)
+    }
+
+    pub fn remove(&mut self, key: Vec<u8>) -> Result<Option<u64>> {
+        if !self.index.read().unwrap().contains_key(&key) {
+            return Err(KvsError::Remove(String::from_utf8_lossy(&key).into_owned()).into());
+        }
+        let (rcd, ticket) = self.write_log(&Command::Remove { key: key.clone() })?;
+        {
+            let mut index = self.index.write().unwrap();
+            let old = index.remove(&key);
+            if let Some(old) = old {
+                let mut generations = self.generations.lock().unwrap();
+                generations.stale(old.log_id, old.length);
+                // The tombstone itself is garbage once compaction drops the key.
+                generations.stale(rcd.log_id, rcd.length);
+            }
+            self.versions.written([(&key[..], old)]);
+        }
+        self.after_write()?;
+        Ok(ticket)
+    }
+
+    // Write the batch between begin and commit markers with a single write,
+    // then make it visible to readers under a single index lock.
+    pub fn write_batch(&mut self, batch: WriteBatch) -> Result<Option<u64>> {
+        if batch.is_empty() {
+            return Ok(None);
+        }
+        let start = self.writer.pos;
+        let mut buf = encode_record(&Command::BatchBegin {
+            count: batch.len() as u32,
+        })?;
+        let mut markers = buf.len() as u64;
+        let mut records = Vec::with_capacity(batch.len());
+        for op in batch.into_ops() {
+            let cmd = match op {
+                BatchOp::Set { key, value } => Command::Set {
+                    key,
+                    value,
+                    expires_at: None,
+                },
+                BatchOp::Remove { key } => Command::Remove { key },
+            };
+            let record = encode_record(&cmd)?;
+            let rcd = Record {
+                log_id: self.log_pointer,
+                offset: start + buf.len() as u64,
+                length: record.len() as u64,
+            };
+            buf.extend_from_slice(&record);
+            records.push((cmd, rcd));
+        }
+        let commit = encode_record(&Command::BatchCommit)?;
+        markers += commit.len() as u64;
+        buf.extend_from_slice(&commit);
+        let ticket = self.write_buf(&buf)?;
+
+        {
+            let mut index = self.index.write().unwrap();
+            let mut generations = self.generations.lock().unwrap();
+            generations.stale(self.log_pointer, markers);
+            let mut replaced = Vec::with_capacity(records.len());
+            for (cmd, rcd) in records {
+                let (key, old) = match cmd {
+                    Command::Set { key, .. } => {
+                        let old = index.insert(key.clone(), rcd);
+                        (key, old)
+                    }
+                    Command::Remove { key } => {
+                        generations.stale(rcd.log_id, rcd.length);
+                        let old = index.remove(&key);
+                        (key, old)
+                    }
+                    _ => continue,
+                };
+                if let Some(old) = old {
+                    generations.stale(old.log_id, old.length);
+                }
+                replaced.push((key, old));
+            }
+            self.versions
+                .written(replaced.iter().map(|(key, old)| (&key[..], *old)));
+        }
+        self.after_write()?;
+        Ok(ticket)
+    }
+
+    // Append a chunk of a bulk load with a single write. Unlike other writes,
+    // this neither syncs nor checks whether to compact.
+    pub fn load(&mut self, pairs: Vec<(Vec<u8>, Vec<u8>)>) -> Result<()> {
+        let start = self.writer.pos;
+        let mut buf = Vec::new();
+        let mut records = Vec::with_capacity(pairs.len());
+        for (key, value) in pairs {
+            let record = encode_record(&Command::Set {
+                key: key.clone(),
+                value,
+                expires_at: None,
+            })?;
+            let rcd = Record {
+                log_id: self.log_pointer,
+                offset: start + buf.len() as u64,
+                length: record.len() as u64,
+            };
+            buf.extend_from_slice(&record);
+            records.push((key, rcd));
+        }
+        self.writer.write_all(&buf)?;
+        self.writer.flush()?;
+        {
+            let mut index = self.index.write().unwrap();
+            let mut generations = self.generations.lock().unwrap();
+            generations.written(self.log_pointer, buf.len() as u64);
+            let mut replaced = Vec::with_capacity(records.len());
+            for (key, rcd) in &records {
+                let old = index.insert(key.clone(), *rcd);
+                if let Some(old) = old {
+                    generations.stale(old.log_id, old.length);
+                }
+                replaced.push((&key[..], old));
+            }
+            self.versions.written(replaced);
+        }
+        if self.writer.pos > self.options.max_log_size {
+            self.rotate()?;
+        }
+        Ok(())
+    }
+
+    // Make a bulk load durable and catch up on compaction.
+    pub fn finish_load(&mut self) -> Result<()> {
+        if self.options.durability != Durability::None {
+            self.writer.get_ref().sync_data()?;
+            self.syncer.group.mark_synced();
+        }
+        self.after_write()
+    }
+
+    fn write_log(&mut self, cmd: &Command) -> Result<(Record, Option<u64>)> {
+        let offset = self.writer.pos;
+        let ticket = self.write_buf(&encode_record(cmd)?)?;
+        let rcd = Record {
+            log_id: self.log_pointer,
+            offset,
+            length: self.writer.pos - offset,
+        };
+        Ok((rcd, ticket))
+    }
+
+    fn write_buf(&mut self, buf: &[u8]) -> Result<Option<u64>> {
+        self.writer.write_all(buf)?;
+        self.writer.flush()?;
+        // Only the writer touches the generation of the active log.
+        self.generations
+            .lock()
+            .unwrap()
+            .written(self.log_pointer, buf.len() as u64);
+        let ticket = match self.options.durability {
+            Durability::EveryWrite => {
+                self.writer.get_ref().sync_data()?;
+                None
+            }
+            Durability::GroupCommit => Some(self.syncer.group.written()),
+            Durability::None | Durability::Interval(_) => None,
+        };
+        Ok(ticket)
+    }
+
+    // Switch the active log, first syncing the old one so that acknowledged
+    // writes are not left behind in a log the syncer no longer sees, and so
+    // that a crash never leaves a torn record in a log before the active one.
+    fn switch_log(&mut self, log_id: u64) -> Result<()> {
+        self.writer.get_ref().sync_data()?;
+        self.syncer.group.mark_synced();
+        self.log_pointer = log_id;
+        self.writer = open_log(&self.path, log_id)?;
+        self.syncer.replace(self.writer.get_ref())
+    }
+
+    fn after_write(&mut self) -> Result<()> {
+        if self.should_compact() && self.compaction_idle() {
+            let inputs = self.candidates();
+            if !inputs.is_empty() {
+                return self.compact(inputs);
+            }
+        }
+        if self.writer.pos > self.options.max_log_size {
+            self.rotate()
+        } else {
+            Ok(())
+        }
+    }
+
+    fn should_compact(&self) -> bool {
+        let sum = self.generations.lock().unwrap().sum();
+        match self.options.compaction_trigger {
+            CompactionTrigger::Absolute(bytes) => sum.stale > bytes,
+            CompactionTrigger::StaleRatio { ratio, min_bytes } => {
+                sum.stale >= min_bytes && sum.stale as f64 > ratio * sum.total as f64
+            }
+        }
+    }
+
+    // The active log is merged too if it qualifies, as compaction moves on to
+    // a new one anyway.
+    fn candidates(&self) -> Vec<u64> {
+        self.generations.lock().unwrap().candidates(
+            self.log_pointer + 1,
+            self.options.merge_threshold,
+            self.options.max_log_size / 4,
+        )
+    }
+
+    // Only one compaction runs at a time.
+    fn compaction_idle(&mut self) -> bool {
+        match self.compaction.take() {
+            Some(handle) if !handle.is_finished() => {
+                self.compaction = Some(handle);
+                false
+            }
+            Some(handle) => {
+                self.join_compaction(handle);
+                true
+            }
+            None => true,
+        }
+    }
+
+    fn join_compaction(&mut self, handle: JoinHandle<Result<()>>) {
+        let res = match handle.join() {
+            Ok(res) => res,
+            Err(_) => Err(KvsError::Compaction("compaction thread panicked".to_string()).into()),
+        };
+        if let Err(e) = res {
+            self.compaction_error = Some(e);
+        }
+    }
+
+    // Wait for the running compaction, then merge the logs that qualify now
+    // and wait for that too. Report the error of any compaction that failed
+    // since the last call.
+    pub fn compact_now(&mut self) -> Result<()> {
+        if let Some(handle) = self.compaction.take() {
+            self.join_compaction(handle);
+        }
+        if self.compaction_error.is_none() {
+            let inputs = self.candidates();
+            if !inputs.is_empty() {
+                self.compact(inputs)?;
+                if let Some(handle) = self.compaction.take() {
+                    self.join_compaction(handle);
+                }
+            }
+        }
+        match self.compaction_error.take() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    fn rotate(&mut self) -> Result<()> {
+        self.switch_log(self.log_pointer + 1)
+    }
+
+    // Rotate to a new active log and merge `inputs` on a background thread
+    // while writes continue to the new log. Its output takes the id between
+    // the two, above every input and below every later write.
+    fn compact(&mut self, inputs: Vec<u64>) -> Result<()> {
+        let compaction_id = self.log_pointer + 1;
+        self.switch_log(self.log_pointer + 2)?;
+
+        let compaction = Compaction {
+            path: Arc::clone(&self.path),
+            reader: self.reader.clone(),
+            index: Arc::clone(&self.index),
+            compaction_id,
+            inputs,
+            pins: Arc::clone(&self.pins),
+            versions: Arc::clone(&self.versions),
+            generations: Arc::clone(&self.generations),
+        };
+        self.compaction = Some(thread::spawn(move || compaction.run()));
+        Ok(())
+    }
+}
+
+impl Drop for KvStoreWriter {
+    // Never leave a compaction deleting files behind a closed store.
+    fn drop(&mut self) {
+        if let Some(handle) = self.compaction.take() {
+            let _ = handle.join();
+        }
+    }
+}
@@ -1,11 +1,24 @@
+pub mod batch;
+mod counter;
+pub mod durability;
 pub mod errors;
+mod expiry;
 pub mod kvsengine;
 pub mod kvsled;
 pub mod kvstore;
+pub mod manifest;
+pub mod migrate;
 pub mod server;
+pub mod thread_pool;
+pub mod transaction;
 
+pub use batch::{BatchOp, WriteBatch};
+pub use durability::Durability;
 pub use errors::{KvsError, Result};
-pub use kvsengine::KvsEngine;
-pub use kvsled::SledKvsEngine;
-pub use kvstore::KvStore;
-pub use server::{Client, Server};
+pub use kvsengine::{prefix_range, CasOutcome, KvsEngine, KvsIter, KvsSnapshot};
+pub use kvsled::{SledKvsEngine, SledSnapshot};
+pub use kvstore::{CompactionTrigger, KvStore, KvStoreSnapshot, Options};
+pub use manifest::Manifest;
+pub use migrate::{migrate, MigrationReport};
+pub use server::{Client, Request, Response, ScanPage, Server};
+pub use transaction::Transaction;
@@ -0,0 +1,146 @@
+use {
+    crate::{expiry, kvsled, kvstore, KvsError, Result},
+    serde::{Deserialize, Serialize},
+    std::{
+        fs::{self, File},
+        io::{self, Write},
+        path::Path,
+    },
+    uuid::Uuid,
+};
+
+// The file in a data directory recording which engine the store belongs to.
+pub const MANIFEST_FILE: &str = "MANIFEST";
+
+// Older versions recorded only the engine name, in this file.
+const LEGACY_FILE: &str = "pre_engine";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Manifest {
+    pub engine: String,
+    // The newest on-disk format of the engine the store may hold.
+    pub format_version: u32,
+    // Milliseconds since the Unix epoch.
+    pub created_at: u64,
+    pub store_id: String,
+}
+
+impl Manifest {
+    // A manifest for a new store of `engine`.
+    pub fn new(engine: &str) -> Result<Self> {
+        Ok(Manifest {
+            engine: engine.to_string(),
+            format_version: supported_format(engine)?,
+            created_at: expiry::now_millis(),
+            store_id: Uuid::new_v4().to_string(),
+        })
+    }
+
+    // A copy of a store, such as a restored backup, is a store of its own.
+    pub fn renew_store_id(&mut self) {
+        self.store_id = Uuid::new_v4().to_string();
+    }
+
+    // Read the manifest of the store in `dir`, or make one from the engine
+    // name of a legacy `pre_engine` file, recording the legacy sled format
+    // for databases written by sled 0.22. `None` if `dir` holds neither.
+    pub fn read(dir: &Path) -> Result<Option<Self>> {
+        let path = dir.join(MANIFEST_FILE);
+        match fs::read(&path) {
+            Ok(bytes) => {
+                let manifest = serde_json::from_slice(&bytes).map_err(|e| {
+                    KvsError::Manifest(format!("{} is malformed: {}", path.display(), e))
+                })?;
+                return Ok(Some(manifest));
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        match fs::read_to_string(dir.join(LEGACY_FILE)) {
+            Ok(engine) => {
+                let mut manifest = Manifest::new(engine.trim())?;
+                if manifest.engine == "sled" && kvsled::is_legacy_db(dir)? {
+                    manifest.format_version = kvsled::LEGACY_FORMAT_VERSION;
+                }
+                Ok(Some(manifest))
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    // Replace the manifest in `dir` atomically and drop any legacy file.
+    pub fn write(&self, dir: &Path) -> Result<()> {
+        let tmp = dir.join(format!("{}.tmp", MANIFEST_FILE));
+        {
+            let mut file = File::create(&tmp)?;
+            serde_json::to_writer_pretty(&mut file, self)?;
+            file.write_all(b"\n")?;
+            file.sync_all()?;
+        }
+        fs::rename(&tmp, dir.join(MANIFEST_FILE))?;
+        match fs::remove_file(dir.join(LEGACY_FILE)) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            res => Ok(res?),
+        }
+    }
+
+    // Check that the store can be opened with `engine`.
+    pub fn check(&self, dir: &Path, engine: &str) -> Result<()> {
+        if self.engine != engine {
+            return Err(KvsError::EngineMismatch {
+                dir: dir.display().to_string(),
+                recorded: self.engine.clone(),
+                requested: engine.to_string(),
+            }
+            .into());
+        }
+        if engine == "sled" && self.format_version == kvsled::LEGACY_FORMAT_VERSION {
+            return Err(KvsError::Manifest(format!(
+                "the store at {} was written by sled 0.22, which this build cannot open; \
+                 see \"Upgrading sled stores\" in the README",
+                dir.display()
+            ))
+            .into());
+        }
+        let supported = supported_format(engine)?;
+        if self.format_version > supported {
+            return Err(KvsError::Manifest(format!(
+                "the store at {} has {} format version {}, but only up to {} is supported",
+                dir.display(),
+                engine,
+                self.format_version,
+                supported
+            ))
+            .into());
+        }
+        Ok(())
+    }
+
+    // The manifest of the store in `dir`, checked against `engine`, which
+    // defaults to the recorded engine and else to kvs. Nothing is written.
+    pub fn open(dir: &Path, engine: Option<&str>) -> Result<Self> {
+        let manifest = match Manifest::read(dir)? {
+            Some(manifest) => manifest,
+            None => Manifest::new(engine.unwrap_or("kvs"))?,
+        };
+        manifest.check(dir, engine.unwrap_or(&manifest.engine))?;
+        Ok(manifest)
+    }
+
+    // Record the store in `dir` as written by this build, once it is opened
+    // for writing. This is how stores with only `pre_engine` are upgraded.
+    pub fn upgrade(&mut self, dir: &Path) -> Result<()> {
+        self.format_version = supported_format(&self.engine)?;
+        self.write(dir)
+    }
+}
+
+// The newest on-disk format this build writes for `engine`.
+pub fn supported_format(engine: &str) -> Result<u32> {
+    match engine {
+        "kvs" => Ok(kvstore::FORMAT_VERSION.into()),
+        "sled" => Ok(kvsled::FORMAT_VERSION),
+        _ => Err(KvsError::Manifest(format!("unknown engine {}", engine)).into()),
+    }
+}
@@ -0,0 +1,85 @@
+use {
+    crate::{expiry, KvsEngine, KvsError, KvsSnapshot, Result},
+    std::collections::HashMap,
+};
+
+// What `migrate` copied, as verified on the destination.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MigrationReport {
+    pub pairs: u64,
+    // Wrapping sum of the CRC32 of every pair, so it does not depend on the
+    // order pairs are read in and a pair that expires can be taken out.
+    pub checksum: u64,
+}
+
+// Copy every pair from `src` into the empty engine `dst`, keeping expiry
+// times, then check that `dst` holds the same pairs, apart from those that
+// have expired meanwhile. `src` is read from a snapshot, so it is left as it
+// was, expired keys included; writes to it meanwhile are not copied.
+pub fn migrate<S: KvsEngine, D: KvsEngine>(src: &S, dst: &D) -> Result<MigrationReport> {
+    if dst.scan(..)?.next().is_some() {
+        return Err(KvsError::Migration("the destination is not empty".to_string()).into());
+    }
+    let mut expected = MigrationReport {
+        pairs: 0,
+        checksum: 0,
+    };
+    // Deadline and CRC of every key that expires.
+    let mut expiring: HashMap<Vec<u8>, (u64, u32)> = HashMap::new();
+    let snapshot = src.snapshot()?;
+    let pairs = snapshot.scan(..)?.map(|pair| {
+        let (key, value) = pair?;
+        let ttl = snapshot.ttl(key.clone())?;
+        let crc = pair_crc(&key, &value);
+        if let Some(ttl) = ttl {
+            expiring.insert(key.clone(), (expiry::deadline(ttl), crc));
+        }
+        expected.pairs += 1;
+        expected.checksum = expected.checksum.wrapping_add(crc as u64);
+        Ok((key, value))
+    });
+    dst.bulk_load(pairs)?;
+    for (key, &(deadline, _)) in &expiring {
+        match dst.expire(key.clone(), expiry::remaining(deadline)) {
+            Ok(()) => {}
+            // Loading takes long enough for a key to expire on the way.
+            Err(_) if expiry::is_expired(deadline) => {}
+            Err(e) => return Err(e),
+        }
+    }
+
+    let mut copied = MigrationReport {
+        pairs: 0,
+        checksum: 0,
+    };
+    for pair in dst.scan(..)? {
+        let (key, value) = pair?;
+        expiring.remove(&key);
+        copied.pairs += 1;
+        copied.checksum = copied.checksum.wrapping_add(pair_crc(&key, &value) as u64);
+    }
+    for (deadline, crc) in expiring.into_values() {
+        if expiry::is_expired(deadline) {
+            expected.pairs -= 1;
+            expected.checksum = expected.checksum.wrapping_sub(crc as u64);
+        }
+    }
+    if copied != expected {
+        return Err(KvsError::Migration(format!(
+            "copied {} pairs with checksum {:016x}, expected {} pairs with checksum {:016x}",
+            copied.pairs, copied.checksum, expected.pairs, expected.checksum
+        ))
+        .into());
+    }
+    Ok(copied)
+}
+
+// Each length is hashed before its bytes, so no two pairs hash the same input.
+fn pair_crc(key: &[u8], value: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&(key.len() as u64).to_le_bytes());
+    hasher.update(key);
+    hasher.update(&(value.len() as u64).to_le_bytes());
+    hasher.update(value);
+    hasher.finalize()
+}
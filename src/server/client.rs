@@ -1,49 +1,292 @@
 use {
-    crate::{Result},
+    crate::{
+        server::protocol::{read_frame, write_frame, Request, Response, ScanPage},
+        CasOutcome, KvsError, Result, WriteBatch,
+    },
     std::{
-        io::{Read, Write},
-        net::TcpStream,
+        io::{BufReader, BufWriter, Write},
+        net::{Shutdown, TcpStream},
+        ops::Bound,
+        path::Path,
+        thread,
+        time::Duration,
     },
 };
 
+// A client keeps one connection open and reuses it for every request.
 pub struct Client {
-    addr: String,
+    reader: BufReader<TcpStream>,
+    writer: BufWriter<TcpStream>,
 }
 
 impl Client {
-    pub fn new(addr: String) -> Self {
-        return Client { addr };
+    pub fn connect(addr: &str) -> Result<Self> {
+        let conn = TcpStream::connect(addr)?;
+        conn.set_nodelay(true)?;
+        Ok(Client {
+            reader: BufReader::new(conn.try_clone()?),
+            writer: BufWriter::new(conn),
+        })
     }
-    pub fn get(&self, key: &str) -> Result<String> {
-        let mut conn = TcpStream::connect(self.addr.to_string())?;
 
-        let req = ["get", key].join(" ");
-        conn.write_all(req.as_bytes())?;
-        conn.shutdown(std::net::Shutdown::Write)?;
+    pub fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        match self.request(&Request::Get { key: key.to_vec() })? {
+            Response::Ok(value) => Ok(value),
+            Response::KeyNotFound => Ok(None),
+            Response::Err(e) => Err(KvsError::Server(e).into()),
+            resp => Err(KvsError::Frame(format!("unexpected response {:?}", resp)).into()),
+        }
+    }
 
-        let mut buf = String::new();
-        conn.read_to_string(&mut buf)?;
-        Ok(buf)
+    pub fn set(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        match self.request(&Request::Set {
+            key: key.to_vec(),
+            value: value.to_vec(),
+        })? {
+            Response::Ok(_) => Ok(()),
+            Response::Err(e) => Err(KvsError::Server(e).into()),
+            resp => Err(KvsError::Frame(format!("unexpected response {:?}", resp)).into()),
+        }
     }
 
-    pub fn set(&self, key: &str, value: &str) -> Result<()> {
-        let mut conn = TcpStream::connect(self.addr.to_string())?;
+    pub fn remove(&mut self, key: &[u8]) -> Result<()> {
+        match self.request(&Request::Remove { key: key.to_vec() })? {
+            Response::Ok(_) => Ok(()),
+            Response::KeyNotFound => {
+                Err(KvsError::Remove(String::from_utf8_lossy(key).into_owned()).into())
+            }
+            Response::Err(e) => Err(KvsError::Server(e).into()),
+            resp => Err(KvsError::Frame(format!("unexpected response {:?}", resp)).into()),
+        }
+    }
 
-        let req = ["set", key, value].join(" ");
-        conn.write_all(req.as_bytes())?;
-        conn.shutdown(std::net::Shutdown::Write)?;
-        Ok(())
+    pub fn set_with_ttl(&mut self, key: &[u8], value: &[u8], ttl: Duration) -> Result<()> {
+        let req = Request::SetWithTtl {
+            key: key.to_vec(),
+            value: value.to_vec(),
+            ttl_ms: ttl.as_millis() as u64,
+        };
+        self.request_for_key(&req, key)
     }
 
-    pub fn remove(&self, key: &str) -> Result<String> {
-        let mut conn = TcpStream::connect(self.addr.to_string())?;
+    pub fn expire(&mut self, key: &[u8], ttl: Duration) -> Result<()> {
+        let req = Request::Expire {
+            key: key.to_vec(),
+            ttl_ms: ttl.as_millis() as u64,
+        };
+        self.request_for_key(&req, key)
+    }
 
-        let req = ["rm", key].join(" ");
-        conn.write_all(req.as_bytes())?;
-        conn.shutdown(std::net::Shutdown::Write)?;
+    // Return the time left before the key expires, `None` if it never does.
+    pub fn ttl(&mut self, key: &[u8]) -> Result<Option<Duration>> {
+        match self.request(&Request::Ttl { key: key.to_vec() })? {
+            Response::Ttl(ttl) => Ok(ttl.map(Duration::from_millis)),
+            Response::KeyNotFound => Err(key_not_found(key)),
+            Response::Err(e) => Err(KvsError::Server(e).into()),
+            resp => Err(KvsError::Frame(format!("unexpected response {:?}", resp)).into()),
+        }
+    }
+
+    pub fn persist(&mut self, key: &[u8]) -> Result<()> {
+        self.request_for_key(&Request::Persist { key: key.to_vec() }, key)
+    }
+
+    pub fn write_batch(&mut self, batch: WriteBatch) -> Result<()> {
+        self.request_ok(&Request::Batch(batch))
+    }
+
+    pub fn compare_and_swap(
+        &mut self,
+        key: &[u8],
+        expected: Option<&[u8]>,
+        new: Option<&[u8]>,
+    ) -> Result<CasOutcome> {
+        match self.request(&Request::Cas {
+            key: key.to_vec(),
+            expected: expected.map(<[u8]>::to_vec),
+            new: new.map(<[u8]>::to_vec),
+        })? {
+            Response::Ok(_) => Ok(CasOutcome::Swapped),
+            Response::Conflict(current) => Ok(CasOutcome::Conflict { current }),
+            Response::Err(e) => Err(KvsError::Server(e).into()),
+            resp => Err(KvsError::Frame(format!("unexpected response {:?}", resp)).into()),
+        }
+    }
+
+    // Return whether the key was absent and is now set.
+    pub fn set_if_absent(&mut self, key: &[u8], value: &[u8]) -> Result<bool> {
+        Ok(self.compare_and_swap(key, None, Some(value))? == CasOutcome::Swapped)
+    }
+
+    // Return whether the key was present and is now overwritten.
+    pub fn set_if_present(&mut self, key: &[u8], value: &[u8]) -> Result<bool> {
+        let mut current = self.get(key)?;
+        while let Some(expected) = current {
+            match self.compare_and_swap(key, Some(&expected), Some(value))? {
+                CasOutcome::Swapped => return Ok(true),
+                CasOutcome::Conflict { current: actual } => current = actual,
+            }
+        }
+        Ok(false)
+    }
+
+    // Return the value after adding `delta`.
+    pub fn incr_by(&mut self, key: &[u8], delta: i64) -> Result<i64> {
+        let req = Request::IncrBy {
+            key: key.to_vec(),
+            delta,
+        };
+        self.request_integer(&req, key)
+    }
+
+    // Return the value after subtracting `delta`.
+    pub fn decr_by(&mut self, key: &[u8], delta: i64) -> Result<i64> {
+        let req = Request::DecrBy {
+            key: key.to_vec(),
+            delta,
+        };
+        self.request_integer(&req, key)
+    }
+
+    // Return the length of the value after appending.
+    pub fn append(&mut self, key: &[u8], value: &[u8]) -> Result<u64> {
+        let req = Request::Append {
+            key: key.to_vec(),
+            value: value.to_vec(),
+        };
+        Ok(self.request_integer(&req, key)? as u64)
+    }
+
+    // Open a transaction on this connection. `get`, `set` and `remove` act on
+    // it until `commit` or `abort`.
+    pub fn begin(&mut self) -> Result<()> {
+        self.request_ok(&Request::Begin)
+    }
+
+    // Return `KvsError::TransactionConflict` if a key the transaction read
+    // has changed since; nothing is written then.
+    pub fn commit(&mut self) -> Result<()> {
+        match self.request(&Request::Commit)? {
+            Response::Ok(_) => Ok(()),
+            Response::TransactionConflict => Err(KvsError::TransactionConflict.into()),
+            Response::Err(e) => Err(KvsError::Server(e).into()),
+            resp => Err(KvsError::Frame(format!("unexpected response {:?}", resp)).into()),
+        }
+    }
 
-        let mut buf = String::new();
-        conn.read_to_string(&mut buf)?;
-        Ok(buf)
+    pub fn abort(&mut self) -> Result<()> {
+        self.request_ok(&Request::Abort)
     }
+
+    // `path` is relative to the backup directory of the server.
+    pub fn backup(&mut self, path: &Path) -> Result<()> {
+        self.request_ok(&Request::Backup {
+            path: path.to_path_buf(),
+        })
+    }
+
+    // Return an error if the stored value is not valid UTF-8.
+    pub fn get_string(&mut self, key: &str) -> Result<Option<String>> {
+        match self.get(key.as_bytes())? {
+            Some(value) => Ok(Some(String::from_utf8(value)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn set_string(&mut self, key: &str, value: &str) -> Result<()> {
+        self.set(key.as_bytes(), value.as_bytes())
+    }
+
+    pub fn remove_string(&mut self, key: &str) -> Result<()> {
+        self.remove(key.as_bytes())
+    }
+
+    // Fetch one page of at most `limit` pairs. Pass the returned cursor as
+    // `Bound::Excluded` start to fetch the next page.
+    pub fn scan(
+        &mut self,
+        start: Bound<Vec<u8>>,
+        end: Bound<Vec<u8>>,
+        limit: u32,
+    ) -> Result<ScanPage> {
+        match self.request(&Request::Scan { start, end, limit })? {
+            Response::Scan(page) => Ok(page),
+            Response::Err(e) => Err(KvsError::Server(e).into()),
+            resp => Err(KvsError::Frame(format!("unexpected response {:?}", resp)).into()),
+        }
+    }
+
+    // Send all requests without waiting for their responses. The server
+    // answers in request order, so the n-th response belongs to the n-th
+    // request. Responses are read on another thread while requests are still
+    // being sent: otherwise both ends could fill their socket buffers and
+    // block on writing forever.
+    pub fn pipeline(&mut self, reqs: &[Request]) -> Result<Vec<Response>> {
+        let Client { reader, writer } = self;
+        thread::scope(|scope| {
+            let responses = scope.spawn(move || {
+                reqs.iter()
+                    .map(|_| read_response(reader))
+                    .collect::<Result<Vec<_>>>()
+            });
+            let sent = reqs
+                .iter()
+                .try_for_each(|req| write_frame(writer, req))
+                .and_then(|()| Ok(writer.flush()?));
+            if let Err(e) = sent {
+                // Unblock the reader, which would wait for responses to
+                // requests that were never sent.
+                let _ = writer.get_ref().shutdown(Shutdown::Both);
+                return Err(e);
+            }
+            responses.join().expect("pipeline reader panicked")
+        })
+    }
+
+    // Send a request about `key` that is answered with `Response::Ok`, or
+    // `Response::KeyNotFound` if the key does not exist.
+    fn request_for_key(&mut self, req: &Request, key: &[u8]) -> Result<()> {
+        match self.request(req)? {
+            Response::Ok(_) => Ok(()),
+            Response::KeyNotFound => Err(key_not_found(key)),
+            Response::Err(e) => Err(KvsError::Server(e).into()),
+            resp => Err(KvsError::Frame(format!("unexpected response {:?}", resp)).into()),
+        }
+    }
+
+    // Send a request that is answered with `Response::Ok`.
+    fn request_ok(&mut self, req: &Request) -> Result<()> {
+        match self.request(req)? {
+            Response::Ok(_) => Ok(()),
+            Response::Err(e) => Err(KvsError::Server(e).into()),
+            resp => Err(KvsError::Frame(format!("unexpected response {:?}", resp)).into()),
+        }
+    }
+
+    // Send a request about `key` that is answered with `Response::Integer`.
+    fn request_integer(&mut self, req: &Request, key: &[u8]) -> Result<i64> {
+        match self.request(req)? {
+            Response::Integer(value) => Ok(value),
+            Response::NotAnInteger => {
+                Err(KvsError::NotAnInteger(String::from_utf8_lossy(key).into_owned()).into())
+            }
+            Response::Err(e) => Err(KvsError::Server(e).into()),
+            resp => Err(KvsError::Frame(format!("unexpected response {:?}", resp)).into()),
+        }
+    }
+
+    fn request(&mut self, req: &Request) -> Result<Response> {
+        write_frame(&mut self.writer, req)?;
+        self.writer.flush()?;
+        read_response(&mut self.reader)
+    }
+}
+
+fn read_response(reader: &mut BufReader<TcpStream>) -> Result<Response> {
+    read_frame(reader)?
+        .ok_or_else(|| KvsError::Frame("connection closed before response".to_string()).into())
+}
+
+fn key_not_found(key: &[u8]) -> failure::Error {
+    KvsError::Get(String::from_utf8_lossy(key).into_owned()).into()
 }
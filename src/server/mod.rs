@@ -1,5 +1,8 @@
 pub mod client;
+pub mod protocol;
+#[allow(clippy::module_inception)]
 pub mod server;
 
 pub use client::Client;
+pub use protocol::{Request, Response, ScanPage};
 pub use server::Server;
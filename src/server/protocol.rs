@@ -0,0 +1,157 @@
+use {
+    crate::{KvsError, Result, WriteBatch},
+    serde::{de::DeserializeOwned, Deserialize, Serialize},
+    std::{
+        io::{self, Read, Write},
+        ops::Bound,
+        path::PathBuf,
+    },
+};
+
+// Frames larger than this are rejected before allocating the payload buffer.
+const MAX_FRAME_LEN: u32 = 64 * 1024 * 1024;
+
+// A scan page stops before its keys and values would take more than this
+// many bytes, well below `MAX_FRAME_LEN`, whatever its limit. A single pair
+// larger than this still gets a page of its own.
+pub const MAX_PAGE_LEN: usize = 4 * 1024 * 1024;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum Request {
+    Get {
+        key: Vec<u8>,
+    },
+    Set {
+        key: Vec<u8>,
+        value: Vec<u8>,
+    },
+    SetWithTtl {
+        key: Vec<u8>,
+        value: Vec<u8>,
+        ttl_ms: u64,
+    },
+    // Answered with `Response::KeyNotFound` for absent keys, like `Remove`.
+    Expire {
+        key: Vec<u8>,
+        ttl_ms: u64,
+    },
+    Ttl {
+        key: Vec<u8>,
+    },
+    Persist {
+        key: Vec<u8>,
+    },
+    Remove {
+        key: Vec<u8>,
+    },
+    // Applied atomically, answered with `Response::Ok(None)`.
+    Batch(WriteBatch),
+    // Answered with `Response::Ok(None)` if swapped, else `Response::Conflict`.
+    Cas {
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    },
+    // At most `limit` pairs in key order, with `limit` at least 1, and fewer
+    // when another pair would take the page past `MAX_PAGE_LEN` bytes. Continue
+    // a scan by starting after the returned cursor.
+    Scan {
+        start: Bound<Vec<u8>>,
+        end: Bound<Vec<u8>>,
+        limit: u32,
+    },
+    // Answered with `Response::Integer` holding the new value, or
+    // `Response::NotAnInteger`.
+    IncrBy {
+        key: Vec<u8>,
+        delta: i64,
+    },
+    DecrBy {
+        key: Vec<u8>,
+        delta: i64,
+    },
+    // Answered with `Response::Integer` holding the new length of the value.
+    Append {
+        key: Vec<u8>,
+        value: Vec<u8>,
+    },
+    // Open a transaction on this connection. Until `Commit` or `Abort`, only
+    // `Get`, `Set` and `Remove` are accepted and they act on the transaction.
+    // Closing the connection aborts it, and so does the server once the
+    // connection sends nothing for its transaction timeout; it closes the
+    // connection then. Refused with `Response::Err` while the server has as
+    // many transactions open as it allows.
+    //
+    // A transaction reads a snapshot, which costs nothing to take. While it
+    // is open, every write keeps what its keys held before: on kvs where the
+    // old records are in the logs, which stay on disk meanwhile, and on sled
+    // the old values themselves.
+    Begin,
+    // Answered with `Response::TransactionConflict` if a key the transaction
+    // read was written since it began, even back to the value it had; nothing
+    // is written then.
+    Commit,
+    Abort,
+    // Write a backup to a directory on the server host, which must be empty
+    // or missing.
+    Backup {
+        path: PathBuf,
+    },
+}
+
+// Every variant is an explicit status: callers match on it instead of parsing
+// the payload.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum Response {
+    Ok(Option<Vec<u8>>),
+    Scan(ScanPage),
+    // Milliseconds left before the key expires, `None` if it never does.
+    Ttl(Option<u64>),
+    // A compare-and-swap found this value instead of the expected one.
+    Conflict(Option<Vec<u8>>),
+    KeyNotFound,
+    Err(String),
+    Integer(i64),
+    // The stored value is not an integer, or the result would overflow.
+    NotAnInteger,
+    TransactionConflict,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ScanPage {
+    pub pairs: Vec<(Vec<u8>, Vec<u8>)>,
+    // The last key returned, if the range may hold more keys.
+    pub cursor: Option<Vec<u8>>,
+}
+
+// A frame is a big-endian u32 payload length followed by the bincode encoded
+// message, which carries keys and values as raw bytes.
+// The writer is not flushed so that pipelined frames can share one syscall.
+pub fn write_frame<W: Write, T: Serialize>(writer: &mut W, msg: &T) -> Result<()> {
+    let payload = bincode::serialize(msg)?;
+    if payload.len() as u64 > MAX_FRAME_LEN as u64 {
+        return Err(
+            KvsError::Frame(format!("frame of {} bytes is too large", payload.len())).into(),
+        );
+    }
+    writer.write_all(&(payload.len() as u32).to_be_bytes())?;
+    writer.write_all(&payload)?;
+    Ok(())
+}
+
+// Return `None` if the peer closed the connection before a new frame started.
+pub fn read_frame<R: Read, T: DeserializeOwned>(reader: &mut R) -> Result<Option<T>> {
+    let mut len = [0u8; 4];
+    match reader.read_exact(&mut len) {
+        Ok(()) => {}
+        Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    let len = u32::from_be_bytes(len);
+    if len > MAX_FRAME_LEN {
+        return Err(KvsError::Frame(format!("frame of {} bytes is too large", len)).into());
+    }
+    let mut payload = vec![0u8; len as usize];
+    reader.read_exact(&mut payload)?;
+    Ok(Some(bincode::deserialize(&payload)?))
+}
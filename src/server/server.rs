@@ -1,107 +1,560 @@
 use {
-    crate::{ KvsEngine, KvsError, Result},
-    failure::{ Fail},
+    crate::{
+        server::protocol::{read_frame, write_frame, Request, Response, ScanPage, MAX_PAGE_LEN},
+        thread_pool::ThreadPool,
+        CasOutcome, KvsEngine, KvsError, Result, Transaction,
+    },
+    crossbeam_channel::{unbounded, Sender},
+    mio::{net::TcpListener, Events, Interest, Poll, Token, Waker},
     slog::{debug, error, Logger},
     std::{
-        io::{Read, Write},
-        net::{TcpListener, TcpStream},
+        collections::{BTreeSet, HashMap},
+        io::{self, BufRead, BufReader, BufWriter, Write},
+        net::{self, TcpStream},
+        ops::Bound,
+        path::{Component, Path, PathBuf},
         process::exit,
-        rc::Rc,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::{Duration, Instant},
     },
 };
 
-pub struct Server<E: KvsEngine> {
+pub struct Server<E: KvsEngine, P: ThreadPool> {
     engine: E,
-    logger: Rc<Logger>,
+    pool: P,
+    logger: Logger,
+    // Where clients may write backups. `None` refuses them.
+    backup_dir: Option<Arc<PathBuf>>,
+    txn_timeout: Duration,
+    txn_slots: Arc<TxnSlots>,
 }
 
-impl<E: KvsEngine> Server<E> {
-    pub fn new(engine: E, logger: Rc<Logger>) -> Result<Self> {
-        Ok(Server { engine, logger })
+impl<E: KvsEngine, P: ThreadPool> Server<E, P> {
+    pub fn new(engine: E, pool: P, logger: Logger) -> Result<Self> {
+        Ok(Server {
+            engine,
+            pool,
+            logger,
+            backup_dir: None,
+            txn_timeout: TXN_TIMEOUT,
+            txn_slots: Arc::new(TxnSlots::new(MAX_TXNS)),
+        })
     }
 
-    pub fn serve(&mut self, addr: &String) -> Result<()> {
-        let logger = self.logger.clone();
-        let listener = match TcpListener::bind(addr) {
+    // Accept backup requests, writing them under `dir`.
+    pub fn backup_dir(mut self, dir: PathBuf) -> Self {
+        self.backup_dir = Some(Arc::new(dir));
+        self
+    }
+
+    // Abort a transaction once its connection has sent nothing for `timeout`,
+    // closing the connection.
+    pub fn transaction_timeout(mut self, timeout: Duration) -> Self {
+        self.txn_timeout = timeout;
+        self
+    }
+
+    // Refuse to begin more than `max` transactions at once.
+    pub fn max_transactions(mut self, max: usize) -> Self {
+        self.txn_slots = Arc::new(TxnSlots::new(max));
+        self
+    }
+
+    // Accept connections forever. A connection only holds a pool thread while
+    // it has requests to serve: once it goes quiet it is parked, and this
+    // thread hands it back to the pool when its next request arrives, so idle
+    // clients never starve busy ones. This thread sleeps until a connection
+    // arrives, a parked one becomes readable, or a parked one has left its
+    // transaction idle for too long.
+    pub fn serve(&self, addr: &str) -> Result<()> {
+        let logger = &self.logger;
+        let listener = match net::TcpListener::bind(addr) {
             Ok(l) => l,
             Err(e) => {
                 error!(logger, "listen on {} failed: {:?}", addr, e);
                 exit(1);
             }
         };
+        listener.set_nonblocking(true)?;
+        let mut listener = TcpListener::from_std(listener);
 
-        for stream in listener.incoming() {
-            let stream = match stream {
-                Ok(s) => s,
-                Err(e) => {
-                    error!(logger, "accept tcp connection failed: {:?}", e);
-                    continue;
+        let mut poll = Poll::new()?;
+        poll.registry()
+            .register(&mut listener, LISTENER, Interest::READABLE)?;
+        let waker = Arc::new(Waker::new(poll.registry(), WAKER)?);
+        let (park, parked) = unbounded();
+        let mut idle: HashMap<Token, Parked<E>> = HashMap::new();
+        // When each parked connection with an open transaction times out.
+        let mut deadlines: BTreeSet<(Instant, Token)> = BTreeSet::new();
+        let mut next_token = FIRST_CONN.0;
+        let mut events = Events::with_capacity(1024);
+        loop {
+            let timeout = deadlines
+                .first()
+                .map(|&(deadline, _)| deadline.saturating_duration_since(Instant::now()));
+            if let Err(e) = poll.poll(&mut events, timeout) {
+                if e.kind() != io::ErrorKind::Interrupted {
+                    return Err(e.into());
+                }
+            }
+            for event in events.iter() {
+                match event.token() {
+                    LISTENER => loop {
+                        match listener.accept() {
+                            Ok((stream, _)) => {
+                                debug!(logger, "accept conn: {:?}", stream);
+                                match Connection::new(stream.into()) {
+                                    Ok(conn) => self.dispatch(conn, &park, &waker),
+                                    Err(e) => {
+                                        error!(logger, "set up tcp connection failed: {:?}", e)
+                                    }
+                                }
+                            }
+                            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                            Err(e) => {
+                                error!(logger, "accept tcp connection failed: {:?}", e);
+                                break;
+                            }
+                        }
+                    },
+                    WAKER => {
+                        for conn in parked.try_iter() {
+                            let token = Token(next_token);
+                            next_token += 1;
+                            let deadline = conn.txn_deadline(self.txn_timeout);
+                            match Parked::new(conn, poll.registry(), token) {
+                                Ok(parked) => {
+                                    idle.insert(token, parked);
+                                    if let Some(deadline) = deadline {
+                                        deadlines.insert((deadline, token));
+                                    }
+                                }
+                                Err(e) => debug!(logger, "drop parked conn: {:?}", e),
+                            }
+                        }
+                    }
+                    // The peer sent something, or hung up; either way a worker
+                    // has to look at it.
+                    token => {
+                        if let Some(parked) = idle.remove(&token) {
+                            if let Some(deadline) = parked.conn.txn_deadline(self.txn_timeout) {
+                                deadlines.remove(&(deadline, token));
+                            }
+                            match parked.wake(poll.registry()) {
+                                Ok(conn) => self.dispatch(conn, &park, &waker),
+                                Err(e) => debug!(logger, "drop parked conn: {:?}", e),
+                            }
+                        }
+                    }
+                }
+            }
+            // Dropping a connection aborts its transaction and frees the
+            // snapshot it holds.
+            let now = Instant::now();
+            while let Some(&(deadline, token)) = deadlines.first() {
+                if deadline > now {
+                    break;
                 }
-            };
-            self.handle(stream)?;
+                deadlines.pop_first();
+                if let Some(parked) = idle.remove(&token) {
+                    debug!(logger, "abort idle transaction of conn: {:?}", parked.watch);
+                    if let Err(e) = parked.wake(poll.registry()) {
+                        debug!(logger, "drop parked conn: {:?}", e);
+                    }
+                }
+            }
         }
-        Ok(())
     }
 
-    pub fn handle(&mut self, stream: TcpStream) -> Result<()> {
-        let engine = &mut self.engine;
+    // Serve requests of `conn` on the pool, and park it once it goes quiet.
+    fn dispatch(&self, conn: Connection<E>, park: &Sender<Connection<E>>, waker: &Arc<Waker>) {
+        let engine = self.engine.clone();
         let logger = self.logger.clone();
-        let mut stream = stream;
+        let backup_dir = self.backup_dir.clone();
+        let txn_slots = Arc::clone(&self.txn_slots);
+        let park = park.clone();
+        let waker = Arc::clone(waker);
+        self.pool.spawn(move || {
+            match handle(&engine, backup_dir.as_deref(), &txn_slots, conn, &logger) {
+                Ok(Some(conn)) => {
+                    // The server only drops the receiver when it stops serving.
+                    if park.send(conn).is_ok() {
+                        if let Err(e) = waker.wake() {
+                            error!(logger, "wake server failed: {:?}", e);
+                        }
+                    }
+                }
+                Ok(None) => {}
+                Err(e) => error!(logger, "handle connection failed: {:?}", e),
+            }
+        });
+    }
+}
 
-        debug!(logger, "accept conn: {:?}", stream);
+const LISTENER: Token = Token(0);
+const WAKER: Token = Token(1);
+const FIRST_CONN: Token = Token(2);
 
-        let mut buf = String::new();
-        stream.read_to_string(&mut buf)?;
+// How long a worker waits for the next request before parking a connection.
+const LINGER: Duration = Duration::from_millis(10);
 
-        debug!(logger, "read from stream: {}", buf);
+// How long a client may pause while sending a request it started. The
+// request as a whole may take longer, as long as its bytes keep coming.
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
 
-        let params: Vec<&str> = buf.split(" ").collect();
+// How long a response may take to go out before the connection is dropped, so
+// that a client cannot hold a pool thread by not reading its responses.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(5);
 
-        match params.as_slice() {
-            ["get", params @ ..] => {
-                let key = params.get(0).ok_or(CliError::Key)?;
-                match engine.get(key.to_string())? {
-                    Some(value) => {
-                        if let Err(e) = stream.write_fmt(format_args!("{}", value)) {
-                            error!(logger, "write data to tcp stream failed: {:?}", e);
-                            return Ok(());
-                        }
-                    }
-                    None => {
-                        if let Err(e) = stream.write_fmt(format_args!("Key not found")) {
-                            error!(logger, "write data to tcp stream failed: {:?}", e);
-                            return Ok(());
-                        }
-                    }
+// How long a transaction may stay open without requests, by default. An open
+// transaction holds a snapshot, which costs memory and slows writers.
+const TXN_TIMEOUT: Duration = Duration::from_secs(30);
+
+// How many transactions may be open at once, by default.
+const MAX_TXNS: usize = 64;
+
+// Counts the open transactions of all connections against a limit.
+struct TxnSlots {
+    open: AtomicUsize,
+    max: usize,
+}
+
+impl TxnSlots {
+    fn new(max: usize) -> Self {
+        TxnSlots {
+            open: AtomicUsize::new(0),
+            max,
+        }
+    }
+
+    // `None` if every slot is taken.
+    fn take(self: &Arc<Self>) -> Option<TxnSlot> {
+        self.open
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |open| {
+                (open < self.max).then_some(open + 1)
+            })
+            .ok()
+            .map(|_| TxnSlot(Arc::clone(self)))
+    }
+}
+
+// One taken slot, given back when dropped along with its transaction.
+struct TxnSlot(Arc<TxnSlots>);
+
+impl Drop for TxnSlot {
+    fn drop(&mut self) {
+        self.0.open.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// The transaction open on a connection.
+struct OpenTxn<E: KvsEngine> {
+    txn: Transaction<E>,
+    _slot: TxnSlot,
+    // When the connection last sent a request.
+    last_used: Instant,
+}
+
+// A parked connection, registered to wake the server once it is readable.
+struct Parked<E: KvsEngine> {
+    conn: Connection<E>,
+    watch: mio::net::TcpStream,
+}
+
+impl<E: KvsEngine> Parked<E> {
+    fn new(conn: Connection<E>, registry: &mio::Registry, token: Token) -> Result<Self> {
+        // A clone of the socket is only watched, never read, so the
+        // connection stays in blocking mode.
+        let mut watch = mio::net::TcpStream::from_std(conn.reader.get_ref().try_clone()?);
+        registry.register(&mut watch, token, Interest::READABLE)?;
+        Ok(Parked { conn, watch })
+    }
+
+    fn wake(mut self, registry: &mio::Registry) -> Result<Connection<E>> {
+        registry.deregister(&mut self.watch)?;
+        Ok(self.conn)
+    }
+}
+
+// A client connection and the transaction opened on it, if any.
+struct Connection<E: KvsEngine> {
+    reader: BufReader<TcpStream>,
+    writer: BufWriter<Deadline>,
+    txn: Option<OpenTxn<E>>,
+}
+
+impl<E: KvsEngine> Connection<E> {
+    fn new(stream: TcpStream) -> Result<Self> {
+        // Accepted sockets may inherit the listener's non-blocking mode.
+        stream.set_nonblocking(false)?;
+        Ok(Connection {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: BufWriter::new(Deadline {
+                stream,
+                until: None,
+            }),
+            txn: None,
+        })
+    }
+
+    // When the open transaction, if any, has been idle for `timeout`.
+    fn txn_deadline(&self, timeout: Duration) -> Option<Instant> {
+        self.txn.as_ref().map(|open| open.last_used + timeout)
+    }
+
+    // Wait up to `LINGER` for the next request to start, and then up to
+    // `READ_TIMEOUT` for each read of the rest of it. `None` if the peer
+    // closed the connection.
+    fn next_request(&mut self) -> Result<Option<Option<Request>>> {
+        if self.reader.buffer().is_empty() {
+            self.reader.get_ref().set_read_timeout(Some(LINGER))?;
+            let res = self.reader.fill_buf().map(|buf| buf.is_empty());
+            self.reader.get_ref().set_read_timeout(None)?;
+            match res {
+                Ok(true) => return Ok(None),
+                Ok(false) => {}
+                Err(ref e)
+                    if e.kind() == io::ErrorKind::WouldBlock
+                        || e.kind() == io::ErrorKind::TimedOut =>
+                {
+                    return Ok(Some(None))
                 }
+                Err(e) => return Err(e.into()),
             }
-            ["set", params @ ..] => {
-                let key = params.get(0).ok_or(CliError::Key)?;
-                let value = params.get(1).ok_or(CliError::Value)?;
-                engine.set(key.to_string(), value.to_string())?;
+        }
+        self.reader.get_ref().set_read_timeout(Some(READ_TIMEOUT))?;
+        let req = read_frame::<_, Request>(&mut self.reader);
+        self.reader.get_ref().set_read_timeout(None)?;
+        Ok(req?.map(Some))
+    }
+}
+
+// Fails writes once `WRITE_TIMEOUT` has passed since the first write after
+// `until` was cleared. A write timeout alone is not enough: a client that
+// reads a few bytes now and then keeps every write going.
+struct Deadline {
+    stream: TcpStream,
+    until: Option<Instant>,
+}
+
+impl Write for Deadline {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let until = *self
+            .until
+            .get_or_insert_with(|| Instant::now() + WRITE_TIMEOUT);
+        let left = until.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "response not sent in time",
+            ));
+        }
+        self.stream.set_write_timeout(Some(left))?;
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+// Serve requests from one connection until it goes quiet, and hand it back to
+// be parked then. `None` once the peer has closed it.
+fn handle<E: KvsEngine>(
+    engine: &E,
+    backup_dir: Option<&PathBuf>,
+    txn_slots: &Arc<TxnSlots>,
+    mut conn: Connection<E>,
+    logger: &Logger,
+) -> Result<Option<Connection<E>>> {
+    loop {
+        let req = match conn.next_request()? {
+            Some(Some(req)) => req,
+            Some(None) => return Ok(Some(conn)),
+            None => return Ok(None),
+        };
+        debug!(logger, "read from stream: {:?}", req);
+
+        let txn = &mut conn.txn;
+        if let Some(ref mut open) = txn {
+            open.last_used = Instant::now();
+        }
+        let resp = match req {
+            Request::Begin if txn.is_some() => {
+                Response::Err("a transaction is already open".to_string())
             }
-            ["rm", params @ ..] => {
-                let key = params.get(0).ok_or(CliError::Key)?;
-                if let Err(err) = engine.remove(key.to_string()) {
-                    debug!(logger, "Key not found: {:?}", err);
-                    if let Err(e) = stream.write_fmt(format_args!("Key not found: {:?}", err)) {
-                        error!(logger, "write data to tcp stream failed: {:?}", e);
-                        return Ok(());
+            Request::Begin => match txn_slots.take() {
+                Some(slot) => match engine.begin() {
+                    Ok(begun) => {
+                        *txn = Some(OpenTxn {
+                            txn: begun,
+                            _slot: slot,
+                            last_used: Instant::now(),
+                        });
+                        Response::Ok(None)
                     }
-                }
+                    Err(e) => error_response(e),
+                },
+                None => Response::Err("too many transactions are open".to_string()),
+            },
+            Request::Commit => match txn.take().map(|open| open.txn.commit()) {
+                Some(Ok(())) => Response::Ok(None),
+                Some(Err(e)) => error_response(e),
+                None => Response::Err("no transaction is open".to_string()),
+            },
+            Request::Abort => match txn.take() {
+                Some(_) => Response::Ok(None),
+                None => Response::Err("no transaction is open".to_string()),
+            },
+            req => match txn {
+                Some(ref mut open) => execute_in_transaction(&mut open.txn, req),
+                None => execute(engine, backup_dir, req),
+            },
+        };
+        conn.writer.get_mut().until = None;
+        write_frame(&mut conn.writer, &resp)?;
+        // Hold responses back while more pipelined requests are buffered.
+        if conn.reader.buffer().is_empty() {
+            conn.writer.flush()?;
+        }
+    }
+}
+
+fn execute<E: KvsEngine>(engine: &E, backup_dir: Option<&PathBuf>, req: Request) -> Response {
+    let res = match req {
+        Request::Cas { key, expected, new } => {
+            return match engine.compare_and_swap(key, expected, new) {
+                Ok(CasOutcome::Swapped) => Response::Ok(None),
+                Ok(CasOutcome::Conflict { current }) => Response::Conflict(current),
+                Err(e) => error_response(e),
             }
-            _ => {
-                return Err(KvsError::UnKnownOperation("".to_string()).into());
+        }
+        // A page of no pairs could not tell where the next one starts.
+        Request::Scan { limit: 0, .. } => {
+            return Response::Err("scan limit must be at least 1".to_string())
+        }
+        Request::Scan { start, end, limit } => {
+            return match scan(engine, start, end, limit) {
+                Ok(page) => Response::Scan(page),
+                Err(e) => error_response(e),
             }
         }
-        Ok(())
+        Request::Ttl { key } => {
+            return match engine.ttl(key) {
+                Ok(ttl) => Response::Ttl(ttl.map(|ttl| ttl.as_millis() as u64)),
+                Err(e) => error_response(e),
+            }
+        }
+        Request::IncrBy { key, delta } => return integer_response(engine.incr_by(key, delta)),
+        Request::DecrBy { key, delta } => return integer_response(engine.decr_by(key, delta)),
+        Request::Append { key, value } => {
+            return integer_response(engine.append(key, value).map(|len| len as i64))
+        }
+        // Handled by `handle`, which tracks the transaction of the connection.
+        Request::Begin | Request::Commit | Request::Abort => unreachable!(),
+        Request::Get { key } => engine.get(key),
+        Request::Set { key, value } => engine.set(key, value).map(|_| None),
+        Request::SetWithTtl { key, value, ttl_ms } => engine
+            .set_with_ttl(key, value, Duration::from_millis(ttl_ms))
+            .map(|_| None),
+        Request::Expire { key, ttl_ms } => engine
+            .expire(key, Duration::from_millis(ttl_ms))
+            .map(|_| None),
+        Request::Persist { key } => engine.persist(key).map(|_| None),
+        Request::Remove { key } => engine.remove(key).map(|_| None),
+        Request::Batch(batch) => engine.write_batch(batch).map(|_| None),
+        Request::Backup { path } => match backup_path(backup_dir, &path) {
+            Ok(path) => engine.backup_to(&path).map(|_| None),
+            Err(msg) => return Response::Err(msg),
+        },
+    };
+    match res {
+        Ok(value) => Response::Ok(value),
+        Err(e) => error_response(e),
     }
 }
 
-#[derive(Fail, Debug)]
-enum CliError {
-    #[fail(display = "The key is wanted")]
-    Key,
-    #[fail(display = "The value is wanted")]
-    Value,
+fn execute_in_transaction<E: KvsEngine>(txn: &mut Transaction<E>, req: Request) -> Response {
+    let res = match req {
+        Request::Get { key } => txn.get(key),
+        Request::Set { key, value } => {
+            txn.set(key, value);
+            Ok(None)
+        }
+        Request::Remove { key } => txn.remove(key).map(|_| None),
+        _ => {
+            return Response::Err(
+                "only get, set and remove are allowed in a transaction".to_string(),
+            )
+        }
+    };
+    match res {
+        Ok(value) => Response::Ok(value),
+        Err(e) => error_response(e),
+    }
+}
+
+// Clients name a backup relative to the backup directory, and cannot reach
+// outside of it.
+fn backup_path(backup_dir: Option<&PathBuf>, path: &Path) -> std::result::Result<PathBuf, String> {
+    let backup_dir = match backup_dir {
+        Some(dir) => dir,
+        None => return Err("backups are disabled on this server".to_string()),
+    };
+    let inside = path.components().all(|c| matches!(c, Component::Normal(_)));
+    if !inside || path.as_os_str().is_empty() {
+        return Err(format!(
+            "{} is not a path inside the backup directory",
+            path.display()
+        ));
+    }
+    Ok(backup_dir.join(path))
+}
+
+fn error_response(e: failure::Error) -> Response {
+    match e.downcast_ref::<KvsError>() {
+        Some(KvsError::Remove(_)) | Some(KvsError::Get(_)) => Response::KeyNotFound,
+        Some(KvsError::NotAnInteger(_)) => Response::NotAnInteger,
+        Some(KvsError::TransactionConflict) => Response::TransactionConflict,
+        _ => Response::Err(format!("{}", e)),
+    }
+}
+
+fn integer_response(res: Result<i64>) -> Response {
+    match res {
+        Ok(value) => Response::Integer(value),
+        Err(e) => error_response(e),
+    }
+}
+
+fn scan<E: KvsEngine>(
+    engine: &E,
+    start: Bound<Vec<u8>>,
+    end: Bound<Vec<u8>>,
+    limit: u32,
+) -> Result<ScanPage> {
+    // Look one pair ahead to tell whether the range holds more. A page ends
+    // early rather than take a pair that would grow it past `MAX_PAGE_LEN`,
+    // so that its response always fits in a frame. Only a page's first pair
+    // may be larger on its own.
+    let mut pairs: Vec<(Vec<u8>, Vec<u8>)> = Vec::new();
+    let mut len = 0;
+    for pair in engine.scan((start, end))? {
+        let (key, value) = pair?;
+        let full = pairs.len() == limit as usize
+            || (!pairs.is_empty() && len + key.len() + value.len() > MAX_PAGE_LEN);
+        if full {
+            let cursor = pairs.last().map(|(key, _)| key.clone());
+            return Ok(ScanPage { pairs, cursor });
+        }
+        len += key.len() + value.len();
+        pairs.push((key, value));
+    }
+    Ok(ScanPage {
+        pairs,
+        cursor: None,
+    })
 }
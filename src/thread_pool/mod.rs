@@ -0,0 +1,21 @@
+use crate::Result;
+
+mod naive;
+mod rayon;
+mod shared_queue;
+
+pub use self::naive::NaiveThreadPool;
+pub use self::rayon::RayonThreadPool;
+pub use self::shared_queue::SharedQueueThreadPool;
+
+pub trait ThreadPool {
+    //Create a pool running `threads` workers.
+    //Return an error if any worker thread can not be started.
+    fn new(threads: u32) -> Result<Self>
+    where
+        Self: Sized;
+    //Run a job on one of the workers. A panicking job must not shrink the pool.
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static;
+}
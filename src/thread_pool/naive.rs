@@ -0,0 +1,17 @@
+use {super::ThreadPool, crate::Result, std::thread};
+
+// Not really a pool: every job gets a fresh thread.
+pub struct NaiveThreadPool;
+
+impl ThreadPool for NaiveThreadPool {
+    fn new(_threads: u32) -> Result<Self> {
+        Ok(NaiveThreadPool)
+    }
+
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        thread::spawn(job);
+    }
+}
@@ -0,0 +1,23 @@
+use {super::ThreadPool, crate::Result};
+
+pub struct RayonThreadPool {
+    pool: rayon::ThreadPool,
+}
+
+impl ThreadPool for RayonThreadPool {
+    fn new(threads: u32) -> Result<Self> {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads as usize)
+            // Without a handler, rayon aborts the process when a job panics.
+            .panic_handler(|_| {})
+            .build()?;
+        Ok(RayonThreadPool { pool })
+    }
+
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.pool.spawn(job);
+    }
+}
@@ -0,0 +1,49 @@
+use {
+    super::ThreadPool,
+    crate::Result,
+    crossbeam_channel::{unbounded, Receiver, Sender},
+    std::{
+        panic::{self, AssertUnwindSafe},
+        thread,
+    },
+};
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+// Fixed number of workers pulling jobs from one shared channel.
+pub struct SharedQueueThreadPool {
+    sender: Sender<Job>,
+}
+
+impl ThreadPool for SharedQueueThreadPool {
+    fn new(threads: u32) -> Result<Self> {
+        let (sender, receiver) = unbounded::<Job>();
+        for _ in 0..threads {
+            let worker = Worker(receiver.clone());
+            thread::Builder::new().spawn(move || worker.run())?;
+        }
+        Ok(SharedQueueThreadPool { sender })
+    }
+
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.sender
+            .send(Box::new(job))
+            .expect("thread pool has no worker left");
+    }
+}
+
+// Workers exit once every sender is dropped, i.e. when the pool is dropped.
+// A job that panics only unwinds as far as its worker, which moves on to the
+// next job, so there is no thread to replace and nothing that can fail then.
+struct Worker(Receiver<Job>);
+
+impl Worker {
+    fn run(&self) {
+        while let Ok(job) = self.0.recv() {
+            let _ = panic::catch_unwind(AssertUnwindSafe(job));
+        }
+    }
+}
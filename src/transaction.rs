@@ -0,0 +1,89 @@
+use {
+    crate::{KvsEngine, KvsError, KvsSnapshot, Result, WriteBatch},
+    std::collections::BTreeMap,
+};
+
+// An optimistic transaction. Reads come from a snapshot taken when the
+// transaction began and are remembered, writes are buffered. Commit applies
+// the writes as one batch only if no key read was written since the snapshot,
+// even back to the value it had, so a committed transaction behaves as if it
+// ran alone at the moment it committed. Dropping a transaction aborts it.
+//
+// Beginning costs what taking a snapshot costs, which is nothing up front:
+// instead, writes made while the transaction is open keep what their keys
+// held before. `KvStore` keeps where the old records are in its logs, sled
+// the old values.
+pub struct Transaction<E: KvsEngine> {
+    engine: E,
+    snapshot: E::Snapshot,
+    // `None` records that a key was read as absent.
+    reads: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+    // `None` is a pending remove.
+    writes: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+}
+
+impl<E: KvsEngine> Transaction<E> {
+    pub fn new(engine: E) -> Result<Self> {
+        Ok(Transaction {
+            snapshot: engine.snapshot()?,
+            engine,
+            reads: BTreeMap::new(),
+            writes: BTreeMap::new(),
+        })
+    }
+
+    // Get the value of a key, as written by this transaction if it was.
+    // Reading a key again returns the same value.
+    pub fn get(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        if let Some(value) = self.writes.get(&key) {
+            return Ok(value.clone());
+        }
+        if let Some(value) = self.reads.get(&key) {
+            return Ok(value.clone());
+        }
+        let value = self.snapshot.get(key.clone())?;
+        self.reads.insert(key, value.clone());
+        Ok(value)
+    }
+
+    pub fn set(&mut self, key: Vec<u8>, value: Vec<u8>) {
+        self.writes.insert(key, Some(value));
+    }
+
+    // Return an error if the key does not exist, as `KvsEngine::remove` does.
+    pub fn remove(&mut self, key: Vec<u8>) -> Result<()> {
+        if self.get(key.clone())?.is_none() {
+            return Err(KvsError::Remove(String::from_utf8_lossy(&key).into_owned()).into());
+        }
+        self.writes.insert(key, None);
+        Ok(())
+    }
+
+    // Return `KvsError::TransactionConflict` if another write touched a key
+    // this transaction read since it began. Nothing is written then, and the
+    // whole transaction can be retried.
+    pub fn commit(self) -> Result<()> {
+        // Whatever was read came from one snapshot, so a transaction that
+        // writes nothing always commits.
+        if self.writes.is_empty() {
+            return Ok(());
+        }
+        let mut batch = WriteBatch::new();
+        for (key, value) in self.writes {
+            match value {
+                Some(value) => batch.set(key, value),
+                None => batch.remove(key),
+            };
+        }
+        let read = self.reads.into_keys().collect();
+        match self
+            .engine
+            .write_batch_unless_written(&self.snapshot, read, batch)?
+        {
+            true => Ok(()),
+            false => Err(KvsError::TransactionConflict.into()),
+        }
+    }
+
+    pub fn abort(self) {}
+}
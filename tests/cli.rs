@@ -1,13 +1,15 @@
+// The original tests pass `&[..]` to `args`.
+#![allow(clippy::needless_borrows_for_generic_args)]
+
 use assert_cmd::prelude::*;
+use kvs::{Durability, KvsEngine, Manifest, SledKvsEngine};
 use predicates::str::{contains, is_empty};
+use std::fs::{self, File};
+use std::path::Path;
 use std::process::Command;
 use std::sync::mpsc;
 use std::thread;
 use std::time::Duration;
-use std::{
-    fs::{self, File},
-    path::Path,
-};
 use tempfile::TempDir;
 
 // `kvs-client` with no args should exit with a non-zero code.
@@ -167,6 +169,7 @@ fn cli_log_configuration() {
         .unwrap();
     thread::sleep(Duration::from_secs(1));
     child.kill().expect("server exited before killed");
+    child.wait().expect("server could not be reaped");
 
     let content = fs::read_to_string(&stderr_path).expect("unable to read from stderr file");
     assert!(content.contains(env!("CARGO_PKG_VERSION")));
@@ -187,6 +190,7 @@ fn cli_wrong_engine() {
             .unwrap();
         thread::sleep(Duration::from_secs(1));
         child.kill().expect("server exited before killed");
+        child.wait().expect("server could not be reaped");
 
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
         cmd.args(&["--engine", "kvs", "--addr", "127.0.0.1:4003"])
@@ -206,6 +210,7 @@ fn cli_wrong_engine() {
             .unwrap();
         thread::sleep(Duration::from_secs(1));
         child.kill().expect("server exited before killed");
+        child.wait().expect("server could not be reaped");
 
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
         cmd.args(&["--engine", "sled", "--addr", "127.0.0.1:4003"])
@@ -228,6 +233,7 @@ fn cli_access_server(engine: &str, addr: &str) {
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().expect("server could not be reaped");
     });
     thread::sleep(Duration::from_secs(1));
 
@@ -309,6 +315,7 @@ fn cli_access_server(engine: &str, addr: &str) {
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().expect("server could not be reaped");
     });
     thread::sleep(Duration::from_secs(1));
 
@@ -339,3 +346,674 @@ fn cli_access_server_kvs_engine() {
 fn cli_access_server_sled_engine() {
     cli_access_server("sled", "127.0.0.1:4005");
 }
+
+// Values are framed, so spaces and quotes must survive the round trip.
+#[test]
+fn cli_value_with_spaces() {
+    let addr = "127.0.0.1:4006";
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "a sentence with \"quotes\"", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("a sentence with \"quotes\"\n");
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("server could not be reaped");
+}
+
+// `kvs-server --read-only` serves gets and rejects writes.
+#[test]
+fn cli_read_only_server() {
+    let addr = "127.0.0.1:4008";
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", addr, "--read-only"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("Key not found"));
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("server could not be reaped");
+}
+
+// sled cannot honour `--read-only` or `--no-create`, so the server refuses them
+// instead of writing to the store anyway.
+#[test]
+fn cli_sled_refuses_kvs_only_flags() {
+    let temp_dir = TempDir::new().unwrap();
+    let data_dir = temp_dir.path().join("store");
+    for flag in ["--read-only", "--no-create"] {
+        Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(["--engine", "sled", "--addr", "127.0.0.1:4035", flag])
+            .arg("--data-dir")
+            .arg(&data_dir)
+            .assert()
+            .failure()
+            .stderr(contains("not supported by the sled engine"));
+        assert!(!data_dir.exists());
+    }
+}
+
+// `kvs-client scan` lists pairs in key order, one page at a time.
+#[test]
+fn cli_scan() {
+    let addr = "127.0.0.1:4011";
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    for key in &["b1", "a", "b3", "b2", "c"] {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["set", key, "v", "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success();
+    }
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["scan", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("a v\nb1 v\nb2 v\nb3 v\nc v\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["scan", "--prefix", "b", "--limit", "2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("b1 v\nb2 v\nNext cursor: 6232\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args([
+            "scan", "--prefix", "b", "--limit", "2", "--cursor", "6232", "--addr", addr,
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("b3 v\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "d e\n", "v", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["scan", "--prefix", "d", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("d\\x20e\\n v\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["scan", "--cursor", "zz", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["scan", "--limit", "0", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stdout(contains("scan limit must be at least 1"));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["scan", "a", "b3", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("a v\nb1 v\nb2 v\n");
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("server could not be reaped");
+}
+
+// `kvs-client cas` swaps only on a match and reports the current value otherwise.
+#[test]
+fn cli_cas() {
+    for (engine, addr) in [("kvs", "127.0.0.1:4014"), ("sled", "127.0.0.1:4015")] {
+        let temp_dir = TempDir::new().unwrap();
+        let mut server = Command::cargo_bin("kvs-server").unwrap();
+        let mut child = server
+            .args(["--engine", engine, "--addr", addr])
+            .current_dir(&temp_dir)
+            .spawn()
+            .unwrap();
+        thread::sleep(Duration::from_secs(1));
+
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["cas", "lease", "--new", "owner1", "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+            .stdout(is_empty());
+
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["cas", "lease", "--new", "owner2", "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .failure()
+            .stderr(contains("Conflict, current value: owner1"));
+
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["cas", "lease", "--expected", "owner1", "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success();
+
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args([
+                "cas",
+                "lease",
+                "--expected",
+                "owner1",
+                "--new",
+                "owner2",
+                "--addr",
+                addr,
+            ])
+            .current_dir(&temp_dir)
+            .assert()
+            .failure()
+            .stderr(contains("Conflict, key not found"));
+
+        child.kill().expect("server exited before killed");
+        child.wait().expect("server could not be reaped");
+    }
+}
+
+// `kvs-client set --ttl`, `expire`, `ttl` and `persist` behave alike on both engines.
+#[test]
+fn cli_ttl() {
+    for (engine, addr) in [("kvs", "127.0.0.1:4016"), ("sled", "127.0.0.1:4017")] {
+        let temp_dir = TempDir::new().unwrap();
+        let mut server = Command::cargo_bin("kvs-server").unwrap();
+        let mut child = server
+            .args(["--engine", engine, "--addr", addr])
+            .current_dir(&temp_dir)
+            .spawn()
+            .unwrap();
+        thread::sleep(Duration::from_secs(1));
+
+        let client = |args: &[&str]| {
+            let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+            cmd.args(args).args(["--addr", addr]).current_dir(&temp_dir);
+            cmd
+        };
+
+        client(&["set", "session", "token", "--ttl", "1"])
+            .assert()
+            .success();
+        client(&["set", "user", "alice", "--ttl", "100"])
+            .assert()
+            .success();
+        client(&["ttl", "user"]).assert().success().stdout("100\n");
+        client(&["persist", "user"]).assert().success();
+        client(&["ttl", "user"])
+            .assert()
+            .success()
+            .stdout("No expiry\n");
+        client(&["expire", "user", "50"]).assert().success();
+        client(&["ttl", "user"]).assert().success().stdout("50\n");
+        client(&["get", "session"])
+            .assert()
+            .success()
+            .stdout("token\n");
+
+        thread::sleep(Duration::from_millis(1100));
+        client(&["get", "session"])
+            .assert()
+            .success()
+            .stdout("Key not found\n");
+        client(&["ttl", "session"])
+            .assert()
+            .failure()
+            .stderr(contains("Key not found"));
+        client(&["expire", "session", "5"])
+            .assert()
+            .failure()
+            .stderr(contains("Key not found"));
+
+        child.kill().expect("server exited before killed");
+        child.wait().expect("server could not be reaped");
+    }
+}
+
+// `kvs-client incr`, `decr` and `append` behave alike on both engines.
+#[test]
+fn cli_counters() {
+    for (engine, addr) in [("kvs", "127.0.0.1:4018"), ("sled", "127.0.0.1:4019")] {
+        let temp_dir = TempDir::new().unwrap();
+        let mut server = Command::cargo_bin("kvs-server").unwrap();
+        let mut child = server
+            .args(["--engine", engine, "--addr", addr])
+            .current_dir(&temp_dir)
+            .spawn()
+            .unwrap();
+        thread::sleep(Duration::from_secs(1));
+
+        let client = |args: &[&str]| {
+            let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+            cmd.args(args).args(["--addr", addr]).current_dir(&temp_dir);
+            cmd
+        };
+
+        client(&["incr", "hits"]).assert().success().stdout("1\n");
+        client(&["incr", "hits", "--by", "10"])
+            .assert()
+            .success()
+            .stdout("11\n");
+        client(&["decr", "hits", "--by", "-4"])
+            .assert()
+            .success()
+            .stdout("15\n");
+        client(&["decr", "hits"]).assert().success().stdout("14\n");
+        client(&["get", "hits"]).assert().success().stdout("14\n");
+        client(&["incr", "hits", "--by", "x"]).assert().failure();
+
+        client(&["append", "greeting", "hello"])
+            .assert()
+            .success()
+            .stdout("5\n");
+        client(&["append", "greeting", " world"])
+            .assert()
+            .success()
+            .stdout("11\n");
+        client(&["get", "greeting"])
+            .assert()
+            .success()
+            .stdout("hello world\n");
+        client(&["incr", "greeting"])
+            .assert()
+            .failure()
+            .stderr(contains("Value is not an integer"));
+
+        child.kill().expect("server exited before killed");
+        child.wait().expect("server could not be reaped");
+    }
+}
+
+// `kvs-client backup` copies a live store that `kvs-server --restore` serves again.
+#[test]
+fn cli_backup_and_restore() {
+    for (engine, addr) in [("kvs", "127.0.0.1:4022"), ("sled", "127.0.0.1:4023")] {
+        let temp_dir = TempDir::new().unwrap();
+        let backup_dir = TempDir::new().unwrap();
+        let backup_root = backup_dir.path().to_str().unwrap();
+        let backup = backup_dir.path().join("backup");
+        let backup = backup.to_str().unwrap();
+        let client = |args: &[&str]| {
+            let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+            cmd.args(args).args(["--addr", addr]).current_dir(&temp_dir);
+            cmd
+        };
+
+        let mut child = Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args([
+                "--engine",
+                engine,
+                "--addr",
+                addr,
+                "--backup-dir",
+                backup_root,
+            ])
+            .current_dir(&temp_dir)
+            .spawn()
+            .unwrap();
+        thread::sleep(Duration::from_secs(1));
+        client(&["set", "key1", "value1"]).assert().success();
+        client(&["backup", "backup"]).assert().success();
+        client(&["set", "key2", "value2"]).assert().success();
+        client(&["backup", "backup"]).assert().failure();
+        // Clients cannot write outside of the backup directory.
+        let outside = temp_dir.path().join("outside");
+        client(&["backup", outside.to_str().unwrap()])
+            .assert()
+            .failure();
+        client(&["backup", "../outside"]).assert().failure();
+        assert!(!outside.exists());
+        child.kill().expect("server exited before killed");
+        child.wait().expect("server could not be reaped");
+
+        // The backup records its engine, so none needs to be given.
+        let restore_dir = TempDir::new().unwrap();
+        let mut child = Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(["--addr", addr, "--restore", backup])
+            .current_dir(&restore_dir)
+            .spawn()
+            .unwrap();
+        thread::sleep(Duration::from_secs(1));
+        let restored = Manifest::read(restore_dir.path()).unwrap().unwrap();
+        assert_eq!(restored.engine, engine);
+        let backed_up = Manifest::read(Path::new(backup)).unwrap().unwrap();
+        assert_ne!(restored.store_id, backed_up.store_id);
+        client(&["get", "key1"])
+            .assert()
+            .success()
+            .stdout("value1\n");
+        client(&["get", "key2"])
+            .assert()
+            .success()
+            .stdout("Key not found\n");
+        // Without --backup-dir the server refuses backups.
+        client(&["backup", "again"])
+            .assert()
+            .failure()
+            .stdout(contains("backups are disabled"));
+
+        // Restoring over a store fails instead of overwriting it, served or not.
+        let restore_over = || {
+            let mut cmd = Command::cargo_bin("kvs-server").unwrap();
+            cmd.args(["--engine", engine, "--addr", addr, "--restore", backup])
+                .current_dir(&restore_dir);
+            cmd
+        };
+        let refused = restore_over().assert().failure();
+        if engine == "kvs" {
+            refused.stderr(contains("already open for writing"));
+        }
+        child.kill().expect("server exited before killed");
+        child.wait().expect("server could not be reaped");
+        restore_over().assert().failure();
+
+        // A backup without a manifest is only restored with --engine.
+        let bare = TempDir::new().unwrap();
+        for entry in fs::read_dir(backup).unwrap() {
+            let entry = entry.unwrap();
+            if entry.file_type().unwrap().is_file() && entry.file_name() != "MANIFEST" {
+                fs::copy(entry.path(), bare.path().join(entry.file_name())).unwrap();
+            }
+        }
+        let bare = bare.path().to_str().unwrap();
+        Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(["--addr", addr, "--restore", bare])
+            .current_dir(TempDir::new().unwrap().path())
+            .assert()
+            .failure()
+            .stderr(contains("--engine must be given"));
+
+        // So does restoring into a directory holding anything else.
+        let other_dir = TempDir::new().unwrap();
+        fs::write(other_dir.path().join("notes.txt"), "keep me").unwrap();
+        Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(["--engine", engine, "--addr", addr, "--restore", backup])
+            .current_dir(&other_dir)
+            .assert()
+            .failure();
+    }
+}
+
+// `kvs-dump` loads JSON Lines and CSV into both engines and exports them back.
+#[test]
+fn cli_dump_and_load() {
+    let jsonl = "{\"key\":\"a\",\"value\":\"1\"}\n\n{\"key\":[255,0],\"value\":\"bin\"}\n";
+    let csv = "key,value\nb,2\n\"c,d\",\"say \"\"hi\"\"\nbye\"\n";
+    for engine in ["kvs", "sled"] {
+        let temp_dir = TempDir::new().unwrap();
+        let dump = |args: &[&str]| {
+            let mut cmd = Command::cargo_bin("kvs-dump").unwrap();
+            cmd.args(args)
+                .args(["--engine", engine])
+                .current_dir(&temp_dir);
+            cmd
+        };
+
+        dump(&["load"])
+            .with_stdin()
+            .buffer(jsonl)
+            .assert()
+            .success();
+        dump(&["load", "--format", "csv"])
+            .with_stdin()
+            .buffer(csv)
+            .assert()
+            .success();
+        dump(&["export"]).assert().success().stdout(
+            "{\"key\":\"a\",\"value\":\"1\"}\n\
+             {\"key\":\"b\",\"value\":\"2\"}\n\
+             {\"key\":\"c,d\",\"value\":\"say \\\"hi\\\"\\nbye\"}\n\
+             {\"key\":[255,0],\"value\":\"bin\"}\n",
+        );
+        // CSV cannot carry the binary key.
+        dump(&["export", "--format", "csv"]).assert().failure();
+        dump(&["load"])
+            .with_stdin()
+            .buffer("{\"key\":\"a\"}\n")
+            .assert()
+            .failure()
+            .stderr(contains("line 1"));
+
+        let copy = TempDir::new().unwrap();
+        let exported = copy.path().join("pairs.csv");
+        fs::write(&exported, csv).unwrap();
+        dump(&[
+            "load",
+            "--format",
+            "csv",
+            "--input",
+            exported.to_str().unwrap(),
+        ])
+        .arg("--dir")
+        .arg(copy.path())
+        .assert()
+        .success();
+        dump(&["export", "--format", "csv"])
+            .arg("--dir")
+            .arg(copy.path())
+            .assert()
+            .success()
+            .stdout(csv);
+        // The loaded store records its engine, as kvs-server does.
+        let other = if engine == "kvs" { "sled" } else { "kvs" };
+        Command::cargo_bin("kvs-dump")
+            .unwrap()
+            .args(["export", "--engine", other])
+            .current_dir(&temp_dir)
+            .assert()
+            .failure();
+    }
+}
+
+// Exporting a sled store skips expired keys without deleting them.
+#[test]
+fn cli_dump_leaves_sled_store_alone() {
+    let temp_dir = TempDir::new().unwrap();
+    {
+        let engine = SledKvsEngine::open(temp_dir.path(), Durability::EveryWrite).unwrap();
+        engine.set(b"kept".to_vec(), b"1".to_vec()).unwrap();
+        engine
+            .set_with_ttl(b"gone".to_vec(), b"2".to_vec(), Duration::from_millis(1))
+            .unwrap();
+    }
+    thread::sleep(Duration::from_millis(10));
+
+    Command::cargo_bin("kvs-dump")
+        .unwrap()
+        .args(["export", "--engine", "sled"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("{\"key\":\"kept\",\"value\":\"1\"}\n");
+    let db = sled::Config::new().path(temp_dir.path()).open().unwrap();
+    assert_eq!(db.len(), 2);
+    assert_eq!(db.open_tree("ttl").unwrap().len(), 1);
+}
+
+// `kvs-migrate` refuses a store that is being served, and moves a stopped kvs
+// store to sled, after which kvs-server serves it with sled.
+#[test]
+fn cli_migrate() {
+    let addr = "127.0.0.1:4024";
+    let src = TempDir::new().unwrap();
+    let dst_dir = TempDir::new().unwrap();
+    let dst = dst_dir.path().join("sled");
+    let client = |args: &[&str]| {
+        let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+        cmd.args(args).args(["--addr", addr]);
+        cmd
+    };
+    let serve = |engine: &str, dir: &std::path::Path| {
+        let child = Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(["--engine", engine, "--addr", addr])
+            .current_dir(dir)
+            .spawn()
+            .unwrap();
+        thread::sleep(Duration::from_secs(1));
+        child
+    };
+
+    let migrate = |from: &str, to: &str| {
+        let mut cmd = Command::cargo_bin("kvs-migrate").unwrap();
+        cmd.args(["--from", from, "--to", to])
+            .arg(src.path())
+            .arg(&dst);
+        cmd
+    };
+
+    let mut child = serve("kvs", src.path());
+    client(&["set", "key1", "value1"]).assert().success();
+    client(&["set", "key2", "value2"]).assert().success();
+    migrate("kvs", "sled")
+        .assert()
+        .failure()
+        .stderr(contains("already open for writing"));
+    child.kill().expect("server exited before killed");
+    child.wait().expect("server could not be reaped");
+
+    migrate("sled", "kvs")
+        .assert()
+        .failure()
+        .stderr(contains("kvs engine"));
+    migrate("kvs", "sled")
+        .assert()
+        .success()
+        .stdout(contains("Migrated 2 pairs"));
+    migrate("kvs", "sled")
+        .assert()
+        .failure()
+        .stderr(contains("not empty"));
+
+    let mut child = serve("sled", &dst);
+    client(&["get", "key1"])
+        .assert()
+        .success()
+        .stdout("value1\n");
+    client(&["get", "key2"])
+        .assert()
+        .success()
+        .stdout("value2\n");
+    child.kill().expect("server exited before killed");
+    child.wait().expect("server could not be reaped");
+}
+
+// `--data-dir` serves a store outside the working directory, whose manifest
+// then decides the engine.
+#[test]
+fn cli_data_dir() {
+    let temp_dir = TempDir::new().unwrap();
+    let data_dir = temp_dir.path().join("store");
+    let addr = "127.0.0.1:4025";
+    let client = |args: &[&str]| {
+        let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+        cmd.args(args).args(["--addr", addr]).current_dir(&temp_dir);
+        cmd
+    };
+    let server = |args: &[&str]| {
+        let mut cmd = Command::cargo_bin("kvs-server").unwrap();
+        cmd.args(["--addr", addr, "--data-dir"])
+            .arg(&data_dir)
+            .args(args)
+            .current_dir(&temp_dir);
+        cmd
+    };
+
+    let mut child = server(&["--engine", "sled"]).spawn().unwrap();
+    thread::sleep(Duration::from_secs(1));
+    client(&["set", "key1", "value1"]).assert().success();
+    child.kill().expect("server exited before killed");
+    child.wait().expect("server could not be reaped");
+
+    let manifest = fs::read_to_string(data_dir.join("MANIFEST")).unwrap();
+    assert!(manifest.contains("\"engine\": \"sled\""));
+    assert!(manifest.contains("store_id"));
+    assert!(!temp_dir.path().join("MANIFEST").exists());
+
+    server(&["--engine", "kvs"])
+        .assert()
+        .failure()
+        .stderr(contains("created by the sled engine, not kvs"));
+
+    let mut child = server(&[]).spawn().unwrap();
+    thread::sleep(Duration::from_secs(1));
+    client(&["get", "key1"])
+        .assert()
+        .success()
+        .stdout("value1\n");
+    child.kill().expect("server exited before killed");
+    child.wait().expect("server could not be reaped");
+    assert_eq!(
+        fs::read_to_string(data_dir.join("MANIFEST")).unwrap(),
+        manifest
+    );
+}
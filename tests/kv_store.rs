@@ -1,4 +1,12 @@
-use kvs::{KvStore, KvsEngine, Result};
+use kvs::{
+    CasOutcome, CompactionTrigger, Durability, KvStore, KvsEngine, KvsError, KvsIter, KvsSnapshot,
+    Manifest, Options, Result, SledKvsEngine, WriteBatch,
+};
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -6,19 +14,31 @@ use walkdir::WalkDir;
 #[test]
 fn get_stored_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
-    store.set("key1".to_owned(), "value1".to_owned())?;
-    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set_string("key1".to_owned(), "value1".to_owned())?;
+    store.set_string("key2".to_owned(), "value2".to_owned())?;
 
-    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
-    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(
+        store.get_string("key1".to_owned())?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(
+        store.get_string("key2".to_owned())?,
+        Some("value2".to_owned())
+    );
 
     // Open from disk again and check persistent data
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
-    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        store.get_string("key1".to_owned())?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(
+        store.get_string("key2".to_owned())?,
+        Some("value2".to_owned())
+    );
 
     Ok(())
 }
@@ -27,19 +47,31 @@ fn get_stored_value() -> Result<()> {
 #[test]
 fn overwrite_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
-    store.set("key1".to_owned(), "value1".to_owned())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
-    store.set("key1".to_owned(), "value2".to_owned())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    store.set_string("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(
+        store.get_string("key1".to_owned())?,
+        Some("value1".to_owned())
+    );
+    store.set_string("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(
+        store.get_string("key1".to_owned())?,
+        Some("value2".to_owned())
+    );
 
     // Open from disk again and check persistent data
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
-    store.set("key1".to_owned(), "value3".to_owned())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        store.get_string("key1".to_owned())?,
+        Some("value2".to_owned())
+    );
+    store.set_string("key1".to_owned(), "value3".to_owned())?;
+    assert_eq!(
+        store.get_string("key1".to_owned())?,
+        Some("value3".to_owned())
+    );
 
     Ok(())
 }
@@ -48,15 +80,15 @@ fn overwrite_value() -> Result<()> {
 #[test]
 fn get_non_existent_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
-    store.set("key1".to_owned(), "value1".to_owned())?;
-    assert_eq!(store.get("key2".to_owned())?, None);
+    store.set_string("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get_string("key2".to_owned())?, None);
 
     // Open from disk again and check persistent data
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
-    assert_eq!(store.get("key2".to_owned())?, None);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_string("key2".to_owned())?, None);
 
     Ok(())
 }
@@ -64,18 +96,18 @@ fn get_non_existent_value() -> Result<()> {
 #[test]
 fn remove_non_existent_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
-    assert!(store.remove("key1".to_owned()).is_err());
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.remove_string("key1".to_owned()).is_err());
     Ok(())
 }
 
 #[test]
 fn remove_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
-    store.set("key1".to_owned(), "value1".to_owned())?;
-    assert!(store.remove("key1".to_owned()).is_ok());
-    assert_eq!(store.get("key1".to_owned())?, None);
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_string("key1".to_owned(), "value1".to_owned())?;
+    assert!(store.remove_string("key1".to_owned()).is_ok());
+    assert_eq!(store.get_string("key1".to_owned())?, None);
     Ok(())
 }
 
@@ -84,7 +116,7 @@ fn remove_key() -> Result<()> {
 #[test]
 fn compaction() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     let dir_size = || {
         let entries = WalkDir::new(temp_dir.path()).into_iter();
@@ -102,7 +134,7 @@ fn compaction() -> Result<()> {
         for key_id in 0..1000 {
             let key = format!("key{}", key_id);
             let value = format!("{}", iter);
-            store.set(key, value)?;
+            store.set_string(key, value)?;
         }
 
         let new_size = dir_size();
@@ -114,13 +146,1664 @@ fn compaction() -> Result<()> {
 
         drop(store);
         // reopen and check content
-        let mut store = KvStore::open(temp_dir.path())?;
+        let store = KvStore::open(temp_dir.path())?;
         for key_id in 0..1000 {
             let key = format!("key{}", key_id);
-            assert_eq!(store.get(key)?, Some(format!("{}", iter)));
+            assert_eq!(store.get_string(key)?, Some(format!("{}", iter)));
         }
         return Ok(());
     }
 
     panic!("No compaction detected");
 }
+
+// Clones of one store can be used from many threads at once.
+#[test]
+fn concurrent_set_and_get() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let handles: Vec<_> = (0..8)
+        .map(|t| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for i in 0..100 {
+                    let key = format!("key{}-{}", t, i);
+                    store.set_string(key.clone(), format!("value{}", i))?;
+                    assert_eq!(store.get_string(key)?, Some(format!("value{}", i)));
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for t in 0..8 {
+        for i in 0..100 {
+            let key = format!("key{}-{}", t, i);
+            assert_eq!(store.get_string(key)?, Some(format!("value{}", i)));
+        }
+    }
+    Ok(())
+}
+
+// Readers keep seeing every key while a writer overwrites them and compaction
+// moves records between logs.
+#[test]
+fn concurrent_get_during_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let value = "v".repeat(1024);
+    for key_id in 0..100 {
+        store.set_string(format!("key{}", key_id), value.clone())?;
+    }
+
+    let writer = {
+        let store = store.clone();
+        let value = value.clone();
+        thread::spawn(move || -> Result<()> {
+            for iter in 0..100 {
+                for key_id in 0..100 {
+                    store.set_string(format!("key{}", key_id), format!("{}{}", value, iter))?;
+                }
+            }
+            Ok(())
+        })
+    };
+    let readers: Vec<_> = (0..4)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for _ in 0..20 {
+                    for key_id in 0..100 {
+                        assert!(store.get_string(format!("key{}", key_id))?.is_some());
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    writer.join().unwrap()?;
+    for reader in readers {
+        reader.join().unwrap()?;
+    }
+    assert_eq!(
+        store.get_string("key0".to_owned())?,
+        Some(format!("{}99", value))
+    );
+    Ok(())
+}
+
+// Keys removed or overwritten while a background compaction copies them must
+// not come back after reopening.
+#[test]
+fn writes_during_background_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let value = "v".repeat(1024);
+    for iter in 0..100 {
+        for key_id in 0..100 {
+            let key = format!("key{}", key_id);
+            if iter % 10 == 9 && key_id % 2 == 0 {
+                store.remove_string(key)?;
+            } else {
+                store.set_string(key, format!("{}{}", value, iter))?;
+            }
+        }
+    }
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        let expected = if key_id % 2 == 0 {
+            None
+        } else {
+            Some(format!("{}99", value))
+        };
+        assert_eq!(store.get_string(format!("key{}", key_id))?, expected);
+    }
+    Ok(())
+}
+
+// A partially written record at the end of the active log is dropped on open.
+#[test]
+fn recover_torn_tail() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_string("key1".to_owned(), "value1".to_owned())?;
+    store.set_string("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let log = temp_dir.path().join("1.log");
+    let size = fs::metadata(&log)?.len();
+    let mut file = OpenOptions::new().append(true).open(&log)?;
+    file.write_all(&[42, 0, 0, 0, 1, 2])?;
+    drop(file);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(fs::metadata(&log)?.len(), size);
+    assert_eq!(
+        store.get_string("key1".to_owned())?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(
+        store.get_string("key2".to_owned())?,
+        Some("value2".to_owned())
+    );
+    store.set_string("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        store.get_string("key3".to_owned())?,
+        Some("value3".to_owned())
+    );
+    Ok(())
+}
+
+// A damaged record in an older log is reported instead of silently dropped.
+#[test]
+fn report_corruption_in_old_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_string("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let log = temp_dir.path().join("1.log");
+    let mut content = fs::read(&log)?;
+    let last = content.len() - 3;
+    content[last] ^= 0xff;
+    fs::write(&log, content)?;
+    File::create(temp_dir.path().join("2.log"))?;
+
+    match KvStore::open(temp_dir.path()) {
+        Err(e) => match e.downcast_ref::<KvsError>() {
+            Some(KvsError::Corruption { log_id, offset }) => {
+                assert_eq!((*log_id, *offset), (1, 0));
+            }
+            _ => panic!("unexpected error {:?}", e),
+        },
+        Ok(_) => panic!("corruption not detected"),
+    }
+    Ok(())
+}
+
+// A background compaction that fails does not fail the writes that come
+// after it, and `compact` reports it instead.
+#[test]
+fn compaction_errors_do_not_fail_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = Options::new()
+        .max_log_size(1024)
+        .compaction_trigger(CompactionTrigger::Absolute(2 * 1024));
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    store.set_string("damaged".to_owned(), "value".to_owned())?;
+    for key_id in 0..20 {
+        store.set_string(format!("key{}", key_id), format!("{:064}", 0))?;
+    }
+    // Compaction copies the record and finds its checksum wrong.
+    let log = temp_dir.path().join("1.log");
+    let mut content = fs::read(&log)?;
+    let pos = content.windows(5).position(|w| w == b"value").unwrap();
+    content[pos] ^= 0xff;
+    fs::write(&log, content)?;
+
+    for round in 1..50 {
+        for key_id in 0..20 {
+            store.set_string(format!("key{}", key_id), format!("{:064}", round))?;
+        }
+    }
+    match store.compact() {
+        Err(e) => match e.downcast_ref::<KvsError>() {
+            Some(KvsError::Corruption { log_id, .. }) => assert_eq!(*log_id, 1),
+            _ => panic!("unexpected error {:?}", e),
+        },
+        Ok(()) => panic!("compaction error not reported"),
+    }
+    assert_eq!(
+        store.get_string("key0".to_owned())?,
+        Some(format!("{:064}", 49))
+    );
+    Ok(())
+}
+
+// Logs written before the binary record format stay readable, and new
+// records never get appended to them.
+#[test]
+fn read_legacy_json_logs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::write(
+        temp_dir.path().join("1.log"),
+        r#"{"Set":{"key":"key1","value":"value1"}}{"Set":{"key":"key2","value":"value2"}}{"Remove":{"key":"key2"}}"#,
+    )?;
+    let payload = br#"{"Set":{"key":"key3","value":"value 3"}}"#;
+    let mut framed = Vec::new();
+    framed.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    framed.extend_from_slice(&crc32fast::hash(payload).to_le_bytes());
+    framed.extend_from_slice(payload);
+    fs::write(temp_dir.path().join("2.log"), &framed)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        store.get_string("key1".to_owned())?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(store.get_string("key2".to_owned())?, None);
+    assert_eq!(
+        store.get_string("key3".to_owned())?,
+        Some("value 3".to_owned())
+    );
+    store.set_string("key4".to_owned(), "value4".to_owned())?;
+    drop(store);
+
+    assert_eq!(fs::read(temp_dir.path().join("2.log"))?, framed);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        store.get_string("key3".to_owned())?,
+        Some("value 3".to_owned())
+    );
+    assert_eq!(
+        store.get_string("key4".to_owned())?,
+        Some("value4".to_owned())
+    );
+    Ok(())
+}
+
+// A crash while writing a legacy JSON log leaves a partial command at its end,
+// which is cut off like a torn binary record.
+#[test]
+fn truncate_torn_legacy_json_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let whole = r#"{"Set":{"key":"key1","value":"value1"}}"#;
+    fs::write(
+        temp_dir.path().join("1.log"),
+        format!(r#"{}{{"Set":{{"key":"key2","val"#, whole),
+    )?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        store.get_string("key1".to_owned())?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(store.get_string("key2".to_owned())?, None);
+    store.set_string("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    assert_eq!(fs::read(temp_dir.path().join("1.log"))?, whole.as_bytes());
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        store.get_string("key2".to_owned())?,
+        Some("value2".to_owned())
+    );
+    Ok(())
+}
+
+// Legacy logs are rewritten by the next compaction, however few of their
+// records are stale.
+#[test]
+fn compaction_rewrites_legacy_logs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let json: String = (0..100)
+        .map(|i| format!(r#"{{"Set":{{"key":"key{}","value":"value{}"}}}}"#, i, i))
+        .collect();
+    fs::write(temp_dir.path().join("1.log"), json)?;
+    let mut framed = Vec::new();
+    for i in 0..100 {
+        let payload = format!(r#"{{"Set":{{"key":"framed{}","value":"value{}"}}}}"#, i, i);
+        framed.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        framed.extend_from_slice(&crc32fast::hash(payload.as_bytes()).to_le_bytes());
+        framed.extend_from_slice(payload.as_bytes());
+    }
+    fs::write(temp_dir.path().join("2.log"), &framed)?;
+
+    // Both logs are too big to be merged as small ones.
+    let options = Options::new()
+        .max_log_size(4 * 1024)
+        .compaction_trigger(CompactionTrigger::Absolute(1024));
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for i in 0..100 {
+        store.set_string("hot".to_owned(), format!("value{}", i))?;
+    }
+    drop(store);
+
+    assert!(!temp_dir.path().join("1.log").exists());
+    assert!(!temp_dir.path().join("2.log").exists());
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..100 {
+        assert_eq!(
+            store.get_string(format!("key{}", i))?,
+            Some(format!("value{}", i))
+        );
+        assert_eq!(
+            store.get_string(format!("framed{}", i))?,
+            Some(format!("value{}", i))
+        );
+    }
+    assert_eq!(
+        store.get_string("hot".to_owned())?,
+        Some("value99".to_owned())
+    );
+    Ok(())
+}
+
+// Compaction leaves a hint file behind; the store opens correctly with the
+// hint, with a damaged hint and without it.
+#[test]
+fn open_with_hint_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let hint_files = || -> Vec<std::path::PathBuf> {
+        fs::read_dir(temp_dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension() == Some("hint".as_ref()))
+            .collect()
+    };
+
+    let store = KvStore::open(temp_dir.path())?;
+    let value = "v".repeat(1024);
+    let mut iter = 0;
+    while hint_files().is_empty() {
+        assert!(iter < 100, "No compaction detected");
+        for key_id in 0..1000 {
+            store.set_string(format!("key{}", key_id), format!("{}{}", value, iter))?;
+        }
+        iter += 1;
+    }
+    drop(store);
+
+    let check = || -> Result<()> {
+        let store = KvStore::open(temp_dir.path())?;
+        for key_id in 0..1000 {
+            let key = format!("key{}", key_id);
+            assert_eq!(
+                store.get_string(key)?,
+                Some(format!("{}{}", value, iter - 1))
+            );
+        }
+        Ok(())
+    };
+    check()?;
+
+    for hint in hint_files() {
+        let mut content = fs::read(&hint)?;
+        let mid = content.len() / 2;
+        content[mid] ^= 0xff;
+        fs::write(&hint, content)?;
+    }
+    check()?;
+
+    for hint in hint_files() {
+        fs::remove_file(hint)?;
+    }
+    check()
+}
+
+// A read-only store serves reads, rejects writes and never creates anything.
+#[test]
+fn open_read_only() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let missing = temp_dir.path().join("missing");
+    assert!(KvStore::open_with(&missing, Options::new().read_only(true)).is_err());
+    assert!(KvStore::open_with(&missing, Options::new().create_if_missing(false)).is_err());
+    assert!(!missing.exists());
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_string("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open_with(temp_dir.path(), Options::new().read_only(true))?;
+    assert_eq!(
+        store.get_string("key1".to_owned())?,
+        Some("value1".to_owned())
+    );
+    match store.set_string("key2".to_owned(), "value2".to_owned()) {
+        Err(e) => assert!(matches!(
+            e.downcast_ref::<KvsError>(),
+            Some(KvsError::ReadOnly)
+        )),
+        Ok(_) => panic!("read-only store accepted a write"),
+    }
+    assert!(store.remove_string("key1".to_owned()).is_err());
+    Ok(())
+}
+
+// Logs rotate at `max_log_size` and compaction follows the configured trigger.
+#[test]
+fn open_with_options() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log_count = || {
+        fs::read_dir(temp_dir.path())
+            .unwrap()
+            .filter(|entry| entry.as_ref().unwrap().path().extension() == Some("log".as_ref()))
+            .count()
+    };
+    let options = Options::new()
+        .max_log_size(4 * 1024)
+        .compaction_trigger(CompactionTrigger::Absolute(u64::MAX))
+        .durability(Durability::EveryWrite);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for key_id in 0..1000 {
+        store.set_string(format!("key{}", key_id), "value".to_owned())?;
+    }
+    drop(store);
+    assert!(log_count() > 1);
+
+    let options = Options::new().compaction_trigger(CompactionTrigger::StaleRatio {
+        ratio: 0.3,
+        min_bytes: 0,
+    });
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for key_id in 0..1000 {
+        store.set_string(format!("key{}", key_id), "value2".to_owned())?;
+    }
+    drop(store);
+    assert!(log_count() <= 3);
+
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..1000 {
+        assert_eq!(
+            store.get_string(format!("key{}", key_id))?,
+            Some("value2".to_owned())
+        );
+    }
+    Ok(())
+}
+
+// Concurrent writers sharing group commits, across log rotations and
+// compactions, all see their writes once `set` returns.
+#[test]
+fn group_commit_durability() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = Options::new()
+        .max_log_size(4 * 1024)
+        .compaction_trigger(CompactionTrigger::Absolute(8 * 1024))
+        .durability(Durability::GroupCommit);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    let handles: Vec<_> = (0..8)
+        .map(|thread_id| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for i in 0..200 {
+                    store.set_string(format!("key{}", thread_id), format!("{}", i))?;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    for thread_id in 0..8 {
+        assert_eq!(
+            store.get_string(format!("key{}", thread_id))?,
+            Some("199".to_owned())
+        );
+    }
+    Ok(())
+}
+
+#[test]
+fn interval_durability() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = Options::new().durability(Durability::Interval(Duration::from_millis(10)));
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    store.set_string("key1".to_owned(), "value1".to_owned())?;
+    thread::sleep(Duration::from_millis(50));
+    store.remove_string("key1".to_owned())?;
+    store.set_string("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_string("key1".to_owned())?, None);
+    assert_eq!(
+        store.get_string("key2".to_owned())?,
+        Some("value2".to_owned())
+    );
+    Ok(())
+}
+
+// sled refuses a directory written by sled 0.22, whose `conf` file is binary,
+// instead of opening it with the newer format.
+#[test]
+fn sled_refuses_legacy_db() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dir = temp_dir.path();
+    drop(SledKvsEngine::open(dir, Durability::EveryWrite)?);
+
+    fs::write(
+        dir.join("conf"),
+        [0x00, 0x00, 0x80, 0x00, 0x01, 0xfe, 0x9a, 0x2c],
+    )?;
+    match SledKvsEngine::open(dir, Durability::EveryWrite)
+        .err()
+        .unwrap()
+        .downcast()?
+    {
+        KvsError::LegacySled(_) => {}
+        e => panic!("unexpected error: {}", e),
+    }
+    Ok(())
+}
+
+// Keys and values are stored as raw bytes, including across compaction and
+// reopening.
+#[test]
+fn binary_keys_and_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let key = vec![0xff, 0x00, 0xfe];
+    let value: Vec<u8> = (0..=255).collect();
+    let store = KvStore::open(temp_dir.path())?;
+    store.set(key.clone(), value.clone())?;
+    store.set(vec![0x80], vec![])?;
+    assert_eq!(store.get(key.clone())?, Some(value.clone()));
+    assert!(store
+        .get_string(String::from_utf8_lossy(&key).into_owned())?
+        .is_none());
+    // The string helpers refuse to hand out values that are not UTF-8.
+    store.set_string("key1".to_owned(), "value1".to_owned())?;
+    store.set(b"key2".to_vec(), vec![0xc3, 0x28])?;
+    assert!(store.get_string("key2".to_owned()).is_err());
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get(key.clone())?, Some(value));
+    assert_eq!(store.get(vec![0x80])?, Some(vec![]));
+    assert_eq!(
+        store.get_string("key1".to_owned())?,
+        Some("value1".to_owned())
+    );
+    store.remove(key.clone())?;
+    assert_eq!(store.get(key)?, None);
+    Ok(())
+}
+
+fn collect(iter: KvsIter<'_>) -> Result<Vec<Vec<u8>>> {
+    iter.map(|pair| pair.map(|(key, _)| key)).collect()
+}
+
+// Scans return keys in order, both for ranges and prefixes, across many
+// batches and after keys are overwritten and removed.
+#[test]
+fn scan_ranges_and_prefixes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..1000 {
+        store.set_string(format!("key{:04}", key_id), format!("{}", key_id))?;
+    }
+    for key_id in (0..1000).step_by(2) {
+        store.remove_string(format!("key{:04}", key_id))?;
+    }
+    store.set(vec![0xff, 0xff], vec![1])?;
+    store.set(vec![0xff, 0xff, 0x00], vec![2])?;
+
+    let pairs: Vec<_> = store
+        .scan(b"key0100".to_vec()..b"key0106".to_vec())?
+        .collect::<Result<_>>()?;
+    assert_eq!(
+        pairs,
+        vec![
+            (b"key0101".to_vec(), b"101".to_vec()),
+            (b"key0103".to_vec(), b"103".to_vec()),
+            (b"key0105".to_vec(), b"105".to_vec()),
+        ]
+    );
+    assert_eq!(collect(store.scan(..)?)?.len(), 502);
+    assert_eq!(collect(store.scan_prefix(b"key09".to_vec())?)?.len(), 50);
+    assert_eq!(
+        collect(store.scan_prefix(vec![0xff])?)?,
+        vec![vec![0xff, 0xff], vec![0xff, 0xff, 0x00]]
+    );
+    assert!(collect(store.scan(b"z".to_vec()..b"a".to_vec())?)?.is_empty());
+
+    let keys = collect(store.scan(..)?)?;
+    let mut sorted = keys.clone();
+    sorted.sort();
+    assert_eq!(keys, sorted);
+    Ok(())
+}
+
+// A batch is applied as a whole, and a batch cut short by a crash is dropped
+// as a whole on open.
+#[test]
+fn write_batch_atomically() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_string("key1".to_owned(), "value1".to_owned())?;
+    let mut batch = WriteBatch::new();
+    batch
+        .set(b"key2".to_vec(), b"value2".to_vec())
+        .remove(b"key1".to_vec())
+        .remove(b"missing".to_vec());
+    store.write_batch(batch)?;
+    assert_eq!(store.get_string("key1".to_owned())?, None);
+    assert_eq!(
+        store.get_string("key2".to_owned())?,
+        Some("value2".to_owned())
+    );
+    drop(store);
+
+    let log = temp_dir.path().join("1.log");
+    let size = fs::metadata(&log)?.len();
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_string("key1".to_owned())?, None);
+    assert_eq!(
+        store.get_string("key2".to_owned())?,
+        Some("value2".to_owned())
+    );
+    let mut batch = WriteBatch::new();
+    batch
+        .set(b"key3".to_vec(), b"value3".to_vec())
+        .set(b"key4".to_vec(), b"value4".to_vec());
+    store.write_batch(batch)?;
+    drop(store);
+
+    // Lose the commit marker, as if the process died mid-write.
+    let torn = fs::metadata(&log)?.len() - 5;
+    OpenOptions::new().write(true).open(&log)?.set_len(torn)?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(fs::metadata(&log)?.len(), size);
+    assert_eq!(
+        store.get_string("key2".to_owned())?,
+        Some("value2".to_owned())
+    );
+    assert_eq!(store.get_string("key3".to_owned())?, None);
+    assert_eq!(store.get_string("key4".to_owned())?, None);
+    Ok(())
+}
+
+// Concurrent compare-and-swap increments never lose an update.
+#[test]
+fn compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.set_if_absent(b"counter".to_vec(), b"0".to_vec())?);
+    assert!(!store.set_if_absent(b"counter".to_vec(), b"1".to_vec())?);
+    assert!(!store.set_if_present(b"missing".to_vec(), b"1".to_vec())?);
+    assert_eq!(store.get(b"missing".to_vec())?, None);
+
+    let handles: Vec<_> = (0..4)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for _ in 0..50 {
+                    let mut current = store.get(b"counter".to_vec())?;
+                    loop {
+                        let n: u32 = String::from_utf8(current.clone().unwrap())?.parse()?;
+                        let next = (n + 1).to_string().into_bytes();
+                        match store.compare_and_swap(b"counter".to_vec(), current, Some(next))? {
+                            CasOutcome::Swapped => break,
+                            CasOutcome::Conflict { current: actual } => current = actual,
+                        }
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    assert_eq!(
+        store.get_string("counter".to_owned())?,
+        Some("200".to_owned())
+    );
+
+    assert_eq!(
+        store.compare_and_swap(b"counter".to_vec(), Some(b"1".to_vec()), None)?,
+        CasOutcome::Conflict {
+            current: Some(b"200".to_vec())
+        }
+    );
+    assert!(store.set_if_present(b"counter".to_vec(), b"0".to_vec())?);
+    let removed = store.compare_and_swap(b"counter".to_vec(), Some(b"0".to_vec()), None)?;
+    assert_eq!(removed, CasOutcome::Swapped);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get(b"counter".to_vec())?, None);
+    Ok(())
+}
+
+// Keys set with a TTL disappear once it has passed, also after reopening, and
+// can be made persistent or given a new TTL before that.
+#[test]
+fn ttl_and_expiry() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let short = Duration::from_millis(300);
+    store.set_with_ttl(b"session1".to_vec(), b"a".to_vec(), short)?;
+    store.set_with_ttl(b"session2".to_vec(), b"b".to_vec(), short)?;
+    store.set_with_ttl(b"session3".to_vec(), b"c".to_vec(), short)?;
+    store.set_string("plain".to_owned(), "d".to_owned())?;
+
+    assert!(store.ttl(b"session1".to_vec())? <= Some(short));
+    assert_eq!(store.ttl(b"plain".to_vec())?, None);
+    assert!(store.ttl(b"missing".to_vec()).is_err());
+    assert!(store.expire(b"missing".to_vec(), short).is_err());
+    store.persist(b"session2".to_vec())?;
+    store.expire(b"session3".to_vec(), Duration::from_secs(60))?;
+    store.expire(b"plain".to_vec(), short)?;
+    assert_eq!(
+        store.get_string("session1".to_owned())?,
+        Some("a".to_owned())
+    );
+    drop(store);
+
+    thread::sleep(Duration::from_millis(400));
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_string("session1".to_owned())?, None);
+    assert_eq!(store.get_string("plain".to_owned())?, None);
+    assert!(store.ttl(b"session1".to_vec()).is_err());
+    assert!(store.remove_string("session1".to_owned()).is_err());
+    assert_eq!(
+        store.get_string("session2".to_owned())?,
+        Some("b".to_owned())
+    );
+    assert_eq!(store.ttl(b"session2".to_vec())?, None);
+    assert!(store.ttl(b"session3".to_vec())? > Some(Duration::from_secs(50)));
+    assert_eq!(
+        collect(store.scan(..)?)?,
+        vec![b"session2".to_vec(), b"session3".to_vec()]
+    );
+    // A plain set clears the TTL.
+    store.set_string("session3".to_owned(), "c2".to_owned())?;
+    assert_eq!(store.ttl(b"session3".to_vec())?, None);
+    Ok(())
+}
+
+// sled deletes expired keys from both of its trees once it finds them.
+#[test]
+fn sled_purges_expired_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let db = sled::Config::new().path(temp_dir.path()).open()?;
+    let engine = SledKvsEngine::new(db.clone())?;
+    let ttl = Duration::from_millis(1);
+    engine.set_with_ttl(b"got".to_vec(), b"1".to_vec(), ttl)?;
+    engine.set_with_ttl(b"scanned".to_vec(), b"2".to_vec(), ttl)?;
+    engine.set_with_ttl(b"kept".to_vec(), b"3".to_vec(), Duration::from_secs(60))?;
+    thread::sleep(Duration::from_millis(10));
+
+    assert_eq!(engine.get(b"got".to_vec())?, None);
+    assert_eq!(
+        collect(engine.scan_prefix(b"s".to_vec())?)?,
+        Vec::<Vec<u8>>::new()
+    );
+    let keys = db.iter().keys().collect::<sled::Result<Vec<_>>>()?;
+    assert_eq!(keys, vec![sled::IVec::from(b"kept")]);
+    assert_eq!(db.open_tree("ttl")?.len(), 1);
+    Ok(())
+}
+
+// A damaged expiry time in sled's TTL tree leaves its key without a TTL.
+#[test]
+fn sled_ignores_malformed_expiry() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let db = sled::Config::new().path(temp_dir.path()).open()?;
+    let engine = SledKvsEngine::new(db.clone())?;
+    engine.set_with_ttl(b"key".to_vec(), b"value".to_vec(), Duration::from_secs(60))?;
+    db.open_tree("ttl")?.insert(b"key", &[0u8; 3])?;
+
+    assert_eq!(engine.get(b"key".to_vec())?, Some(b"value".to_vec()));
+    assert_eq!(engine.ttl(b"key".to_vec())?, None);
+    assert_eq!(
+        collect(engine.scan_prefix(b"k".to_vec())?)?,
+        vec![b"key".to_vec()]
+    );
+    Ok(())
+}
+
+// Compaction drops expired keys instead of copying them.
+#[test]
+fn compaction_drops_expired_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = Options::new().compaction_trigger(CompactionTrigger::Absolute(64 * 1024));
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    let value = vec![b'v'; 1024];
+    for key_id in 0..100u32 {
+        let key = key_id.to_be_bytes().to_vec();
+        store.set_with_ttl(key, value.clone(), Duration::from_millis(100))?;
+    }
+    thread::sleep(Duration::from_millis(200));
+    // Overwrite one key until compactions have run over the expired ones,
+    // giving each background compaction time to finish.
+    for _ in 0..10 {
+        for _ in 0..50 {
+            store.set(b"filler".to_vec(), value.clone())?;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    drop(store);
+
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    assert_eq!(store.scan(..)?.count(), 1);
+    let log_bytes: u64 = fs::read_dir(temp_dir.path())?
+        .map(|entry| entry.unwrap().metadata().unwrap().len())
+        .sum();
+    assert!(log_bytes < 100 * 1024);
+    Ok(())
+}
+
+// Concurrent increments are never lost, and counters keep their expiry.
+#[test]
+fn atomic_counters() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let handles: Vec<_> = (0..4)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || {
+                for _ in 0..50 {
+                    store.incr_by(b"hits".to_vec(), 2).unwrap();
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    assert_eq!(store.get_string("hits".to_owned())?, Some("400".to_owned()));
+    assert_eq!(store.decr_by(b"hits".to_vec(), 500)?, -100);
+    assert_eq!(store.decr_by(b"fresh".to_vec(), 1)?, -1);
+
+    store.set_with_ttl(b"window".to_vec(), b"5".to_vec(), Duration::from_secs(60))?;
+    assert_eq!(store.incr_by(b"window".to_vec(), 1)?, 6);
+    assert!(store.ttl(b"window".to_vec())?.is_some());
+
+    store.set_string("name".to_owned(), "ali".to_owned())?;
+    match store.incr_by(b"name".to_vec(), 1) {
+        Err(e) => match e.downcast_ref::<KvsError>() {
+            Some(KvsError::NotAnInteger(_)) => {}
+            _ => panic!("unexpected error {}", e),
+        },
+        Ok(_) => panic!("incremented a non-integer value"),
+    }
+    store.set_string("max".to_owned(), i64::MAX.to_string())?;
+    assert!(store.incr_by(b"max".to_vec(), 1).is_err());
+    assert_eq!(
+        store.get_string("max".to_owned())?,
+        Some(i64::MAX.to_string())
+    );
+
+    assert_eq!(store.append(b"name".to_vec(), b"ce".to_vec())?, 5);
+    assert_eq!(store.append(b"log".to_vec(), b"a".to_vec())?, 1);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        store.get_string("hits".to_owned())?,
+        Some("-100".to_owned())
+    );
+    assert_eq!(
+        store.get_string("name".to_owned())?,
+        Some("alice".to_owned())
+    );
+    assert_eq!(store.get_string("log".to_owned())?, Some("a".to_owned()));
+    Ok(())
+}
+
+fn snapshot_reads_one_moment<E: KvsEngine>(engine: E) -> Result<()> {
+    engine.set(b"a".to_vec(), b"1".to_vec())?;
+    engine.set(b"b".to_vec(), b"2".to_vec())?;
+    let snapshot = engine.snapshot()?;
+    engine.set(b"a".to_vec(), b"10".to_vec())?;
+    engine.remove(b"b".to_vec())?;
+    engine.set(b"c".to_vec(), b"3".to_vec())?;
+
+    assert_eq!(snapshot.get(b"a".to_vec())?, Some(b"1".to_vec()));
+    assert_eq!(snapshot.get(b"b".to_vec())?, Some(b"2".to_vec()));
+    assert_eq!(snapshot.get(b"c".to_vec())?, None);
+    assert_eq!(
+        collect(snapshot.scan(..)?)?,
+        vec![b"a".to_vec(), b"b".to_vec()]
+    );
+    assert_eq!(
+        collect(snapshot.scan_prefix(b"b".to_vec())?)?,
+        vec![b"b".to_vec()]
+    );
+    assert_eq!(engine.get(b"a".to_vec())?, Some(b"10".to_vec()));
+
+    // Writes carry on while a scan of the snapshot is under way.
+    let mut pairs = snapshot.scan(..)?;
+    assert_eq!(
+        pairs.next().transpose()?,
+        Some((b"a".to_vec(), b"1".to_vec()))
+    );
+    engine.set(b"b".to_vec(), b"20".to_vec())?;
+    engine.set(b"bb".to_vec(), b"4".to_vec())?;
+    assert_eq!(
+        pairs.next().transpose()?,
+        Some((b"b".to_vec(), b"2".to_vec()))
+    );
+    assert_eq!(pairs.next().transpose()?, None);
+    Ok(())
+}
+
+// A snapshot keeps showing the values of the moment it was taken.
+#[test]
+fn snapshot_reads() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    snapshot_reads_one_moment(KvStore::open(temp_dir.path())?)?;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    snapshot_reads_one_moment(SledKvsEngine::open(temp_dir.path(), Durability::None)?)
+}
+
+// A KvStore snapshot shares the index with the store, so it has to see past
+// writes made since in long scans and across a refresh of a read-only store.
+#[test]
+fn snapshot_shares_the_index() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let key = |key_id: u32| format!("key{:04}", key_id).into_bytes();
+    for key_id in (0..1000).step_by(2) {
+        store.set(key(key_id), b"old".to_vec())?;
+    }
+    let reader = KvStore::open_with(temp_dir.path(), Options::new().read_only(true))?;
+    let snapshot = store.snapshot()?;
+    let reader_snapshot = reader.snapshot()?;
+    for key_id in 0..1000 {
+        match key_id % 4 {
+            0 => store.remove(key(key_id))?,
+            _ => store.set(key(key_id), b"new".to_vec())?,
+        }
+    }
+    reader.refresh()?;
+    assert_eq!(reader.get(key(2))?, Some(b"new".to_vec()));
+
+    let expected: Vec<_> = (0..1000)
+        .step_by(2)
+        .map(|key_id| (key(key_id), b"old".to_vec()))
+        .collect();
+    for snapshot in [&snapshot, &reader_snapshot] {
+        let pairs = snapshot.scan(..)?.collect::<Result<Vec<_>>>()?;
+        assert_eq!(pairs, expected);
+        assert_eq!(snapshot.get(key(0))?, Some(b"old".to_vec()));
+        assert_eq!(snapshot.get(key(1))?, None);
+    }
+    Ok(())
+}
+
+// Compaction leaves the logs a snapshot reads until the snapshot is dropped.
+#[test]
+fn snapshot_survives_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = Options::new().compaction_trigger(CompactionTrigger::Absolute(16 * 1024));
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for key_id in 0..100u32 {
+        store.set(key_id.to_be_bytes().to_vec(), b"old".to_vec())?;
+    }
+    let snapshot = store.snapshot()?;
+    let value = vec![b'n'; 1024];
+    for _ in 0..10 {
+        for key_id in 0..100u32 {
+            store.set(key_id.to_be_bytes().to_vec(), value.clone())?;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+
+    // Every old value lives in the first log, which compaction has merged.
+    let first_log = temp_dir.path().join(KvStore::gen_log_name(1));
+    assert!(first_log.exists());
+    for key_id in 0..100u32 {
+        assert_eq!(
+            snapshot.get(key_id.to_be_bytes().to_vec())?,
+            Some(b"old".to_vec())
+        );
+    }
+    drop(snapshot);
+    assert!(!first_log.exists());
+    Ok(())
+}
+
+fn transfer<E: KvsEngine>(engine: &E, amount: i64) -> Result<()> {
+    let mut txn = engine.begin()?;
+    let from = txn.get(b"from".to_vec())?.unwrap();
+    let to = txn.get(b"to".to_vec())?.unwrap();
+    let parse = |value: Vec<u8>| String::from_utf8(value).unwrap().parse::<i64>().unwrap();
+    txn.set(
+        b"from".to_vec(),
+        (parse(from) - amount).to_string().into_bytes(),
+    );
+    txn.set(
+        b"to".to_vec(),
+        (parse(to) + amount).to_string().into_bytes(),
+    );
+    txn.commit()
+}
+
+// Concurrent read-modify-write transactions either commit without losing any
+// update or fail with a conflict.
+fn transactions_are_serializable<E: KvsEngine>(engine: E) -> Result<()> {
+    engine.set(b"from".to_vec(), b"1000".to_vec())?;
+    engine.set(b"to".to_vec(), b"0".to_vec())?;
+    let handles: Vec<_> = (0..4)
+        .map(|_| {
+            let engine = engine.clone();
+            thread::spawn(move || {
+                let mut committed = 0;
+                for _ in 0..25 {
+                    match transfer(&engine, 1) {
+                        Ok(()) => committed += 1,
+                        Err(e) => match e.downcast_ref::<KvsError>() {
+                            Some(KvsError::TransactionConflict) => {}
+                            _ => panic!("unexpected error {}", e),
+                        },
+                    }
+                }
+                committed
+            })
+        })
+        .collect();
+    let committed: i64 = handles
+        .into_iter()
+        .map(|handle| handle.join().unwrap())
+        .sum();
+    assert!(committed > 0);
+    assert_eq!(
+        engine.get_string("from".to_owned())?,
+        Some((1000 - committed).to_string())
+    );
+    assert_eq!(
+        engine.get_string("to".to_owned())?,
+        Some(committed.to_string())
+    );
+
+    // Uncommitted writes are invisible, and aborting discards them.
+    let mut txn = engine.begin()?;
+    txn.set(b"to".to_vec(), b"-1".to_vec());
+    txn.remove(b"from".to_vec())?;
+    assert!(txn.remove(b"from".to_vec()).is_err());
+    assert_eq!(txn.get(b"to".to_vec())?, Some(b"-1".to_vec()));
+    assert_eq!(
+        engine.get_string("to".to_owned())?,
+        Some(committed.to_string())
+    );
+    txn.abort();
+    assert_eq!(
+        engine.get_string("to".to_owned())?,
+        Some(committed.to_string())
+    );
+    Ok(())
+}
+
+#[test]
+fn transactions() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    transactions_are_serializable(KvStore::open(temp_dir.path())?)?;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    transactions_are_serializable(SledKvsEngine::open(temp_dir.path(), Durability::None)?)
+}
+
+fn transactions_read_a_snapshot<E: KvsEngine>(engine: E) -> Result<()> {
+    engine.set(b"a".to_vec(), b"1".to_vec())?;
+    let mut txn = engine.begin()?;
+    // Reads never see writes made after the transaction began.
+    engine.set(b"a".to_vec(), b"2".to_vec())?;
+    engine.set(b"b".to_vec(), b"2".to_vec())?;
+    assert_eq!(txn.get(b"a".to_vec())?, Some(b"1".to_vec()));
+    assert_eq!(txn.get(b"b".to_vec())?, None);
+    // Acting on them conflicts.
+    txn.set(b"c".to_vec(), b"3".to_vec());
+    assert!(txn.commit().is_err());
+    assert_eq!(engine.get(b"c".to_vec())?, None);
+
+    // A key changed and changed back conflicts all the same, and so does a key
+    // read as absent that was set and removed again.
+    let mut txn = engine.begin()?;
+    assert_eq!(txn.get(b"a".to_vec())?, Some(b"2".to_vec()));
+    engine.set(b"a".to_vec(), b"10".to_vec())?;
+    engine.set(b"a".to_vec(), b"2".to_vec())?;
+    txn.set(b"c".to_vec(), b"3".to_vec());
+    assert!(txn.commit().is_err());
+    let mut txn = engine.begin()?;
+    assert_eq!(txn.get(b"d".to_vec())?, None);
+    let mut batch = WriteBatch::new();
+    batch
+        .set(b"d".to_vec(), b"4".to_vec())
+        .remove(b"d".to_vec());
+    engine.write_batch(batch)?;
+    txn.set(b"c".to_vec(), b"3".to_vec());
+    assert!(txn.commit().is_err());
+    assert_eq!(engine.get(b"c".to_vec())?, None);
+
+    // Writes to keys it did not read do not hold a transaction back, nor do
+    // writes made before it began.
+    engine.set(b"a".to_vec(), b"5".to_vec())?;
+    let mut txn = engine.begin()?;
+    assert_eq!(txn.get(b"a".to_vec())?, Some(b"5".to_vec()));
+    engine.set(b"b".to_vec(), b"6".to_vec())?;
+    txn.set(b"c".to_vec(), b"3".to_vec());
+    txn.commit()?;
+    assert_eq!(engine.get(b"c".to_vec())?, Some(b"3".to_vec()));
+    Ok(())
+}
+
+// Transactions read a snapshot, and conflict at commit with any write since
+// then to a key they read.
+#[test]
+fn transaction_isolation() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    transactions_read_a_snapshot(KvStore::open(temp_dir.path())?)?;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    transactions_read_a_snapshot(SledKvsEngine::open(temp_dir.path(), Durability::None)?)
+}
+
+fn backup_while_writing<E: KvsEngine>(engine: E, open: impl Fn(&Path) -> Result<E>) -> Result<()> {
+    for key_id in 0..100u32 {
+        engine.set(key_id.to_be_bytes().to_vec(), b"old".to_vec())?;
+        engine.set(key_id.to_be_bytes().to_vec(), b"backed up".to_vec())?;
+    }
+    engine.set_with_ttl(b"gone".to_vec(), b"x".to_vec(), Duration::from_millis(1))?;
+    engine.set_with_ttl(b"session".to_vec(), b"y".to_vec(), Duration::from_secs(60))?;
+    thread::sleep(Duration::from_millis(10));
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_path = backup_dir.path().join("backup");
+    engine.backup_to(&backup_path)?;
+    engine.set(b"later".to_vec(), b"z".to_vec())?;
+    assert!(engine.backup_to(&backup_path).is_err());
+
+    let backup = open(&backup_path)?;
+    for key_id in 0..100u32 {
+        assert_eq!(
+            backup.get(key_id.to_be_bytes().to_vec())?,
+            Some(b"backed up".to_vec())
+        );
+    }
+    assert_eq!(backup.get(b"gone".to_vec())?, None);
+    assert!(backup.ttl(b"session".to_vec())?.is_some());
+    assert_eq!(backup.get(b"later".to_vec())?, None);
+
+    // A restored store keeps what is written to it.
+    backup.set(b"later".to_vec(), b"after restore".to_vec())?;
+    backup.set(0u32.to_be_bytes().to_vec(), b"changed".to_vec())?;
+    drop(backup);
+    let backup = open(&backup_path)?;
+    assert_eq!(
+        backup.get(b"later".to_vec())?,
+        Some(b"after restore".to_vec())
+    );
+    assert_eq!(
+        backup.get(0u32.to_be_bytes().to_vec())?,
+        Some(b"changed".to_vec())
+    );
+    assert_eq!(
+        backup.get(1u32.to_be_bytes().to_vec())?,
+        Some(b"backed up".to_vec())
+    );
+    Ok(())
+}
+
+// A backup holds what the store held when it was taken, without expired keys.
+#[test]
+fn backup_to() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    backup_while_writing(KvStore::open(temp_dir.path())?, |path| KvStore::open(path))?;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::open(temp_dir.path(), Durability::None)?;
+    backup_while_writing(engine, reopen_sled)
+}
+
+// sled releases the lock on a database from a background thread once its last
+// handle drops, so opening it again right away may briefly find it held.
+fn reopen_sled(path: &Path) -> Result<SledKvsEngine> {
+    for _ in 0..100 {
+        match SledKvsEngine::open(path, Durability::None) {
+            Err(e) if e.to_string().contains("could not acquire lock") => {
+                thread::sleep(Duration::from_millis(10))
+            }
+            res => return res,
+        }
+    }
+    SledKvsEngine::open(path, Durability::None)
+}
+
+// A KvStore backup is a single compacted log with its hint file and a manifest.
+#[test]
+fn backup_is_compacted() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with(temp_dir.path(), Options::new().max_log_size(4 * 1024))?;
+    let value = vec![b'v'; 1024];
+    for _ in 0..10 {
+        for key_id in 0..10u32 {
+            store.set(key_id.to_be_bytes().to_vec(), value.clone())?;
+        }
+    }
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    store.backup_to(backup_dir.path())?;
+    let mut files: Vec<_> = fs::read_dir(backup_dir.path())?
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect();
+    files.sort();
+    assert_eq!(files, vec!["1.hint", "1.log", "MANIFEST"]);
+    let manifest = Manifest::read(backup_dir.path())?.unwrap();
+    assert_eq!(manifest.engine, "kvs");
+    assert!(fs::metadata(backup_dir.path().join("1.log"))?.len() < 12 * 1024);
+    Ok(())
+}
+
+// A bulk load sets every pair, across several logs, and survives a reopen.
+#[test]
+fn bulk_load() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = Options::new().max_log_size(64 * 1024);
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    store.set(b"0".to_vec(), b"overwritten".to_vec())?;
+    let pairs = (0..5000u32).map(|i| Ok((i.to_string().into_bytes(), vec![b'v'; 100])));
+    assert_eq!(store.bulk_load(pairs)?, 5000);
+    assert_eq!(store.get(b"0".to_vec())?, Some(vec![b'v'; 100]));
+    drop(store);
+
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    assert_eq!(store.scan(..)?.count(), 5000);
+    assert_eq!(store.get(b"4999".to_vec())?, Some(vec![b'v'; 100]));
+    assert!(fs::read_dir(temp_dir.path())?.count() > 5);
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::open(temp_dir.path(), Durability::None)?;
+    engine.set_with_ttl(b"0".to_vec(), b"x".to_vec(), Duration::from_secs(60))?;
+    let pairs = (0..5000u32).map(|i| Ok((i.to_string().into_bytes(), vec![b'v'; 100])));
+    assert_eq!(engine.bulk_load(pairs)?, 5000);
+    assert_eq!(engine.scan(..)?.count(), 5000);
+    assert_eq!(engine.ttl(b"0".to_vec())?, None);
+    Ok(())
+}
+
+// Migrating between engines keeps every pair and expiry time.
+#[test]
+fn migrate_between_engines() -> Result<()> {
+    let kvs_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(kvs_dir.path())?;
+    for key_id in 0..1000u32 {
+        store.set(
+            key_id.to_be_bytes().to_vec(),
+            key_id.to_string().into_bytes(),
+        )?;
+    }
+    store.set_with_ttl(
+        b"session".to_vec(),
+        b"token".to_vec(),
+        Duration::from_secs(60),
+    )?;
+    store.set_with_ttl(
+        b"gone".to_vec(),
+        b"token".to_vec(),
+        Duration::from_millis(1),
+    )?;
+    thread::sleep(Duration::from_millis(10));
+
+    let sled_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::open(sled_dir.path(), Durability::None)?;
+    let report = kvs::migrate(&store, &engine)?;
+    assert_eq!(report.pairs, 1001);
+    assert_eq!(
+        engine.get(999u32.to_be_bytes().to_vec())?,
+        Some(b"999".to_vec())
+    );
+    assert_eq!(engine.get(b"gone".to_vec())?, None);
+    assert!(engine.ttl(b"session".to_vec())? > Some(Duration::from_secs(50)));
+    assert!(kvs::migrate(&store, &engine).is_err());
+
+    let back_dir = TempDir::new().expect("unable to create temporary working directory");
+    let back = KvStore::open(back_dir.path())?;
+    assert_eq!(kvs::migrate(&engine, &back)?, report);
+    assert!(back.ttl(b"session".to_vec())?.is_some());
+    Ok(())
+}
+
+// Migrating from sled skips its expired keys without deleting them.
+#[test]
+fn migrate_leaves_sled_source_alone() -> Result<()> {
+    let sled_dir = TempDir::new().expect("unable to create temporary working directory");
+    let db = sled::Config::new().path(sled_dir.path()).open()?;
+    let engine = SledKvsEngine::new(db.clone())?;
+    engine.set(b"kept".to_vec(), b"1".to_vec())?;
+    engine.set_with_ttl(b"gone".to_vec(), b"2".to_vec(), Duration::from_millis(1))?;
+    thread::sleep(Duration::from_millis(10));
+
+    let kvs_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(kvs_dir.path())?;
+    assert_eq!(kvs::migrate(&engine, &store)?.pairs, 1);
+    assert_eq!(collect(store.scan(..)?)?, vec![b"kept".to_vec()]);
+    assert_eq!(db.len(), 2);
+    assert_eq!(db.open_tree("ttl")?.len(), 1);
+    Ok(())
+}
+
+// A manifest pins a store to its engine and format, and a legacy `pre_engine`
+// file upgrades to one.
+#[test]
+fn manifest() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dir = temp_dir.path();
+    fs::write(dir.join("pre_engine"), "sled")?;
+    let mut manifest = Manifest::open(dir, None)?;
+    assert_eq!(manifest.engine, "sled");
+    assert!(!dir.join("MANIFEST").exists());
+    manifest.upgrade(dir)?;
+    assert!(!dir.join("pre_engine").exists());
+    assert_eq!(Manifest::open(dir, Some("sled"))?, manifest);
+
+    match Manifest::open(dir, Some("kvs"))
+        .unwrap_err()
+        .downcast::<KvsError>()?
+    {
+        KvsError::EngineMismatch {
+            recorded,
+            requested,
+            ..
+        } => assert_eq!((recorded.as_str(), requested.as_str()), ("sled", "kvs")),
+        e => panic!("unexpected error: {}", e),
+    }
+
+    manifest.format_version += 1;
+    manifest.write(dir)?;
+    assert!(Manifest::open(dir, None).is_err());
+    fs::write(dir.join("MANIFEST"), "{}")?;
+    assert!(Manifest::open(dir, None).is_err());
+
+    let empty_dir = TempDir::new().expect("unable to create temporary working directory");
+    let fresh = Manifest::open(empty_dir.path(), None)?;
+    assert_eq!(fresh.engine, "kvs");
+    assert_ne!(fresh.store_id, manifest.store_id);
+    assert!(Manifest::open(empty_dir.path(), Some("rocks")).is_err());
+    Ok(())
+}
+
+// A legacy sled store is only upgraded when sled 0.34 wrote it. One written by
+// sled 0.22, whose `conf` file is binary, is recorded as such and refused.
+#[test]
+fn legacy_sled_manifest() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dir = temp_dir.path();
+    drop(SledKvsEngine::open(dir, Durability::EveryWrite)?);
+    fs::write(dir.join("pre_engine"), "sled")?;
+    let manifest = Manifest::open(dir, None)?;
+    assert_eq!(
+        manifest.format_version,
+        Manifest::new("sled")?.format_version
+    );
+
+    fs::write(
+        dir.join("conf"),
+        [0x00, 0x00, 0x80, 0x00, 0x01, 0xfe, 0x9a, 0x2c],
+    )?;
+    let manifest = Manifest::read(dir)?.unwrap();
+    assert_eq!(manifest.engine, "sled");
+    assert_eq!(manifest.format_version, 0);
+    match Manifest::open(dir, None)
+        .unwrap_err()
+        .downcast::<KvsError>()?
+    {
+        KvsError::Manifest(msg) => assert!(msg.contains("sled 0.22")),
+        e => panic!("unexpected error: {}", e),
+    }
+    Ok(())
+}
+
+// Only one handle can write a store at a time, but read-only opens can run
+// beside it.
+#[test]
+fn directory_lock() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set(b"key1".to_vec(), b"value1".to_vec())?;
+    match KvStore::open(temp_dir.path())
+        .err()
+        .map(|e| e.downcast::<KvsError>())
+    {
+        Some(Ok(KvsError::Locked(_))) => {}
+        _ => panic!("a second writer opened a locked store"),
+    }
+
+    let reader = KvStore::open_with(temp_dir.path(), Options::new().read_only(true))?;
+    assert_eq!(reader.get(b"key1".to_vec())?, Some(b"value1".to_vec()));
+
+    // Clones share the lock of the handle they came from.
+    let clone = store.clone();
+    drop(store);
+    assert!(KvStore::open(temp_dir.path()).is_err());
+    drop(clone);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get(b"key1".to_vec())?, Some(b"value1".to_vec()));
+    Ok(())
+}
+
+// A read-only store keeps serving what it saw at open until refreshed, and
+// follows a writer through rotation and compaction.
+#[test]
+fn read_only_refresh() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = Options::new()
+        .max_log_size(4 * 1024)
+        .compaction_trigger(CompactionTrigger::Absolute(16 * 1024));
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    let reader = KvStore::open_with(temp_dir.path(), Options::new().read_only(true))?;
+    reader.refresh()?;
+    store.refresh()?;
+    assert_eq!(reader.get(b"key0".to_vec())?, None);
+
+    store.set(b"key0".to_vec(), b"value0".to_vec())?;
+    let mut batch = WriteBatch::new();
+    batch.set(b"key1".to_vec(), b"value1".to_vec());
+    batch.remove(b"key0".to_vec());
+    store.write_batch(batch)?;
+    assert_eq!(reader.get(b"key1".to_vec())?, None);
+    reader.refresh()?;
+    assert_eq!(reader.get(b"key0".to_vec())?, None);
+    assert_eq!(reader.get(b"key1".to_vec())?, Some(b"value1".to_vec()));
+
+    for round in 0..10 {
+        for key_id in 0..100 {
+            store.set_string(format!("key{}", key_id), format!("value{}", round))?;
+        }
+        reader.refresh()?;
+        assert_eq!(
+            reader.get_string("key99".to_owned())?,
+            Some(format!("value{}", round))
+        );
+    }
+    // Wait for the last compaction to finish, then check that refreshing
+    // leaves the files alone.
+    drop(store);
+    let files = || -> Vec<_> {
+        let mut files: Vec<_> = fs::read_dir(temp_dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        files.sort();
+        files
+    };
+    let before = files();
+    reader.refresh()?;
+    assert_eq!(files(), before);
+    for key_id in 0..100 {
+        assert_eq!(
+            reader.get_string(format!("key{}", key_id))?,
+            Some("value9".to_owned())
+        );
+    }
+    assert_eq!(reader.scan(..)?.count(), 100);
+    Ok(())
+}
+
+// A refresh racing with a writer sees a prefix of its writes, however far the
+// logs have grown since the refresh started reading them.
+#[test]
+fn refresh_while_the_log_grows() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with(temp_dir.path(), Options::new().max_log_size(64 * 1024))?;
+    let reader = KvStore::open_with(temp_dir.path(), Options::new().read_only(true))?;
+    let writer = thread::spawn(move || -> Result<()> {
+        for i in 0..50_000u32 {
+            store.set(b"counter".to_vec(), i.to_be_bytes().to_vec())?;
+            if i % 100 == 0 {
+                thread::yield_now();
+            }
+        }
+        Ok(())
+    });
+
+    let mut last = None;
+    while !writer.is_finished() {
+        reader.refresh()?;
+        let counter = reader.get(b"counter".to_vec())?;
+        assert!(
+            counter >= last,
+            "went back from {:?} to {:?}",
+            last,
+            counter
+        );
+        last = counter;
+    }
+    writer.join().unwrap()?;
+    reader.refresh()?;
+    assert_eq!(
+        reader.get(b"counter".to_vec())?,
+        Some(49_999u32.to_be_bytes().to_vec())
+    );
+    Ok(())
+}
+
+// Compaction merges the logs that are mostly stale and leaves mostly live ones
+// alone, without bringing back keys whose removal it merged.
+#[test]
+fn compaction_merges_only_stale_logs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log_ids = || -> Vec<u64> {
+        let mut ids: Vec<u64> = fs::read_dir(temp_dir.path())
+            .unwrap()
+            .filter_map(|entry| {
+                let name = entry.unwrap().file_name().into_string().unwrap();
+                name.strip_suffix(".log")?.parse().ok()
+            })
+            .collect();
+        ids.sort_unstable();
+        ids
+    };
+    let options = || {
+        Options::new()
+            .max_log_size(4 * 1024)
+            .compaction_trigger(CompactionTrigger::Absolute(8 * 1024))
+            .merge_threshold(0.5)
+    };
+    let value = |tag: &str, i: u32| format!("{}-{:032}", tag, i);
+
+    let store = KvStore::open_with(temp_dir.path(), options())?;
+    store.set_string("doomed".to_owned(), "value".to_owned())?;
+    store.set_string("fleeting".to_owned(), "value".to_owned())?;
+    for key_id in 0..400 {
+        store.set_string(format!("cold{}", key_id), value("cold", key_id))?;
+    }
+    // Every log but the active one is full of live records.
+    let cold_logs = log_ids();
+    let cold_logs = &cold_logs[..cold_logs.len() - 1];
+    assert!(cold_logs.len() >= 4);
+
+    let write_hot = |store: &KvStore, round: u32| -> Result<()> {
+        for key_id in 0..10 {
+            store.set_string(format!("hot{}", key_id), value("hot", round))?;
+        }
+        Ok(())
+    };
+    for round in 0..300 {
+        write_hot(&store, round)?;
+        // Removed in logs that get merged, unlike the logs they were set in.
+        if round == 50 {
+            store.remove_string("doomed".to_owned())?;
+            let ttl = Duration::from_millis(1);
+            store.set_with_ttl(b"fleeting".to_vec(), b"value".to_vec(), ttl)?;
+        }
+    }
+    drop(store);
+    // Logs filled while the last compaction ran are merged by the next one.
+    let store = KvStore::open_with(temp_dir.path(), options())?;
+    write_hot(&store, 300)?;
+    drop(store);
+
+    let logs = log_ids();
+    assert!(
+        logs.starts_with(cold_logs),
+        "cold logs were merged: {:?}",
+        logs
+    );
+    // Besides the cold logs, the log that was active when they were written,
+    // the compaction output, the active log and less than the trigger's worth
+    // of stale logs may remain.
+    assert!(
+        logs.len() <= cold_logs.len() + 5,
+        "stale logs were kept: {:?}",
+        logs
+    );
+
+    let store = KvStore::open_with(temp_dir.path(), options())?;
+    for key_id in 0..400 {
+        assert_eq!(
+            store.get_string(format!("cold{}", key_id))?,
+            Some(value("cold", key_id))
+        );
+    }
+    for key_id in 0..10 {
+        assert_eq!(
+            store.get_string(format!("hot{}", key_id))?,
+            Some(value("hot", 300))
+        );
+    }
+    assert_eq!(store.get_string("doomed".to_owned())?, None);
+    assert_eq!(store.get_string("fleeting".to_owned())?, None);
+    Ok(())
+}
+
+// A snapshot that is never dropped leaves the logs it pinned behind, but they
+// must not bring back keys removed before the store is opened again.
+#[test]
+fn reopen_after_leaked_snapshot() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = || {
+        Options::new()
+            .max_log_size(4 * 1024)
+            .compaction_trigger(CompactionTrigger::Absolute(16 * 1024))
+    };
+    let churn = |store: &KvStore, rounds: u32| -> Result<()> {
+        for round in 0..rounds {
+            for key_id in 0..10 {
+                store.set_string(format!("key{}", key_id), format!("{:064}", round))?;
+            }
+        }
+        Ok(())
+    };
+
+    let store = KvStore::open_with(temp_dir.path(), options())?;
+    store.set_string("gone".to_owned(), "old".to_owned())?;
+    store.set_string("kept".to_owned(), "value".to_owned())?;
+    churn(&store, 20)?;
+    store.remove_string("gone".to_owned())?;
+    churn(&store, 20)?;
+    let snapshot = store.snapshot()?;
+    churn(&store, 200)?;
+    thread::sleep(Duration::from_millis(100));
+    churn(&store, 1)?;
+    std::mem::forget(snapshot);
+    drop(store);
+    let retired = || {
+        fs::read_dir(temp_dir.path())
+            .unwrap()
+            .filter(|entry| entry.as_ref().unwrap().path().extension() == Some("retired".as_ref()))
+            .count()
+    };
+    assert!(retired() > 0);
+
+    let store = KvStore::open_with(temp_dir.path(), options())?;
+    assert_eq!(retired(), 0);
+    assert_eq!(store.get_string("gone".to_owned())?, None);
+    assert_eq!(
+        store.get_string("kept".to_owned())?,
+        Some("value".to_owned())
+    );
+    Ok(())
+}
@@ -0,0 +1,431 @@
+use assert_cmd::prelude::*;
+use kvs::server::protocol::{read_frame, write_frame};
+use kvs::{Client, KvsError, Request, Response, Result, WriteBatch};
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::ops::Bound;
+use std::path::Path;
+use std::process::{Child, Command};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// Kills the server when the test finishes, even on an early return.
+struct ServerProcess(Child);
+
+impl Drop for ServerProcess {
+    fn drop(&mut self) {
+        self.0.kill().expect("server exited before killed");
+        self.0.wait().expect("server could not be reaped");
+    }
+}
+
+fn start_server(engine: &str, addr: &str, dir: &Path) -> ServerProcess {
+    start_server_with(engine, addr, dir, &[])
+}
+
+fn start_server_with(engine: &str, addr: &str, dir: &Path, args: &[&str]) -> ServerProcess {
+    let child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", engine, "--addr", addr])
+        // Small enough for tests to open more connections than it has threads.
+        .args(["--threads", "4"])
+        .args(args)
+        .current_dir(dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    ServerProcess(child)
+}
+
+// Many pipelined requests over one connection are answered in order.
+#[test]
+fn pipelined_requests() -> Result<()> {
+    let addr = "127.0.0.1:4007";
+    let temp_dir = TempDir::new().unwrap();
+    let _server = start_server("kvs", addr, temp_dir.path());
+
+    let mut client = Client::connect(addr)?;
+    let sets: Vec<Request> = (0..100)
+        .map(|i| Request::Set {
+            key: format!("key{}", i).into_bytes(),
+            value: format!("value {}", i).into_bytes(),
+        })
+        .collect();
+    let resps = client.pipeline(&sets)?;
+    assert!(resps.iter().all(|resp| *resp == Response::Ok(None)));
+
+    let gets: Vec<Request> = (0..101)
+        .map(|i| Request::Get {
+            key: format!("key{}", i).into_bytes(),
+        })
+        .collect();
+    let resps = client.pipeline(&gets)?;
+    for (i, resp) in resps.into_iter().enumerate().take(100) {
+        assert_eq!(
+            resp,
+            Response::Ok(Some(format!("value {}", i).into_bytes()))
+        );
+    }
+
+    // The same connection keeps serving single requests afterwards.
+    client.remove_string("key0")?;
+    assert_eq!(client.get_string("key0")?, None);
+    assert!(client.remove_string("key0").is_err());
+    Ok(())
+}
+
+// A pipeline whose responses outgrow the socket buffers is read while it is
+// still being sent, so neither end blocks writing forever.
+#[test]
+fn pipelined_large_responses() -> Result<()> {
+    let addr = "127.0.0.1:4030";
+    let temp_dir = TempDir::new().unwrap();
+    let _server = start_server("kvs", addr, temp_dir.path());
+
+    let value = vec![0xab; 4096];
+    Client::connect(addr)?.set(b"key", &value)?;
+    let (done, finished) = mpsc::channel();
+    thread::spawn(move || {
+        // Each of these fails with a conflict that carries the whole value.
+        let cas: Vec<Request> = (0..4000)
+            .map(|_| Request::Cas {
+                key: b"key".to_vec(),
+                expected: Some(vec![0xcd; 4096]),
+                new: None,
+            })
+            .collect();
+        let res = Client::connect(addr).and_then(|mut client| client.pipeline(&cas));
+        done.send(res.map_err(|e| e.to_string())).unwrap();
+    });
+    let resps = finished
+        .recv_timeout(Duration::from_secs(60))
+        .expect("a pipeline with large responses hung")
+        .unwrap();
+    assert_eq!(resps.len(), 4000);
+    assert!(resps
+        .iter()
+        .all(|resp| *resp == Response::Conflict(Some(value.clone()))));
+    Ok(())
+}
+
+// Keys and values that are not UTF-8 round-trip unchanged through both engines.
+#[test]
+fn binary_keys_and_values() -> Result<()> {
+    for (engine, addr) in [("kvs", "127.0.0.1:4009"), ("sled", "127.0.0.1:4010")] {
+        let temp_dir = TempDir::new().unwrap();
+        let _server = start_server(engine, addr, temp_dir.path());
+
+        let key = [0xff, 0x00, 0xfe];
+        let value: Vec<u8> = (0..=255).collect();
+        let mut client = Client::connect(addr)?;
+        client.set(&key, &value)?;
+        assert_eq!(client.get(&key)?, Some(value));
+        assert!(client.get_string("missing")?.is_none());
+        client.remove(&key)?;
+        assert_eq!(client.get(&key)?, None);
+    }
+    Ok(())
+}
+
+// Batches go over the wire and are applied by both engines.
+#[test]
+fn batch_requests() -> Result<()> {
+    for (engine, addr) in [("kvs", "127.0.0.1:4012"), ("sled", "127.0.0.1:4013")] {
+        let temp_dir = TempDir::new().unwrap();
+        let _server = start_server(engine, addr, temp_dir.path());
+
+        let mut client = Client::connect(addr)?;
+        client.set_string("key1", "value1")?;
+        let mut batch = WriteBatch::new();
+        batch
+            .set(b"key2".to_vec(), b"value2".to_vec())
+            .set(b"key3".to_vec(), b"value3".to_vec())
+            .remove(b"key1".to_vec());
+        client.write_batch(batch)?;
+        assert_eq!(client.get_string("key1")?, None);
+        assert_eq!(client.get_string("key2")?, Some("value2".to_owned()));
+        assert_eq!(client.get_string("key3")?, Some("value3".to_owned()));
+    }
+    Ok(())
+}
+
+// A scan page ends early with a cursor once it holds a few megabytes, however
+// high its limit, so that it never outgrows a frame.
+#[test]
+fn large_scans_are_paged() -> Result<()> {
+    let addr = "127.0.0.1:4028";
+    let temp_dir = TempDir::new().unwrap();
+    let _server = start_server("kvs", addr, temp_dir.path());
+
+    let mut client = Client::connect(addr)?;
+    let value = vec![0xab; 1024 * 1024];
+    for i in 0..12u8 {
+        client.set(&[i], &value)?;
+    }
+    let mut keys = Vec::new();
+    let mut start = Bound::Unbounded;
+    loop {
+        let page = client.scan(start, Bound::Unbounded, u32::MAX)?;
+        assert!(!page.pairs.is_empty() && page.pairs.len() < 12);
+        keys.extend(page.pairs.into_iter().map(|(key, _)| key));
+        match page.cursor {
+            Some(cursor) => start = Bound::Excluded(cursor),
+            None => break,
+        }
+    }
+    assert_eq!(keys, (0..12u8).map(|i| vec![i]).collect::<Vec<_>>());
+    Ok(())
+}
+
+// A pair that would take a page past its size cap starts the next page
+// instead. Together these two pairs would not fit in one frame.
+#[test]
+fn large_pairs_start_a_new_page() -> Result<()> {
+    let addr = "127.0.0.1:4029";
+    let temp_dir = TempDir::new().unwrap();
+    let _server = start_server("kvs", addr, temp_dir.path());
+
+    let mut client = Client::connect(addr)?;
+    let small = vec![0xab; 4096];
+    let large = vec![0xcd; 64 * 1024 * 1024 - 1024];
+    client.set(&[0], &small)?;
+    client.set(&[1], &large)?;
+
+    let page = client.scan(Bound::Unbounded, Bound::Unbounded, 10)?;
+    assert_eq!(page.pairs, vec![(vec![0], small)]);
+    assert_eq!(page.cursor, Some(vec![0]));
+    let page = client.scan(Bound::Excluded(vec![0]), Bound::Unbounded, 10)?;
+    assert_eq!(page.pairs.len(), 1);
+    assert_eq!(page.pairs[0].0, vec![1]);
+    assert!(page.pairs[0].1 == large);
+    assert_eq!(page.cursor, None);
+    Ok(())
+}
+
+// A transaction on one connection is hidden from others until it commits, and
+// fails to commit once another connection changed a key it read.
+#[test]
+fn transactions() -> Result<()> {
+    for (engine, addr) in [("kvs", "127.0.0.1:4020"), ("sled", "127.0.0.1:4021")] {
+        let temp_dir = TempDir::new().unwrap();
+        let _server = start_server(engine, addr, temp_dir.path());
+
+        let mut client = Client::connect(addr)?;
+        let mut other = Client::connect(addr)?;
+        client.set_string("alice", "10")?;
+        client.set_string("bob", "0")?;
+
+        client.begin()?;
+        assert_eq!(client.get_string("alice")?, Some("10".to_owned()));
+        client.set_string("alice", "5")?;
+        client.set_string("bob", "5")?;
+        assert_eq!(client.get_string("bob")?, Some("5".to_owned()));
+        assert_eq!(other.get_string("bob")?, Some("0".to_owned()));
+        assert!(client.scan(Bound::Unbounded, Bound::Unbounded, 10).is_err());
+        client.commit()?;
+        assert_eq!(other.get_string("alice")?, Some("5".to_owned()));
+        assert_eq!(other.get_string("bob")?, Some("5".to_owned()));
+
+        client.begin()?;
+        assert_eq!(client.get_string("alice")?, Some("5".to_owned()));
+        other.set_string("alice", "100")?;
+        client.set_string("alice", "0")?;
+        match client.commit() {
+            Err(e) => match e.downcast_ref::<KvsError>() {
+                Some(KvsError::TransactionConflict) => {}
+                _ => panic!("unexpected error {}", e),
+            },
+            Ok(()) => panic!("committed over a concurrent write"),
+        }
+        assert_eq!(client.get_string("alice")?, Some("100".to_owned()));
+
+        client.begin()?;
+        client.remove_string("bob")?;
+        client.abort()?;
+        assert_eq!(client.get_string("bob")?, Some("5".to_owned()));
+        assert!(client.commit().is_err());
+    }
+    Ok(())
+}
+
+// A server only lets so many transactions open at once, and aborts those left
+// idle, closing their connection, so that forgotten ones give back their slot
+// and snapshot.
+#[test]
+fn idle_transactions_are_aborted() -> Result<()> {
+    for (engine, addr) in [("kvs", "127.0.0.1:4031"), ("sled", "127.0.0.1:4032")] {
+        let temp_dir = TempDir::new().unwrap();
+        let args = ["--txn-timeout-ms", "1000", "--max-txns", "2"];
+        let _server = start_server_with(engine, addr, temp_dir.path(), &args);
+
+        let mut first = Client::connect(addr)?;
+        let mut forgotten = Client::connect(addr)?;
+        let mut other = Client::connect(addr)?;
+        first.begin()?;
+        forgotten.begin()?;
+        assert!(other.begin().is_err());
+        first.abort()?;
+        other.begin()?;
+
+        // Requests keep a transaction alive.
+        for _ in 0..4 {
+            thread::sleep(Duration::from_millis(400));
+            other.set_string("key", "value")?;
+        }
+        other.commit()?;
+        assert!(forgotten.get_string("key").is_err());
+        first.begin()?;
+        other.begin()?;
+        assert_eq!(other.get_string("key")?, Some("value".to_owned()));
+    }
+    Ok(())
+}
+
+// Clients that keep their connection open without sending anything do not hold
+// on to pool threads, however many of them there are.
+#[test]
+fn idle_clients_do_not_starve_others() -> Result<()> {
+    let addr = "127.0.0.1:4026";
+    let temp_dir = TempDir::new().unwrap();
+    let _server = start_server("kvs", addr, temp_dir.path());
+
+    let idle = (0..12)
+        .map(|_| Client::connect(addr))
+        .collect::<Result<Vec<_>>>()?;
+    thread::sleep(Duration::from_millis(100));
+
+    let (done, finished) = mpsc::channel();
+    thread::spawn(move || {
+        let res = Client::connect(addr).and_then(|mut client| {
+            client.set_string("busy", "value")?;
+            client.get_string("busy")
+        });
+        done.send(res.map_err(|e| e.to_string())).unwrap();
+    });
+    let value = finished
+        .recv_timeout(Duration::from_secs(5))
+        .expect("a new client was starved by idle ones");
+    assert_eq!(value, Ok(Some("value".to_owned())));
+
+    // The idle connections are served again once they send something.
+    for (i, mut client) in idle.into_iter().enumerate() {
+        client.set_string(&format!("idle{}", i), "value")?;
+        assert_eq!(
+            client.get_string(&format!("idle{}", i))?,
+            Some("value".to_owned())
+        );
+    }
+    Ok(())
+}
+
+// A client that sends part of a request and stalls only holds a pool thread
+// until the request times out, and then loses its connection.
+#[test]
+fn stalled_requests_time_out() -> Result<()> {
+    let addr = "127.0.0.1:4027";
+    let temp_dir = TempDir::new().unwrap();
+    let _server = start_server("kvs", addr, temp_dir.path());
+
+    // As many as the server has threads, each with half a length prefix sent.
+    let mut stalled = (0..4)
+        .map(|_| {
+            let mut stream = TcpStream::connect(addr)?;
+            stream.write_all(&[0, 0])?;
+            Ok(stream)
+        })
+        .collect::<Result<Vec<_>>>()?;
+    thread::sleep(Duration::from_millis(100));
+
+    let (done, finished) = mpsc::channel();
+    thread::spawn(move || {
+        let res = Client::connect(addr).and_then(|mut client| {
+            client.set_string("busy", "value")?;
+            client.get_string("busy")
+        });
+        done.send(res.map_err(|e| e.to_string())).unwrap();
+    });
+    let value = finished
+        .recv_timeout(Duration::from_secs(15))
+        .expect("a new client was starved by stalled ones");
+    assert_eq!(value, Ok(Some("value".to_owned())));
+
+    for stream in &mut stalled {
+        stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+        assert_eq!(stream.read(&mut [0; 1])?, 0);
+    }
+    Ok(())
+}
+
+// A request that takes longer than the read timeout to arrive is still served,
+// as long as its bytes keep coming.
+#[test]
+fn slow_requests_are_served() -> Result<()> {
+    let addr = "127.0.0.1:4033";
+    let temp_dir = TempDir::new().unwrap();
+    let _server = start_server("kvs", addr, temp_dir.path());
+
+    let value = vec![0xab; 1024 * 1024];
+    let mut frame = Vec::new();
+    write_frame(
+        &mut frame,
+        &Request::Set {
+            key: b"key".to_vec(),
+            value: value.clone(),
+        },
+    )?;
+    let mut stream = TcpStream::connect(addr)?;
+    for chunk in frame.chunks(frame.len() / 8 + 1) {
+        stream.write_all(chunk)?;
+        thread::sleep(Duration::from_secs(1));
+    }
+    let resp: Option<Response> = read_frame(&mut stream)?;
+    assert_eq!(resp, Some(Response::Ok(None)));
+    assert_eq!(Client::connect(addr)?.get(b"key")?, Some(value));
+    Ok(())
+}
+
+// Clients that send requests and never read the responses only hold pool
+// threads until writing to them times out, and then lose their connection.
+#[test]
+fn unread_responses_time_out() -> Result<()> {
+    let addr = "127.0.0.1:4034";
+    let temp_dir = TempDir::new().unwrap();
+    let _server = start_server("kvs", addr, temp_dir.path());
+
+    Client::connect(addr)?.set(b"key", &vec![0xab; 1024 * 1024])?;
+    // As many as the server has threads, each asking for far more than the
+    // socket buffers hold.
+    let _deaf = (0..4)
+        .map(|_| {
+            let mut stream = TcpStream::connect(addr)?;
+            let mut frames = Vec::new();
+            for _ in 0..200 {
+                write_frame(
+                    &mut frames,
+                    &Request::Get {
+                        key: b"key".to_vec(),
+                    },
+                )?;
+            }
+            stream.write_all(&frames)?;
+            Ok(stream)
+        })
+        .collect::<Result<Vec<_>>>()?;
+    thread::sleep(Duration::from_millis(500));
+
+    let (done, finished) = mpsc::channel();
+    thread::spawn(move || {
+        let res = Client::connect(addr).and_then(|mut client| {
+            client.set_string("busy", "value")?;
+            client.get_string("busy")
+        });
+        done.send(res.map_err(|e| e.to_string())).unwrap();
+    });
+    let value = finished
+        .recv_timeout(Duration::from_secs(15))
+        .expect("a new client was starved by ones that never read");
+    assert_eq!(value, Ok(Some("value".to_owned())));
+    Ok(())
+}